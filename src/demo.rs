@@ -0,0 +1,95 @@
+use dolittle::http::auth::AnonymousAuthenticator;
+use dolittle::http::headers::Headers;
+use dolittle::http::parser::*;
+use dolittle::http::request::Request;
+use dolittle::websockets::config::ServerConfig;
+use dolittle::websockets::handler::*;
+use dolittle::websockets::pubsub::{PubSubHandler, PUBSUB_PROTOCOL};
+use dolittle::websockets::server::Server;
+use dolittle::websockets::websocket::*;
+
+#[deriving(Clone)]
+struct LoggingHandler {
+  messages: uint,
+  pubsub: PubSubHandler
+}
+
+pub fn run_main() {
+  let args = os::args();
+
+  match ServerConfig::from_args(args.tail()) {
+    Ok(config) => {
+      let mut config = config;
+      let server = Server::new();
+
+      for vec::each_mut(config.listeners) |listener| {
+        listener.subprotocols.push(str::from_slice(PUBSUB_PROTOCOL));
+      }
+
+      server.run_until_signalled(&config, AnonymousAuthenticator, LoggingHandler {
+        messages: 0,
+        pubsub: PubSubHandler::new(server.control())
+      });
+    }
+
+    Err(error) => {
+      println(error);
+      println("Usage: dolittle [--config FILE] [--shutdown-timeout-ms N] [--topic-history N] \
+               [--listen ADDRESS:PORT|unix:PATH|fd:N [--backlog N] \
+               [--max-bytes N] [--max-headers N] [--max-header-size N] [--timeout-ms N] \
+               [--mode OCTAL] [--unlink-stale BOOL] [--subprotocols P1,P2] \
+               [--queue-limit N] [--queue-policy POLICY] [--max-connections N] \
+               [--cert FILE --key FILE [--sni NAME:CERT:KEY]... [--alpn P1,P2]]]...");
+      os::set_exit_status(2);
+    }
+  }
+}
+
+impl Handler for LoggingHandler {
+  fn on_open<T: Transport>(&mut self, socket: &WebSocket<T>, request: &Parser) {
+    println(~"Handling: " + sys::log_str(&request.url()));
+    println(~"Protocol requested: " +
+      sys::log_str(&request.get_header("sec-websocket-protocol")));
+    println(~"Protocol selected: " + sys::log_str(socket.protocol()));
+    println(~"Identity: " + sys::log_str(&socket.identity().subject));
+  }
+
+  fn on_text<T: Transport>(&mut self, socket: &WebSocket<T>, text: &str) {
+    self.messages += 1;
+    println(~"Got Message from " + sys::log_str(&socket.identity().subject));
+
+    if uses_pubsub(socket) {
+      self.pubsub.on_text(socket, text);
+    } else {
+      socket.send_text(text);
+    }
+  }
+
+  fn on_binary<T: Transport>(&mut self, socket: &WebSocket<T>, data: &[u8]) {
+    self.messages += 1;
+    println(~"Got Binary Message of " + data.len().to_str() + " bytes");
+
+    if uses_pubsub(socket) {
+      self.pubsub.on_binary(socket, data);
+    } else {
+      socket.send_binary(data);
+    }
+  }
+
+  fn on_ping<T: Transport>(&mut self, _: &WebSocket<T>, _: &[u8]) {
+    println("Got Ping");
+  }
+
+  fn on_close<T: Transport>(&mut self, _: &WebSocket<T>, code: u16, reason: &str) {
+    println(~"Closed with " + code.to_str() + " " + reason + " after " +
+            self.messages.to_str() + " messages");
+  }
+
+  fn on_error<T: Transport>(&mut self, _: &WebSocket<T>, error: &ConnectionError) {
+    println(~"Connection Error: " + error.to_str());
+  }
+}
+
+fn uses_pubsub<T: Transport>(socket: &WebSocket<T>) -> bool {
+  *socket.protocol() == Some(str::from_slice(PUBSUB_PROTOCOL))
+}
@@ -0,0 +1,294 @@
+use std::base64::FromBase64;
+use http::headers::*;
+use http::query::get_query_param;
+use http::request::*;
+
+#[deriving(Eq,Clone)]
+pub struct Cookie {
+  name: ~str,
+  value: ~str
+}
+
+#[deriving(Eq,Clone)]
+pub enum Credentials {
+  Basic(~str,~str),
+  Bearer(~str)
+}
+
+#[deriving(Eq,Clone)]
+pub enum AuthError {
+  UNAUTHORIZED(~str),
+  FORBIDDEN
+}
+
+#[deriving(Eq,Clone)]
+pub struct Identity {
+  subject: Option<~str>,
+  claims: ~[(~str,~str)]
+}
+
+pub trait Authenticator {
+  fn authenticate<T: Headers+Request>(&self, request: &T) -> Result<Identity,AuthError>;
+}
+
+pub struct AnonymousAuthenticator;
+
+pub trait RequestAuth {
+  fn get_cookies(&self) -> ~[Cookie];
+  fn get_cookie(&self, name: &str) -> Option<~str>;
+  fn get_credentials(&self) -> Option<Credentials>;
+  fn get_bearer_token(&self, query_param: &str) -> Option<~str>;
+}
+
+impl AuthError {
+  pub fn to_response_str(&self) -> ~str {
+    match *self {
+      UNAUTHORIZED(ref challenge) => {
+        ~"HTTP/1.1 401 Unauthorized\r\n\
+          WWW-Authenticate: " + *challenge + "\r\n\r\n"
+      }
+
+      FORBIDDEN => ~"HTTP/1.1 403 Forbidden\r\n\r\n"
+    }
+  }
+}
+
+impl Identity {
+  pub fn anonymous() -> Identity {
+    Identity { subject: None, claims: ~[] }
+  }
+
+  pub fn new(subject: &str) -> Identity {
+    Identity { subject: Some(str::from_slice(subject)), claims: ~[] }
+  }
+
+  pub fn is_anonymous(&self) -> bool {
+    self.subject.is_none()
+  }
+
+  pub fn add_claim(&mut self, name: &str, value: &str) {
+    self.claims.push((str::from_slice(name), str::from_slice(value)));
+  }
+
+  pub fn get_claim(&self, name: &str) -> Option<~str> {
+    for self.claims.each |claim| {
+      match *claim {
+        (ref claim_name, ref value) => {
+          if *claim_name == str::from_slice(name) {
+            return Some(value.clone());
+          }
+        }
+      }
+    }
+
+    None
+  }
+
+  pub fn has_claim(&self, name: &str, value: &str) -> bool {
+    for self.claims.each |claim| {
+      match *claim {
+        (ref claim_name, ref claim_value) => {
+          if *claim_name == str::from_slice(name) &&
+             *claim_value == str::from_slice(value) {
+            return true;
+          }
+        }
+      }
+    }
+
+    false
+  }
+}
+
+impl Authenticator for AnonymousAuthenticator {
+  fn authenticate<T: Headers+Request>(&self, _: &T) -> Result<Identity,AuthError> {
+    Ok(Identity::anonymous())
+  }
+}
+
+impl<T: Headers+Request> RequestAuth for T {
+  fn get_cookies(&self) -> ~[Cookie] {
+    let mut cookies = ~[];
+
+    for self.get_all("Cookie").each |values| {
+      for values.each |value| {
+        cookies.push_all_move(parse_cookies(*value));
+      }
+    }
+
+    cookies
+  }
+
+  fn get_cookie(&self, name: &str) -> Option<~str> {
+    for self.get_cookies().each |cookie| {
+      if cookie.name == str::from_slice(name) {
+        return Some(cookie.value.clone());
+      }
+    }
+
+    None
+  }
+
+  /* Several Authorization headers are ambiguous, so they count as none. */
+  fn get_credentials(&self) -> Option<Credentials> {
+    match self.get_all("Authorization") {
+      Some(ref values) if values.len() == 1 => parse_credentials(values[0]),
+      _ => None
+    }
+  }
+
+  fn get_bearer_token(&self, query_param: &str) -> Option<~str> {
+    match self.get_credentials() {
+      Some(Bearer(token)) => return Some(token),
+      _ => {}
+    }
+
+    match self.url() {
+      Some(url) => get_query_param(url, query_param),
+      None => None
+    }
+  }
+}
+
+pub fn parse_cookies(value: &str) -> ~[Cookie] {
+  let mut cookies = ~[];
+
+  for str::split_char(value, ';').each |pair| {
+    let pair = pair.trim();
+
+    match str::find_char(pair, '=') {
+      Some(index) if index > 0 => {
+        cookies.push(Cookie {
+          name: str::from_slice(pair.slice(0, index).trim()),
+          value: unquote(pair.slice(index + 1, pair.len()).trim())
+        });
+      }
+
+      _ => {}
+    }
+  }
+
+  cookies
+}
+
+pub fn parse_credentials(value: &str) -> Option<Credentials> {
+  let value = value.trim();
+
+  let (scheme, params) = match str::find_char(value, ' ') {
+    Some(index) => (value.slice(0, index), value.slice(index + 1, value.len()).trim()),
+    None => return None
+  };
+
+  let lower_scheme = scheme.to_lower();
+
+  if lower_scheme == ~"bearer" && !params.is_empty() {
+    Some(Bearer(str::from_slice(params)))
+  } else if lower_scheme == ~"basic" {
+    parse_basic_credentials(params)
+  } else {
+    None
+  }
+}
+
+fn parse_basic_credentials(encoded: &str) -> Option<Credentials> {
+  if !is_base64(encoded) {
+    return None;
+  }
+
+  let decoded = match checked_str(encoded.from_base64()) {
+    Some(decoded) => decoded,
+    None => return None
+  };
+
+  match str::find_char(decoded, ':') {
+    Some(index) => Some(Basic(str::from_slice(decoded.slice(0, index)),
+                              str::from_slice(decoded.slice(index + 1, decoded.len())))),
+    None => None
+  }
+}
+
+fn is_base64(encoded: &str) -> bool {
+  let bytes = encoded.to_bytes();
+
+  if bytes.is_empty() || bytes.len() % 4 != 0 {
+    return false;
+  }
+
+  for bytes.eachi |index, c| {
+    let c = *c as char;
+    let padding = c == '=' && index + 2 >= bytes.len() &&
+                  (index + 1 == bytes.len() || bytes[index + 1] == '=' as u8);
+
+    match c {
+      'a'..'z' | 'A'..'Z' | '0'..'9' | '+' | '/' => {}
+      _ if padding => {}
+      _ => return false
+    }
+  }
+
+  true
+}
+
+fn unquote(value: &str) -> ~str {
+  if value.len() >= 2 && value.starts_with("\"") && value.ends_with("\"") {
+    str::from_slice(value.slice(1, value.len() - 1))
+  } else {
+    str::from_slice(value)
+  }
+}
+
+#[test]
+fn parse_cookies_test() {
+  assert!(parse_cookies("session=abc123; theme=\"dark\";;invalid; =empty") ==
+          ~[Cookie { name: ~"session", value: ~"abc123" },
+            Cookie { name: ~"theme", value: ~"dark" }]);
+}
+
+#[test]
+fn parse_bearer_credentials_test() {
+  assert!(parse_credentials("Bearer mF_9.B5f-4.1JqM") == Some(Bearer(~"mF_9.B5f-4.1JqM")));
+  assert!(parse_credentials("bearer  abc ") == Some(Bearer(~"abc")));
+  assert!(parse_credentials("Bearer") == None);
+}
+
+#[test]
+fn parse_basic_credentials_test() {
+  assert!(parse_credentials("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==") ==
+          Some(Basic(~"Aladdin", ~"open sesame")));
+  assert!(parse_credentials("Basic dXNlcjo=") == Some(Basic(~"user", ~"")));
+  assert!(parse_credentials("Basic bm9jb2xvbg==") == None);
+  assert!(parse_credentials("Basic !!notbase64!!") == None);
+  assert!(parse_credentials("Digest username=\"Mufasa\"") == None);
+}
+
+#[test]
+fn is_base64_test() {
+  assert!(is_base64("QWxhZGRpbjpvcGVuIHNlc2FtZQ=="));
+  assert!(is_base64("dXNlcjo="));
+  assert!(!is_base64("dXNlcjo"));
+  assert!(!is_base64("dX=lcjo="));
+  assert!(!is_base64(""));
+}
+
+#[test]
+fn identity_claims_test() {
+  let mut identity = Identity::new("alice");
+  identity.add_claim("role", "admin");
+  identity.add_claim("room", "lobby");
+  identity.add_claim("room", "general");
+
+  assert!(!identity.is_anonymous());
+  assert!(identity.get_claim("role") == Some(~"admin"));
+  assert!(identity.get_claim("room") == Some(~"lobby"));
+  assert!(identity.has_claim("room", "general"));
+  assert!(!identity.has_claim("role", "guest"));
+  assert!(identity.get_claim("missing") == None);
+  assert!(Identity::anonymous().is_anonymous());
+}
+
+#[test]
+fn auth_error_response_str_test() {
+  assert!(UNAUTHORIZED(~"Bearer").to_response_str() ==
+          ~"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\n\r\n");
+  assert!(FORBIDDEN.to_response_str() == ~"HTTP/1.1 403 Forbidden\r\n\r\n");
+}
@@ -0,0 +1,114 @@
+#[deriving(Eq)]
+pub enum HttpParseError {
+  CB_MESSAGE_BEGIN,
+  CB_STATUS_COMPLETE,
+  CB_URL,
+  CB_HEADER_FIELD,
+  CB_HEADER_VALUE,
+  CB_HEADERS_COMPLETE,
+  CB_BODY,
+  CB_MESSAGE_COMPLETE,
+  INVALID_EOF_STATE,
+  HEADER_OVERFLOW,
+  CLOSED_CONNECTION,
+  INVALID_VERSION,
+  INVALID_STATUS,
+  INVALID_METHOD,
+  INVALID_URL,
+  INVALID_HOST,
+  INVALID_PORT,
+  INVALID_PATH,
+  INVALID_QUERY_STRING,
+  INVALID_FRAGMENT,
+  LF_EXPECTED,
+  INVALID_HEADER_TOKEN,
+  INVALID_CONTENT_LENGTH,
+  INVALID_CHUNK_SIZE,
+  INVALID_CONSTANT,
+  INVALID_INTERNAL_STATE,
+  STRICT,
+  PAUSED,
+  UNKNOWN
+}
+
+impl HttpParseError {
+  pub fn name(&self) -> ~str {
+    match *self {
+      CB_MESSAGE_BEGIN => ~"HPE_CB_message_begin",
+      CB_STATUS_COMPLETE => ~"HPE_CB_status_complete",
+      CB_URL => ~"HPE_CB_url",
+      CB_HEADER_FIELD => ~"HPE_CB_header_field",
+      CB_HEADER_VALUE => ~"HPE_CB_header_value",
+      CB_HEADERS_COMPLETE => ~"HPE_CB_headers_complete",
+      CB_BODY => ~"HPE_CB_body",
+      CB_MESSAGE_COMPLETE => ~"HPE_CB_message_complete",
+      INVALID_EOF_STATE => ~"HPE_INVALID_EOF_STATE",
+      HEADER_OVERFLOW => ~"HPE_HEADER_OVERFLOW",
+      CLOSED_CONNECTION => ~"HPE_CLOSED_CONNECTION",
+      INVALID_VERSION => ~"HPE_INVALID_VERSION",
+      INVALID_STATUS => ~"HPE_INVALID_STATUS",
+      INVALID_METHOD => ~"HPE_INVALID_METHOD",
+      INVALID_URL => ~"HPE_INVALID_URL",
+      INVALID_HOST => ~"HPE_INVALID_HOST",
+      INVALID_PORT => ~"HPE_INVALID_PORT",
+      INVALID_PATH => ~"HPE_INVALID_PATH",
+      INVALID_QUERY_STRING => ~"HPE_INVALID_QUERY_STRING",
+      INVALID_FRAGMENT => ~"HPE_INVALID_FRAGMENT",
+      LF_EXPECTED => ~"HPE_LF_EXPECTED",
+      INVALID_HEADER_TOKEN => ~"HPE_INVALID_HEADER_TOKEN",
+      INVALID_CONTENT_LENGTH => ~"HPE_INVALID_CONTENT_LENGTH",
+      INVALID_CHUNK_SIZE => ~"HPE_INVALID_CHUNK_SIZE",
+      INVALID_CONSTANT => ~"HPE_INVALID_CONSTANT",
+      INVALID_INTERNAL_STATE => ~"HPE_INVALID_INTERNAL_STATE",
+      STRICT => ~"HPE_STRICT",
+      PAUSED => ~"HPE_PAUSED",
+      UNKNOWN => ~"HPE_UNKNOWN"
+    }
+  }
+
+  pub fn description(&self) -> ~str {
+    match *self {
+      CB_MESSAGE_BEGIN => ~"the on_message_begin callback failed",
+      CB_STATUS_COMPLETE => ~"the on_status_complete callback failed",
+      CB_URL => ~"the on_url callback failed",
+      CB_HEADER_FIELD => ~"the on_header_field callback failed",
+      CB_HEADER_VALUE => ~"the on_header_value callback failed",
+      CB_HEADERS_COMPLETE => ~"the on_headers_complete callback failed",
+      CB_BODY => ~"the on_body callback failed",
+      CB_MESSAGE_COMPLETE => ~"the on_message_complete callback failed",
+      INVALID_EOF_STATE => ~"stream ended at an unexpected time",
+      HEADER_OVERFLOW => ~"too many header bytes seen; overflow detected",
+      CLOSED_CONNECTION => ~"data received after completed connection: close message",
+      INVALID_VERSION => ~"invalid HTTP version",
+      INVALID_STATUS => ~"invalid HTTP status code",
+      INVALID_METHOD => ~"invalid HTTP method",
+      INVALID_URL => ~"invalid URL",
+      INVALID_HOST => ~"invalid host",
+      INVALID_PORT => ~"invalid port",
+      INVALID_PATH => ~"invalid path",
+      INVALID_QUERY_STRING => ~"invalid query string",
+      INVALID_FRAGMENT => ~"invalid fragment",
+      LF_EXPECTED => ~"LF character expected",
+      INVALID_HEADER_TOKEN => ~"invalid character in header",
+      INVALID_CONTENT_LENGTH => ~"invalid character in content-length header",
+      INVALID_CHUNK_SIZE => ~"invalid character in chunk size header",
+      INVALID_CONSTANT => ~"invalid constant string",
+      INVALID_INTERNAL_STATE => ~"encountered unexpected internal state",
+      STRICT => ~"strict mode assertion failed",
+      PAUSED => ~"parser is paused",
+      UNKNOWN => ~"an unknown error occurred"
+    }
+  }
+}
+
+#[test]
+fn http_parse_error_name_and_description() {
+  assert!(INVALID_METHOD.name() == ~"HPE_INVALID_METHOD");
+  assert!(INVALID_METHOD.description() == ~"invalid HTTP method");
+}
+
+#[test]
+fn http_parse_error_callback_name() {
+  assert!(CB_MESSAGE_BEGIN.name() == ~"HPE_CB_message_begin");
+  assert!(CB_MESSAGE_BEGIN.description() == ~"the on_message_begin callback failed");
+}
@@ -0,0 +1,317 @@
+#[deriving(Eq,Clone)]
+pub struct HeaderElement {
+  name: ~str,
+  params: ~[(~str,Option<~str>)]
+}
+
+#[deriving(Eq,Clone)]
+pub enum HeaderSyntaxError {
+  EXPECTED_TOKEN(uint),
+  EXPECTED_SEPARATOR(uint),
+  UNTERMINATED_QUOTED_STRING(uint),
+  INVALID_ENCODING,
+  INVALID_HEADER_NAME,
+  INVALID_HEADER_VALUE,
+}
+
+pub type GrammarResult<T> = Result<(T,uint),HeaderSyntaxError>;
+
+impl HeaderElement {
+  pub fn new(name: &str) -> HeaderElement {
+    HeaderElement { name: str::from_slice(name), params: ~[] }
+  }
+
+  pub fn has_param(&self, name: &str) -> bool {
+    self.find_param(name).is_some()
+  }
+
+  pub fn get_param(&self, name: &str) -> Option<~str> {
+    match self.find_param(name) {
+      Some(value) => value,
+      None => None
+    }
+  }
+
+  priv fn find_param(&self, name: &str) -> Option<Option<~str>> {
+    let lower_name = name.to_lower();
+
+    for self.params.each |param| {
+      match *param {
+        (ref param_name, ref value) => {
+          if param_name.to_lower() == lower_name {
+            return Some(value.clone());
+          }
+        }
+      }
+    }
+
+    None
+  }
+}
+
+pub fn is_tchar(c: u8) -> bool {
+  match c as char {
+    'a'..'z' | 'A'..'Z' | '0'..'9' => true,
+    '!' | '#' | '$' | '%' | '&' | '\'' | '*' | '+' |
+    '-' | '.' | '^' | '_' | '`' | '|' | '~' => true,
+    _ => false
+  }
+}
+
+fn is_ows(c: u8) -> bool {
+  c == ' ' as u8 || c == '\t' as u8
+}
+
+pub fn parse_token_list(value: &str) -> Result<~[~str],HeaderSyntaxError> {
+  do parse_list(value) |bytes, pos| { read_token(bytes, pos) }
+}
+
+pub fn parse_element_list(value: &str) -> Result<~[HeaderElement],HeaderSyntaxError> {
+  do parse_list(value) |bytes, pos| { read_element(bytes, pos) }
+}
+
+pub fn split_list(value: &str) -> ~[~str] {
+  let bytes = value.to_bytes();
+  let mut items = ~[];
+  let mut start = 0;
+  let mut pos = 0;
+
+  while pos < bytes.len() {
+    if bytes[pos] == '"' as u8 {
+      pos = skip_quoted_string(bytes, pos);
+    } else {
+      if bytes[pos] == ',' as u8 {
+        push_list_item(&mut items, bytes.slice(start, pos));
+        start = pos + 1;
+      }
+
+      pos += 1;
+    }
+  }
+
+  push_list_item(&mut items, bytes.slice(start, bytes.len()));
+
+  items
+}
+
+fn push_list_item(items: &mut ~[~str], bytes: &[u8]) {
+  let item = str::from_slice(str::from_bytes(bytes).trim());
+
+  if !item.is_empty() {
+    items.push(item);
+  }
+}
+
+fn parse_list<T>(value: &str, read_item: &fn(&[u8], uint) -> GrammarResult<T>)
+   -> Result<~[T],HeaderSyntaxError> {
+  let bytes = value.to_bytes();
+  let mut items = ~[];
+  let mut pos = skip_ows(bytes, 0);
+
+  while pos < bytes.len() {
+    if bytes[pos] == ',' as u8 {
+      pos = skip_ows(bytes, pos + 1);
+      loop;
+    }
+
+    match read_item(bytes, pos) {
+      Ok((item, next)) => {
+        items.push(item);
+        pos = skip_ows(bytes, next);
+      }
+
+      Err(error) => return Err(error)
+    }
+
+    if pos < bytes.len() && bytes[pos] != ',' as u8 {
+      return Err(EXPECTED_SEPARATOR(pos));
+    }
+  }
+
+  Ok(items)
+}
+
+fn skip_ows(bytes: &[u8], pos: uint) -> uint {
+  let mut pos = pos;
+
+  while pos < bytes.len() && is_ows(bytes[pos]) {
+    pos += 1;
+  }
+
+  pos
+}
+
+fn skip_quoted_string(bytes: &[u8], pos: uint) -> uint {
+  match read_quoted_string(bytes, pos) {
+    Ok((_, next)) => next,
+    Err(_) => bytes.len()
+  }
+}
+
+fn read_token(bytes: &[u8], pos: uint) -> GrammarResult<~str> {
+  let mut end = pos;
+
+  while end < bytes.len() && is_tchar(bytes[end]) {
+    end += 1;
+  }
+
+  if end == pos {
+    Err(EXPECTED_TOKEN(pos))
+  } else {
+    Ok((str::from_bytes(bytes.slice(pos, end)), end))
+  }
+}
+
+pub fn read_quoted_string(bytes: &[u8], pos: uint) -> GrammarResult<~str> {
+  if pos >= bytes.len() || bytes[pos] != '"' as u8 {
+    return Err(UNTERMINATED_QUOTED_STRING(pos));
+  }
+
+  let mut unquoted = ~[];
+  let mut end = pos + 1;
+
+  while end < bytes.len() {
+    let c = bytes[end];
+
+    if c == '"' as u8 {
+      return Ok((str::from_bytes(unquoted), end + 1));
+    } else if c == '\\' as u8 && end + 1 < bytes.len() {
+      unquoted.push(bytes[end + 1]);
+      end += 2;
+    } else {
+      unquoted.push(c);
+      end += 1;
+    }
+  }
+
+  Err(UNTERMINATED_QUOTED_STRING(pos))
+}
+
+fn read_token_or_quoted_string(bytes: &[u8], pos: uint) -> GrammarResult<~str> {
+  if pos < bytes.len() && bytes[pos] == '"' as u8 {
+    read_quoted_string(bytes, pos)
+  } else {
+    read_token(bytes, pos)
+  }
+}
+
+fn read_element(bytes: &[u8], pos: uint) -> GrammarResult<HeaderElement> {
+  let (name, next) = match read_token(bytes, pos) {
+    Ok(result) => result,
+    Err(error) => return Err(error)
+  };
+
+  let mut element = HeaderElement::new(name);
+  let mut pos = skip_ows(bytes, next);
+
+  while pos < bytes.len() && bytes[pos] == ';' as u8 {
+    match read_param(bytes, skip_ows(bytes, pos + 1)) {
+      Ok((param, next)) => {
+        element.params.push(param);
+        pos = skip_ows(bytes, next);
+      }
+
+      Err(error) => return Err(error)
+    }
+  }
+
+  Ok((element, pos))
+}
+
+fn read_param(bytes: &[u8], pos: uint) -> GrammarResult<(~str,Option<~str>)> {
+  let (name, next) = match read_token(bytes, pos) {
+    Ok(result) => result,
+    Err(error) => return Err(error)
+  };
+
+  let after_name = skip_ows(bytes, next);
+
+  if after_name < bytes.len() && bytes[after_name] == '=' as u8 {
+    match read_token_or_quoted_string(bytes, skip_ows(bytes, after_name + 1)) {
+      Ok((value, next)) => Ok(((name, Some(value)), next)),
+      Err(error) => Err(error)
+    }
+  } else {
+    Ok(((name, None), next))
+  }
+}
+
+#[test]
+fn tchar_test() {
+  assert!(is_tchar('a' as u8));
+  assert!(is_tchar('Z' as u8));
+  assert!(is_tchar('7' as u8));
+  assert!(is_tchar('-' as u8));
+  assert!(!is_tchar(' ' as u8));
+  assert!(!is_tchar(',' as u8));
+  assert!(!is_tchar(';' as u8));
+  assert!(!is_tchar('"' as u8));
+  assert!(!is_tchar('/' as u8));
+}
+
+#[test]
+fn parse_token_list_test() {
+  assert!(parse_token_list("Upgrade, Keep-Alive") == Ok(~[~"Upgrade", ~"Keep-Alive"]));
+  assert!(parse_token_list("  chat ,superchat\t") == Ok(~[~"chat", ~"superchat"]));
+  assert!(parse_token_list(", chat,, superchat,") == Ok(~[~"chat", ~"superchat"]));
+  assert!(parse_token_list("") == Ok(~[]));
+}
+
+#[test]
+fn parse_token_list_errors_test() {
+  assert!(parse_token_list("chat superchat") == Err(EXPECTED_SEPARATOR(5)));
+  assert!(parse_token_list("chat, \"quoted\"") == Err(EXPECTED_TOKEN(6)));
+}
+
+#[test]
+fn parse_element_list_test() {
+  let elements = parse_element_list(
+    "permessage-deflate; client_max_window_bits=10, \
+     x-webkit-deflate-frame ;no_context_takeover, \
+     permessage-foo; name=\"a, \\\"b\\\"\"");
+
+  let mut deflate = HeaderElement::new("permessage-deflate");
+  deflate.params.push((~"client_max_window_bits", Some(~"10")));
+
+  let mut webkit = HeaderElement::new("x-webkit-deflate-frame");
+  webkit.params.push((~"no_context_takeover", None));
+
+  let mut foo = HeaderElement::new("permessage-foo");
+  foo.params.push((~"name", Some(~"a, \"b\"")));
+
+  assert!(elements == Ok(~[deflate, webkit, foo]));
+}
+
+#[test]
+fn parse_element_list_errors_test() {
+  assert!(parse_element_list("foo; =1") == Err(EXPECTED_TOKEN(5)));
+  assert!(parse_element_list("foo; bar=\"1") == Err(UNTERMINATED_QUOTED_STRING(9)));
+  assert!(parse_element_list("foo bar") == Err(EXPECTED_SEPARATOR(4)));
+}
+
+#[test]
+fn header_element_params_test() {
+  let mut element = HeaderElement::new("permessage-deflate");
+  element.params.push((~"client_max_window_bits", Some(~"10")));
+  element.params.push((~"server_no_context_takeover", None));
+
+  assert!(element.has_param("Client_Max_Window_Bits"));
+  assert!(element.get_param("client_max_window_bits") == Some(~"10"));
+  assert!(element.has_param("server_no_context_takeover"));
+  assert!(element.get_param("server_no_context_takeover") == None);
+  assert!(!element.has_param("client_no_context_takeover"));
+}
+
+#[test]
+fn read_quoted_string_test() {
+  let bytes = "\"a \\\"quoted\\\" string\" rest".to_bytes();
+
+  assert!(read_quoted_string(bytes, 0) == Ok((~"a \"quoted\" string", 21)));
+}
+
+#[test]
+fn split_list_test() {
+  assert!(split_list("websocket, websocket/2.0") == ~[~"websocket", ~"websocket/2.0"]);
+  assert!(split_list("a=\"x, y\", b") == ~[~"a=\"x, y\"", ~"b"]);
+  assert!(split_list(" , a ,, ") == ~[~"a"]);
+}
@@ -1,49 +1,221 @@
-use std::treemap::*;
+use http::grammar::*;
 
 pub trait Headers {
   fn get_header(&self, name: &str) -> Option<~str>;
+  fn get_all(&self, name: &str) -> Option<~[~str]>;
+  fn get_header_bytes(&self, name: &str) -> Option<~[u8]>;
+  fn get_all_bytes(&self, name: &str) -> ~[~[u8]];
   fn has_header(&self, name: &str) -> bool;
 }
 
 pub trait HeadersUtil {
   fn has_header_value(&self, name: &str, value: &str) -> bool;
   fn has_header_keyword(&self, name: &str, value: &str) -> bool;
+  fn get_header_tokens(&self, name: &str) -> Result<~[~str],HeaderSyntaxError>;
+  fn get_header_elements(&self, name: &str) -> Result<~[HeaderElement],HeaderSyntaxError>;
 }
 
+/* Names must be tokens and values must not contain CR, LF or NUL, so that
+   whatever is stored can be written back out without injecting headers. */
 pub trait HeadersMutable {
-  fn set_header(&mut self, name: &str, value: &str);
+  fn set_header(&mut self, name: &str, value: &str) -> Result<(),HeaderSyntaxError>;
+  fn add_header(&mut self, name: &str, value: &str) -> Result<(),HeaderSyntaxError>;
+  fn add_header_bytes(&mut self, name: &str, value: &[u8]) -> Result<(),HeaderSyntaxError>;
   fn remove_header(&mut self, name: &str);
 }
 
-struct HeaderMap(TreeMap<~str,~str>);
+#[deriving(Eq,Clone)]
+pub struct Header {
+  name: ~str,
+  value: ~[u8]
+}
+
+pub struct HeaderMap {
+  entries: ~[Header]
+}
+
+impl Header {
+  fn is_named(&self, name: &str) -> bool {
+    self.name.to_lower() == name.to_lower()
+  }
+
+  pub fn value_str(&self) -> Option<~str> {
+    checked_str(self.value)
+  }
+
+  pub fn to_wire_bytes(&self) -> ~[u8] {
+    self.name.to_bytes() + ": ".to_bytes() + self.value + "\r\n".to_bytes()
+  }
+}
+
+pub fn check_header(name: &str, value: &[u8]) -> Result<(),HeaderSyntaxError> {
+  if name.is_empty() || !vec::all(name.to_bytes(), |c| is_tchar(*c)) {
+    Err(INVALID_HEADER_NAME)
+  } else if vec::any(value, |c| *c == '\r' as u8 || *c == '\n' as u8 || *c == 0) {
+    Err(INVALID_HEADER_VALUE)
+  } else {
+    Ok(())
+  }
+}
+
+pub fn checked_str(bytes: &[u8]) -> Option<~str> {
+  if str::is_utf8(bytes) {
+    Some(str::from_bytes(bytes))
+  } else {
+    None
+  }
+}
 
 impl HeaderMap {
-  pub fn new() -> HeaderMap { HeaderMap(TreeMap::new()) }
+  pub fn new() -> HeaderMap { HeaderMap { entries: ~[] } }
+
+  pub fn len(&self) -> uint {
+    self.entries.len()
+  }
+
+  pub fn each(&self, f: &fn(&Header) -> bool) {
+    for self.entries.each |header| {
+      if !f(header) { break; }
+    }
+  }
+
+  pub fn to_wire_bytes(&self) -> ~[u8] {
+    let mut wire = ~[];
+
+    for self.entries.each |header| {
+      wire.push_all_move(header.to_wire_bytes());
+    }
+
+    wire
+  }
+}
+
+impl Clone for HeaderMap {
+  fn clone(&self) -> HeaderMap {
+    HeaderMap { entries: self.entries.map(|header| header.clone()) }
+  }
 }
 
 impl Headers for HeaderMap {
   fn has_header(&self, name: &str) -> bool {
-    let our_name = str::from_slice(name).to_lower();
-    self.contains_key(&our_name)
+    vec::any(self.entries, |header| header.is_named(name))
   }
 
   fn get_header(&self, name: &str) -> Option<~str> {
-    let our_name = str::from_slice(name).to_lower();
-    let found = self.find(&our_name);
-    found.map(|s| s.clone())
+    match self.get_header_bytes(name) {
+      Some(bytes) => checked_str(bytes),
+      None => None
+    }
+  }
+
+  /* Like get_header, None when the header is missing or any of its values
+     is not valid UTF-8. */
+  fn get_all(&self, name: &str) -> Option<~[~str]> {
+    let mut values = ~[];
+
+    for self.entries.each |header| {
+      if header.is_named(name) {
+        match header.value_str() {
+          Some(value) => values.push(value),
+          None => return None
+        }
+      }
+    }
+
+    if values.is_empty() { None } else { Some(values) }
+  }
+
+  fn get_header_bytes(&self, name: &str) -> Option<~[u8]> {
+    let values = self.get_all_bytes(name);
+
+    if values.is_empty() {
+      return None;
+    }
+
+    let mut joined = ~[];
+
+    for values.eachi |index, value| {
+      if index > 0 {
+        joined.push_all(", ".to_bytes());
+      }
+
+      joined.push_all(*value);
+    }
+
+    Some(joined)
+  }
+
+  fn get_all_bytes(&self, name: &str) -> ~[~[u8]] {
+    let mut values = ~[];
+
+    for self.entries.each |header| {
+      if header.is_named(name) {
+        values.push(header.value.clone());
+      }
+    }
+
+    values
   }
 }
 
 impl HeadersMutable for HeaderMap {
-  fn set_header(&mut self, name: &str, value: &str) {
-    let our_name = str::from_slice(name).to_lower();
-    let our_value = str::from_slice(value);
-    self.insert(our_name, our_value);
+  fn set_header(&mut self, name: &str, value: &str) -> Result<(),HeaderSyntaxError> {
+    match check_header(name, value.to_bytes()) {
+      Ok(()) => {}
+      Err(error) => return Err(error)
+    }
+
+    let mut entries = ~[];
+    let mut replaced = false;
+
+    for self.entries.each |header| {
+      if !header.is_named(name) {
+        entries.push(header.clone());
+      } else if !replaced {
+        entries.push(Header {
+          name: str::from_slice(name),
+          value: value.to_bytes()
+        });
+        replaced = true;
+      }
+    }
+
+    self.entries = entries;
+
+    if replaced {
+      Ok(())
+    } else {
+      self.add_header(name, value)
+    }
+  }
+
+  fn add_header(&mut self, name: &str, value: &str) -> Result<(),HeaderSyntaxError> {
+    self.add_header_bytes(name, value.to_bytes())
+  }
+
+  fn add_header_bytes(&mut self, name: &str, value: &[u8]) -> Result<(),HeaderSyntaxError> {
+    match check_header(name, value) {
+      Ok(()) => {}
+      Err(error) => return Err(error)
+    }
+
+    self.entries.push(Header {
+      name: str::from_slice(name),
+      value: vec::from_slice(value)
+    });
+    Ok(())
   }
 
   fn remove_header(&mut self, name: &str) {
-    let our_name = str::from_slice(name).to_lower();
-    self.remove(&our_name);
+    let mut entries = ~[];
+
+    for self.entries.each |header| {
+      if !header.is_named(name) {
+        entries.push(header.clone());
+      }
+    }
+
+    self.entries = entries;
   }
 }
 
@@ -54,20 +226,62 @@ impl<T: Headers> HeadersUtil for T {
   }
 
   fn has_header_keyword(&self, name: &str, value: &str) -> bool{
-    let actual_value = self.get_header(name);
+    match self.get_all(name) {
+      Some(actual_values) => {
+        vec::any(actual_values, |actual_str| contains_keyword(*actual_str, value))
+      }
+      None => false
+    }
+  }
+
+  fn get_header_tokens(&self, name: &str) -> Result<~[~str],HeaderSyntaxError> {
+    let mut tokens = ~[];
+    let values = match decoded_values(self, name) {
+      Ok(values) => values,
+      Err(error) => return Err(error)
+    };
 
-    match actual_value {
-      Some(actual_str) => contains_keyword(actual_str, value),
-      _ => false
+    for values.each |value| {
+      match parse_token_list(*value) {
+        Ok(parsed) => tokens.push_all_move(parsed),
+        Err(error) => return Err(error)
+      }
     }
+
+    Ok(tokens)
+  }
+
+  fn get_header_elements(&self, name: &str) -> Result<~[HeaderElement],HeaderSyntaxError> {
+    let mut elements = ~[];
+    let values = match decoded_values(self, name) {
+      Ok(values) => values,
+      Err(error) => return Err(error)
+    };
+
+    for values.each |value| {
+      match parse_element_list(*value) {
+        Ok(parsed) => elements.push_all_move(parsed),
+        Err(error) => return Err(error)
+      }
+    }
+
+    Ok(elements)
+  }
+}
+
+fn decoded_values<T: Headers>(headers: &T, name: &str) -> Result<~[~str],HeaderSyntaxError> {
+  match headers.get_all(name) {
+    Some(values) => Ok(values),
+    None if headers.has_header(name) => Err(INVALID_ENCODING),
+    None => Ok(~[])
   }
 }
 
 fn contains_keyword(haystack: &str, needle: &str) -> bool {
   let lower_needle = needle.to_lower();
 
-  for haystack.each_split_char(',') |word| {
-    if lower_needle == word.trim().to_lower() {
+  for split_list(haystack).each |word| {
+    if lower_needle == word.to_lower() {
       return true
     }
   }
@@ -116,6 +330,171 @@ fn has_header_keyword_test() {
   assert!(headers.has_header_keyword("foo","pants"));
 }
 
+#[test]
+fn add_header_keeps_all_values_in_order_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("Sec-WebSocket-Protocol", "chat");
+  headers.add_header("sec-websocket-protocol", "superchat");
+
+  assert!(headers.get_all("SEC-WEBSOCKET-PROTOCOL") == Some(~[~"chat", ~"superchat"]));
+}
+
+#[test]
+fn get_header_combines_values_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("foo", "shoes");
+  headers.add_header("Foo", "paNts, frocks");
+
+  assert!(headers.get_header("foo") == Some(~"shoes, paNts, frocks"));
+}
+
+#[test]
+fn get_all_missing_header_test() {
+  let headers = HeaderMap::new();
+
+  assert!(headers.get_all("foo") == None);
+}
+
+#[test]
+fn set_header_replaces_all_values_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("foo", "shoes");
+  headers.add_header("foo", "pants");
+  headers.set_header("foo", "frocks");
+
+  assert!(headers.get_all("foo") == Some(~[~"frocks"]));
+}
+
+#[test]
+fn has_header_keyword_across_values_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("foo", "shoes");
+  headers.add_header("foo", "paNts, frocks");
+
+  assert!(headers.has_header_keyword("foo","shoes"));
+  assert!(headers.has_header_keyword("foo","frocks"));
+  assert!(!headers.has_header_keyword("foo","hats"));
+}
+
+#[test]
+fn has_header_keyword_ignores_quoted_commas_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.set_header("foo", "shoes; size=\"9, pants\", frocks");
+
+  assert!(!headers.has_header_keyword("foo","pants"));
+  assert!(headers.has_header_keyword("foo","frocks"));
+}
+
+#[test]
+fn get_header_tokens_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("Sec-WebSocket-Protocol", "chat, superchat");
+  headers.add_header("Sec-WebSocket-Protocol", "v2.chat");
+
+  assert!(headers.get_header_tokens("sec-websocket-protocol") ==
+          Ok(~[~"chat", ~"superchat", ~"v2.chat"]));
+  assert!(headers.get_header_tokens("missing") == Ok(~[]));
+}
+
+#[test]
+fn get_header_elements_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("Sec-WebSocket-Extensions", "permessage-deflate; client_max_window_bits=10");
+  headers.add_header("Sec-WebSocket-Extensions", "x-foo");
+
+  match headers.get_header_elements("Sec-WebSocket-Extensions") {
+    Ok(elements) => {
+      assert!(elements.len() == 2);
+      assert!(elements[0].name == ~"permessage-deflate");
+      assert!(elements[0].get_param("client_max_window_bits") == Some(~"10"));
+      assert!(elements[1] == HeaderElement::new("x-foo"));
+    }
+
+    Err(_) => fail!(~"Expected extensions to parse")
+  }
+}
+
+#[test]
+fn get_header_elements_error_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.set_header("Sec-WebSocket-Extensions", "x-foo; =bar");
+
+  assert!(headers.get_header_elements("Sec-WebSocket-Extensions") == Err(EXPECTED_TOKEN(7)));
+}
+
+#[test]
+fn headers_keep_original_case_and_order_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("Host", "example.com");
+  headers.add_header("X-Forwarded-For", "10.0.0.1");
+  headers.add_header("accept", "*/*");
+  headers.add_header("x-forwarded-for", "10.0.0.2");
+
+  let mut names = ~[];
+  for headers.each |header| {
+    names.push(header.name.clone());
+  }
+
+  assert!(names == ~[~"Host", ~"X-Forwarded-For", ~"accept", ~"x-forwarded-for"]);
+  assert!(headers.get_all("X-FORWARDED-FOR") == Some(~[~"10.0.0.1", ~"10.0.0.2"]));
+}
+
+#[test]
+fn set_header_keeps_position_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("Host", "example.com");
+  headers.add_header("Foo", "shoes");
+  headers.add_header("Accept", "*/*");
+  headers.add_header("foo", "pants");
+  headers.set_header("FOO", "frocks");
+
+  assert!(headers.to_wire_bytes() ==
+          "Host: example.com\r\nFOO: frocks\r\nAccept: */*\r\n".to_bytes());
+}
+
+#[test]
+fn to_wire_bytes_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("Host", "example.com");
+  headers.add_header("Sec-WebSocket-Protocol", "chat");
+  headers.add_header("sec-websocket-protocol", "superchat");
+
+  assert!(headers.len() == 3);
+  assert!(headers.to_wire_bytes() ==
+          "Host: example.com\r\n\
+           Sec-WebSocket-Protocol: chat\r\n\
+           sec-websocket-protocol: superchat\r\n".to_bytes());
+}
+
+#[test]
+fn binary_header_values_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header_bytes("X-Binary", [0x66, 0xFF, 0xFE]);
+  headers.add_header("X-Binary", "pants");
+
+  assert!(headers.has_header("x-binary"));
+  assert!(headers.get_header("x-binary") == None);
+  assert!(headers.get_all("x-binary") == None);
+  assert!(headers.get_header_tokens("x-binary") == Err(INVALID_ENCODING));
+  assert!(!headers.has_header_keyword("x-binary", "pants"));
+  assert!(headers.get_header_bytes("x-binary") ==
+          Some(~[0x66, 0xFF, 0xFE, 0x2C, 0x20, 0x70, 0x61, 0x6E, 0x74, 0x73]));
+  assert!(headers.get_all_bytes("x-binary") ==
+          ~[~[0x66, 0xFF, 0xFE], ~[0x70, 0x61, 0x6E, 0x74, 0x73]]);
+}
+
 #[test]
 fn remove_header_test() {
   let mut headers = HeaderMap::new();
@@ -125,3 +504,30 @@ fn remove_header_test() {
 
   assert!(!headers.has_header("foo"));
 }
+
+#[test]
+fn remove_repeated_header_test() {
+  let mut headers = HeaderMap::new();
+
+  headers.add_header("foO", "paNts");
+  headers.add_header("foO", "shoes");
+  headers.remove_header("fOo");
+
+  assert!(!headers.has_header("foo"));
+}
+
+#[test]
+fn rejects_unsafe_header_names_and_values() {
+  let mut headers = HeaderMap::new();
+
+  assert!(headers.set_header("X-Forwarded", "a\r\nSet-Cookie: evil") == Err(INVALID_HEADER_VALUE));
+  assert!(headers.add_header("X-Forwarded", "a\nb") == Err(INVALID_HEADER_VALUE));
+  assert!(headers.add_header_bytes("X-Forwarded", [0x61, 0x00]) == Err(INVALID_HEADER_VALUE));
+  assert!(headers.add_header("X Forwarded", "a") == Err(INVALID_HEADER_NAME));
+  assert!(headers.add_header("X-Forwarded:", "a") == Err(INVALID_HEADER_NAME));
+  assert!(headers.set_header("", "a") == Err(INVALID_HEADER_NAME));
+  assert!(headers.len() == 0);
+
+  assert!(headers.add_header("X-Forwarded", "a, \"b\"\tc") == Ok(()));
+  assert!(headers.to_wire_bytes() == "X-Forwarded: a, \"b\"\tc\r\n".to_bytes());
+}
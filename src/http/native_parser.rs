@@ -0,0 +1,1028 @@
+use http::errors::*;
+use http::grammar::{is_tchar, split_list};
+use http::headers::*;
+use http::request::*;
+
+static MAX_HEADER_SIZE: uint = 80 * 1024;
+static MAX_TOKEN_SIZE: uint = 16;
+static CR: u8 = 13u8;
+static LF: u8 = 10u8;
+
+#[deriving(Eq)]
+pub enum ParserType {
+  REQUEST,
+  RESPONSE
+}
+
+#[deriving(Eq)]
+enum ParserState {
+  START,
+  METHOD,
+  URL_START,
+  URL,
+  REQUEST_VERSION,
+  RESPONSE_VERSION,
+  STATUS_CODE,
+  REASON_PHRASE,
+  LINE_ALMOST_DONE,
+  HEADER_FIELD_START,
+  HEADER_FIELD,
+  HEADER_VALUE_START,
+  HEADER_VALUE,
+  HEADER_VALUE_ALMOST_DONE,
+  HEADERS_ALMOST_DONE,
+  BODY_IDENTITY,
+  BODY_IDENTITY_EOF,
+  CHUNK_SIZE_START,
+  CHUNK_SIZE,
+  CHUNK_PARAMETERS,
+  CHUNK_SIZE_ALMOST_DONE,
+  CHUNK_DATA,
+  CHUNK_DATA_ALMOST_DONE,
+  CHUNK_DATA_DONE,
+  UPGRADED,
+  CLOSED
+}
+
+pub struct Parser {
+  parser_type: ParserType,
+  state: ParserState,
+  result: ParseResult,
+  error: Option<HttpParseError>,
+  http_major: u16,
+  http_minor: u16,
+  status_code: uint,
+  token: ~[u8],
+  header_bytes: uint,
+  content_length: Option<uint>,
+  chunked: bool,
+  trailing: bool,
+  upgrade_header: bool,
+  connection_close: bool,
+  connection_keep_alive: bool,
+  upgrade: bool,
+  remaining: uint,
+  offset: uint,
+  consumed: uint
+}
+
+struct ParseResult {
+  url: Option<~[u8]>,
+  method: Option<Method>,
+  headers: HeaderMap,
+  partial_header_field: Option<~[u8]>,
+  partial_header_value: Option<~[u8]>,
+  header_count: uint,
+  largest_header: uint,
+  body: ~[u8],
+  message_begun: bool,
+  message_complete: bool
+}
+
+impl Parser {
+  pub fn parse(&self, input: &[u8]) -> Parser {
+    let mut parser = self.clone();
+    parser.execute(input);
+    parser
+  }
+
+  pub fn execute(&mut self, input: &[u8]) -> uint {
+    if self.error.is_some() || self.state == UPGRADED || self.result.message_complete {
+      self.offset = 0;
+      return 0;
+    }
+
+    if input.is_empty() {
+      self.error = self.read_eof();
+      self.offset = 0;
+      return 0;
+    }
+
+    let mut pos = 0;
+
+    while pos < input.len() && !self.result.message_complete {
+      match self.read(input, pos) {
+        Ok(next) => pos = next,
+        Err(error) => {
+          self.error = Some(error);
+          break;
+        }
+      }
+    }
+
+    self.offset = pos;
+    self.consumed += self.offset;
+    self.offset
+  }
+
+  pub fn finish(&self) -> Parser {
+    self.parse([])
+  }
+
+  pub fn message_begun(&self) -> bool {
+    self.result.message_begun
+  }
+
+  pub fn message_complete(&self) -> bool {
+    self.result.message_complete
+  }
+
+  pub fn next_message(&mut self) -> bool {
+    if self.result.message_complete {
+      self.result = ParseResult::new();
+      true
+    } else {
+      false
+    }
+  }
+
+  pub fn should_keep_alive(&self) -> bool {
+    let keep_alive = if self.http_major > 0 && self.http_minor > 0 {
+      !self.connection_close
+    } else {
+      self.connection_keep_alive
+    };
+
+    keep_alive && !self.needs_eof()
+  }
+
+  pub fn body(&self) -> ~[u8] {
+    self.result.body.clone()
+  }
+
+  pub fn take_body(&mut self) -> ~[u8] {
+    let mut body = ~[];
+    body <-> self.result.body;
+    body
+  }
+
+  pub fn success(&self) -> bool {
+    self.error.is_none()
+  }
+
+  pub fn error(&self) -> Option<HttpParseError> {
+    self.error
+  }
+
+  pub fn error_offset(&self) -> Option<uint> {
+    self.error().map(|_| self.consumed)
+  }
+
+  fn error_name(&self) -> ~str {
+    self.error.map_default(~"HPE_OK", |e| e.name())
+  }
+
+  fn error_description(&self) -> ~str {
+    self.error.map_default(~"success", |e| e.description())
+  }
+
+  pub fn upgrade(&self) -> bool {
+    self.upgrade
+  }
+
+  pub fn status_code(&self) -> Option<uint> {
+    match self.parser_type {
+      RESPONSE if self.status_code > 0 => Some(self.status_code),
+      _ => None
+    }
+  }
+
+  pub fn url_bytes(&self) -> Option<~[u8]> {
+    self.result.url.clone()
+  }
+
+  pub fn headers(&self) -> &'self HeaderMap {
+    &self.result.headers
+  }
+
+  pub fn bytes_consumed(&self) -> uint {
+    self.consumed
+  }
+
+  pub fn header_count(&self) -> uint {
+    self.result.header_count
+  }
+
+  pub fn largest_header_size(&self) -> uint {
+    let partial_size =
+      self.result.partial_header_field.map_default(0, |f| f.len()) +
+      self.result.partial_header_value.map_default(0, |v| v.len());
+
+    cmp::max(self.result.largest_header, partial_size)
+  }
+
+  priv fn read(&mut self, input: &[u8], pos: uint) -> Result<uint,HttpParseError> {
+    match self.state {
+      BODY_IDENTITY | CHUNK_DATA => return Ok(self.read_body(input, pos)),
+      BODY_IDENTITY_EOF => {
+        self.result.body.push_all(input.tailn(pos));
+        return Ok(input.len());
+      }
+      _ => {}
+    }
+
+    if self.in_head() {
+      self.header_bytes += 1;
+
+      if self.header_bytes > MAX_HEADER_SIZE {
+        return Err(HEADER_OVERFLOW);
+      }
+    }
+
+    match self.read_byte(input[pos]) {
+      Ok(()) => Ok(pos + 1),
+      Err(error) => Err(error)
+    }
+  }
+
+  priv fn read_body(&mut self, input: &[u8], pos: uint) -> uint {
+    let length = cmp::min(self.remaining, input.len() - pos);
+    self.result.body.push_all(input.slice(pos, pos + length));
+    self.remaining -= length;
+
+    if self.remaining == 0 {
+      if self.state == CHUNK_DATA {
+        self.state = CHUNK_DATA_ALMOST_DONE;
+      } else {
+        self.complete_message();
+      }
+    }
+
+    pos + length
+  }
+
+  priv fn read_eof(&mut self) -> Option<HttpParseError> {
+    match self.state {
+      START | CLOSED => None,
+
+      BODY_IDENTITY_EOF => {
+        self.complete_message();
+        None
+      }
+
+      _ => Some(INVALID_EOF_STATE)
+    }
+  }
+
+  priv fn read_byte(&mut self, c: u8) -> Result<(),HttpParseError> {
+    match self.state {
+      START => {
+        if c == CR || c == LF {
+          return Ok(());
+        }
+
+        self.begin_message();
+        self.read_byte(c)
+      }
+
+      CLOSED => {
+        if c == CR || c == LF {
+          Ok(())
+        } else {
+          Err(CLOSED_CONNECTION)
+        }
+      }
+
+      METHOD => {
+        if c == ' ' as u8 {
+          self.result.method = method_from_bytes(self.token);
+          self.token = ~[];
+          self.state = URL_START;
+
+          if self.result.method.is_none() { Err(INVALID_METHOD) } else { Ok(()) }
+        } else if is_upper(c) && self.token.len() < MAX_TOKEN_SIZE {
+          self.token.push(c);
+          Ok(())
+        } else {
+          Err(INVALID_METHOD)
+        }
+      }
+
+      URL_START => {
+        if is_url_char(c) {
+          self.result.url = Some(~[c]);
+          self.state = URL;
+          Ok(())
+        } else {
+          Err(INVALID_URL)
+        }
+      }
+
+      URL => {
+        if c == ' ' as u8 {
+          self.state = REQUEST_VERSION;
+        } else if c == CR || c == LF {
+          self.http_major = 0;
+          self.http_minor = 9;
+          self.state = if c == CR { LINE_ALMOST_DONE } else { HEADER_FIELD_START };
+        } else if is_url_char(c) {
+          match self.result.url {
+            Some(ref mut url) => url.push(c),
+            None => return Err(INVALID_INTERNAL_STATE)
+          }
+        } else {
+          return Err(INVALID_URL);
+        }
+
+        Ok(())
+      }
+
+      REQUEST_VERSION => {
+        if c == CR || c == LF {
+          match self.read_version() {
+            Ok(()) => {
+              self.state = if c == CR { LINE_ALMOST_DONE } else { HEADER_FIELD_START };
+              Ok(())
+            }
+
+            Err(error) => Err(error)
+          }
+        } else {
+          self.push_token(c, INVALID_VERSION)
+        }
+      }
+
+      RESPONSE_VERSION => {
+        if c == ' ' as u8 {
+          match self.read_version() {
+            Ok(()) => {
+              self.state = STATUS_CODE;
+              Ok(())
+            }
+
+            Err(error) => Err(error)
+          }
+        } else {
+          self.push_token(c, INVALID_CONSTANT)
+        }
+      }
+
+      STATUS_CODE => {
+        if is_digit(c) && self.token.len() < 3 {
+          self.status_code = self.status_code * 10 + (c - '0' as u8) as uint;
+          self.token.push(c);
+          Ok(())
+        } else if self.token.len() == 3 && (c == ' ' as u8 || c == CR || c == LF) {
+          self.token = ~[];
+          self.state = if c == ' ' as u8 {
+            REASON_PHRASE
+          } else if c == CR {
+            LINE_ALMOST_DONE
+          } else {
+            HEADER_FIELD_START
+          };
+
+          Ok(())
+        } else {
+          Err(INVALID_STATUS)
+        }
+      }
+
+      REASON_PHRASE => {
+        if c == CR {
+          self.state = LINE_ALMOST_DONE;
+        } else if c == LF {
+          self.state = HEADER_FIELD_START;
+        }
+
+        Ok(())
+      }
+
+      LINE_ALMOST_DONE => self.expect_lf(c, HEADER_FIELD_START),
+
+      HEADER_FIELD_START => {
+        if c == CR {
+          self.state = HEADERS_ALMOST_DONE;
+          Ok(())
+        } else if c == LF {
+          self.headers_complete()
+        } else if is_tchar(c) {
+          match self.complete_partial_header() {
+            Ok(()) => {
+              self.result.partial_header_field = Some(~[c]);
+              self.state = HEADER_FIELD;
+              Ok(())
+            }
+
+            Err(error) => Err(error)
+          }
+        } else {
+          Err(INVALID_HEADER_TOKEN)
+        }
+      }
+
+      HEADER_FIELD => {
+        if c == ':' as u8 {
+          self.state = HEADER_VALUE_START;
+          Ok(())
+        } else if is_tchar(c) {
+          push_partial(&mut self.result.partial_header_field, c)
+        } else {
+          Err(INVALID_HEADER_TOKEN)
+        }
+      }
+
+      HEADER_VALUE_START => {
+        if c == ' ' as u8 || c == '\t' as u8 {
+          return Ok(());
+        }
+
+        self.result.partial_header_value = Some(~[]);
+        self.state = HEADER_VALUE;
+        self.read_byte(c)
+      }
+
+      HEADER_VALUE => {
+        if c == CR {
+          self.state = HEADER_VALUE_ALMOST_DONE;
+          Ok(())
+        } else if c == LF {
+          self.state = HEADER_FIELD_START;
+          Ok(())
+        } else {
+          push_partial(&mut self.result.partial_header_value, c)
+        }
+      }
+
+      HEADER_VALUE_ALMOST_DONE => self.expect_lf(c, HEADER_FIELD_START),
+
+      HEADERS_ALMOST_DONE => {
+        if c == LF {
+          self.headers_complete()
+        } else {
+          Err(LF_EXPECTED)
+        }
+      }
+
+      CHUNK_SIZE_START => {
+        match hex_value(c) {
+          Some(value) => {
+            self.remaining = value;
+            self.state = CHUNK_SIZE;
+            Ok(())
+          }
+
+          None => Err(INVALID_CHUNK_SIZE)
+        }
+      }
+
+      CHUNK_SIZE => {
+        match hex_value(c) {
+          Some(value) => {
+            if self.remaining > (uint::max_value - value) / 16 {
+              return Err(INVALID_CHUNK_SIZE);
+            }
+
+            self.remaining = self.remaining * 16 + value;
+            Ok(())
+          }
+
+          None => {
+            if c == ';' as u8 || c == ' ' as u8 {
+              self.state = CHUNK_PARAMETERS;
+              Ok(())
+            } else if c == CR {
+              self.state = CHUNK_SIZE_ALMOST_DONE;
+              Ok(())
+            } else if c == LF {
+              self.chunk_size_complete();
+              Ok(())
+            } else {
+              Err(INVALID_CHUNK_SIZE)
+            }
+          }
+        }
+      }
+
+      CHUNK_PARAMETERS => {
+        if c == CR {
+          self.state = CHUNK_SIZE_ALMOST_DONE;
+        } else if c == LF {
+          self.chunk_size_complete();
+        }
+
+        Ok(())
+      }
+
+      CHUNK_SIZE_ALMOST_DONE => {
+        if c == LF {
+          self.chunk_size_complete();
+          Ok(())
+        } else {
+          Err(LF_EXPECTED)
+        }
+      }
+
+      CHUNK_DATA_ALMOST_DONE => {
+        if c == CR {
+          self.state = CHUNK_DATA_DONE;
+          Ok(())
+        } else {
+          self.expect_lf(c, CHUNK_SIZE_START)
+        }
+      }
+
+      CHUNK_DATA_DONE => self.expect_lf(c, CHUNK_SIZE_START),
+
+      BODY_IDENTITY | BODY_IDENTITY_EOF | CHUNK_DATA | UPGRADED => {
+        Err(INVALID_INTERNAL_STATE)
+      }
+    }
+  }
+
+  priv fn in_head(&self) -> bool {
+    match self.state {
+      START | CLOSED | BODY_IDENTITY | BODY_IDENTITY_EOF | CHUNK_SIZE_START |
+      CHUNK_SIZE | CHUNK_PARAMETERS | CHUNK_SIZE_ALMOST_DONE | CHUNK_DATA |
+      CHUNK_DATA_ALMOST_DONE | CHUNK_DATA_DONE | UPGRADED => false,
+      _ => true
+    }
+  }
+
+  priv fn expect_lf(&mut self, c: u8, next: ParserState) -> Result<(),HttpParseError> {
+    if c == LF {
+      self.state = next;
+      Ok(())
+    } else {
+      Err(LF_EXPECTED)
+    }
+  }
+
+  priv fn push_token(&mut self, c: u8, error: HttpParseError) -> Result<(),HttpParseError> {
+    if self.token.len() < MAX_TOKEN_SIZE {
+      self.token.push(c);
+      Ok(())
+    } else {
+      Err(error)
+    }
+  }
+
+  priv fn read_version(&mut self) -> Result<(),HttpParseError> {
+    let mut token = ~[];
+    token <-> self.token;
+
+    match parse_version(token) {
+      Ok((major, minor)) => {
+        self.http_major = major;
+        self.http_minor = minor;
+        Ok(())
+      }
+
+      Err(error) => Err(error)
+    }
+  }
+
+  priv fn complete_partial_header(&mut self) -> Result<(),HttpParseError> {
+    if self.result.partial_header_value.is_none() { return Ok(()); }
+
+    let mut new_field = None;
+    let mut new_value = None;
+
+    new_field <-> self.result.partial_header_field;
+    new_value <-> self.result.partial_header_value;
+
+    match (new_field, new_value) {
+      (Some(field), Some(value)) => {
+        match checked_str(field) {
+          Some(name) => {
+            match self.read_framing_header(name, value) {
+              Ok(()) => {}
+              Err(error) => return Err(error)
+            }
+
+            self.result.header_count += 1;
+            self.result.largest_header = cmp::max(self.result.largest_header,
+                                                  name.len() + value.len());
+            match self.result.headers.add_header_bytes(name, value) {
+              Ok(()) => Ok(()),
+              Err(_) => Err(INVALID_HEADER_TOKEN)
+            }
+          }
+
+          None => Err(INVALID_HEADER_TOKEN)
+        }
+      }
+
+      _ => Err(INVALID_INTERNAL_STATE)
+    }
+  }
+
+  priv fn read_framing_header(&mut self, name: &str, value: &[u8])
+     -> Result<(),HttpParseError> {
+    let lower_name = name.to_lower();
+
+    if lower_name == ~"content-length" {
+      match parse_content_length(value) {
+        Some(length) => self.content_length = Some(length),
+        None => return Err(INVALID_CONTENT_LENGTH)
+      }
+    } else if lower_name == ~"transfer-encoding" {
+      let codings = match checked_str(value) {
+        Some(codings) => split_list(codings),
+        None => ~[]
+      };
+
+      self.chunked = !codings.is_empty() && codings.last().to_lower() == ~"chunked";
+    } else if lower_name == ~"upgrade" {
+      self.upgrade_header = true;
+    } else if lower_name == ~"connection" {
+      let options = match checked_str(value) {
+        Some(options) => split_list(options),
+        None => ~[]
+      };
+
+      for options.each |option| {
+        let lower_option = option.to_lower();
+
+        if lower_option == ~"close" {
+          self.connection_close = true;
+        } else if lower_option == ~"keep-alive" {
+          self.connection_keep_alive = true;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  priv fn headers_complete(&mut self) -> Result<(),HttpParseError> {
+    match self.complete_partial_header() {
+      Ok(()) => {}
+      Err(error) => return Err(error)
+    }
+
+    self.header_bytes = 0;
+
+    if self.trailing {
+      self.complete_message();
+      return Ok(());
+    }
+
+    self.upgrade = self.upgrade_header || self.result.method == Some(CONNECT);
+
+    if self.upgrade {
+      self.state = UPGRADED;
+      self.result.message_complete = true;
+    } else if self.chunked {
+      self.state = CHUNK_SIZE_START;
+    } else {
+      match self.content_length {
+        Some(0) => self.complete_message(),
+
+        Some(length) => {
+          self.remaining = length;
+          self.state = BODY_IDENTITY;
+        }
+
+        None => {
+          if !self.needs_eof() {
+            self.complete_message();
+          } else {
+            self.state = BODY_IDENTITY_EOF;
+          }
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  priv fn needs_eof(&self) -> bool {
+    if self.parser_type == REQUEST {
+      return false;
+    }
+
+    if self.status_code / 100 == 1 || self.status_code == 204 || self.status_code == 304 {
+      return false;
+    }
+
+    !self.chunked && self.content_length.is_none()
+  }
+
+  priv fn chunk_size_complete(&mut self) {
+    if self.remaining == 0 {
+      self.trailing = true;
+      self.state = HEADER_FIELD_START;
+    } else {
+      self.state = CHUNK_DATA;
+    }
+  }
+
+  priv fn begin_message(&mut self) {
+    self.state = match self.parser_type {
+      REQUEST => METHOD,
+      RESPONSE => RESPONSE_VERSION
+    };
+
+    self.status_code = 0;
+    self.header_bytes = 0;
+    self.content_length = None;
+    self.chunked = false;
+    self.trailing = false;
+    self.upgrade_header = false;
+    self.connection_close = false;
+    self.connection_keep_alive = false;
+    self.remaining = 0;
+    self.result.message_begun = true;
+  }
+
+  priv fn complete_message(&mut self) {
+    self.state = if self.should_keep_alive() { START } else { CLOSED };
+    self.result.message_complete = true;
+  }
+}
+
+impl Request for Parser {
+  fn method(&self) -> Option<Method> {
+    self.result.method
+  }
+
+  fn url(&self) -> Option<~str> {
+    match self.result.url {
+      Some(ref bytes) => checked_str(*bytes),
+      None => None
+    }
+  }
+
+  fn http_version(&self) -> Option<HttpVersion> {
+    if self.http_major > 0 {
+      Some(HttpVersion(self.http_major, self.http_minor))
+    } else {
+      None
+    }
+  }
+}
+
+impl Headers for Parser {
+  fn get_header(&self, name: &str) -> Option<~str> {
+    self.result.headers.get_header(name)
+  }
+
+  fn get_all(&self, name: &str) -> Option<~[~str]> {
+    self.result.headers.get_all(name)
+  }
+
+  fn get_header_bytes(&self, name: &str) -> Option<~[u8]> {
+    self.result.headers.get_header_bytes(name)
+  }
+
+  fn get_all_bytes(&self, name: &str) -> ~[~[u8]] {
+    self.result.headers.get_all_bytes(name)
+  }
+
+  fn has_header(&self, name: &str) -> bool {
+    self.result.headers.has_header(name)
+  }
+}
+
+impl ParseResult {
+  fn header(&self, name: &str) -> Option<~str> {
+    self.headers.get_header(name)
+  }
+
+  fn new() -> ParseResult {
+    ParseResult {
+       url: None,
+       method: None,
+       headers: HeaderMap::new(),
+       partial_header_field: None,
+       partial_header_value: None,
+       header_count: 0,
+       largest_header: 0,
+       body: ~[],
+       message_begun: false,
+       message_complete: false
+    }
+  }
+}
+
+impl Clone for Parser {
+  fn clone(&self) -> Parser {
+    Parser {
+      parser_type: self.parser_type,
+      state: self.state,
+      result: self.result.clone(),
+      error: self.error,
+      http_major: self.http_major,
+      http_minor: self.http_minor,
+      status_code: self.status_code,
+      token: self.token.clone(),
+      header_bytes: self.header_bytes,
+      content_length: self.content_length,
+      chunked: self.chunked,
+      trailing: self.trailing,
+      upgrade_header: self.upgrade_header,
+      connection_close: self.connection_close,
+      connection_keep_alive: self.connection_keep_alive,
+      upgrade: self.upgrade,
+      remaining: self.remaining,
+      offset: self.offset,
+      consumed: self.consumed
+    }
+  }
+}
+
+impl Clone for ParseResult {
+  fn clone(&self) -> ParseResult {
+    ParseResult {
+       url: self.url.clone(),
+       method: self.method,
+       headers: self.headers.clone(),
+       partial_header_field: self.partial_header_field.clone(),
+       partial_header_value: self.partial_header_value.clone(),
+       header_count: self.header_count,
+       largest_header: self.largest_header,
+       body: self.body.clone(),
+       message_begun: self.message_begun,
+       message_complete: self.message_complete
+    }
+  }
+}
+
+fn push_partial(target: &mut Option<~[u8]>, c: u8) -> Result<(),HttpParseError> {
+  match *target {
+    Some(ref mut bytes) => {
+      bytes.push(c);
+      Ok(())
+    }
+
+    None => Err(INVALID_INTERNAL_STATE)
+  }
+}
+
+fn is_upper(c: u8) -> bool {
+  c >= 'A' as u8 && c <= 'Z' as u8 || c == '-' as u8
+}
+
+fn is_digit(c: u8) -> bool {
+  c >= '0' as u8 && c <= '9' as u8
+}
+
+fn is_url_char(c: u8) -> bool {
+  c > ' ' as u8 && c < 0x7F
+}
+
+fn hex_value(c: u8) -> Option<uint> {
+  if is_digit(c) {
+    Some((c - '0' as u8) as uint)
+  } else if c >= 'a' as u8 && c <= 'f' as u8 {
+    Some((c - 'a' as u8) as uint + 10)
+  } else if c >= 'A' as u8 && c <= 'F' as u8 {
+    Some((c - 'A' as u8) as uint + 10)
+  } else {
+    None
+  }
+}
+
+fn parse_version(bytes: &[u8]) -> Result<(u16,u16),HttpParseError> {
+  let prefix = "HTTP/";
+
+  if bytes.len() < prefix.len() {
+    return Err(INVALID_CONSTANT);
+  }
+
+  for uint::range(0, prefix.len()) |i| {
+    if bytes[i] != prefix[i] {
+      return Err(INVALID_CONSTANT);
+    }
+  }
+
+  let mut numbers = ~[0u16];
+  let mut digits = 0;
+
+  for bytes.tailn(prefix.len()).each |c| {
+    if is_digit(*c) && digits < 3 {
+      let last = numbers.len() - 1;
+      numbers[last] = numbers[last] * 10 + (*c - '0' as u8) as u16;
+      digits += 1;
+    } else if *c == '.' as u8 && digits > 0 && numbers.len() == 1 {
+      numbers.push(0);
+      digits = 0;
+    } else {
+      return Err(INVALID_VERSION);
+    }
+  }
+
+  if numbers.len() == 2 && digits > 0 {
+    Ok((numbers[0], numbers[1]))
+  } else {
+    Err(INVALID_VERSION)
+  }
+}
+
+fn parse_content_length(value: &[u8]) -> Option<uint> {
+  let mut length = 0u;
+  let mut digits = 0;
+  let mut trailing_space = false;
+
+  for value.each |c| {
+    if is_digit(*c) && !trailing_space {
+      let digit = (*c - '0' as u8) as uint;
+
+      if length > (uint::max_value - digit) / 10 {
+        return None;
+      }
+
+      length = length * 10 + digit;
+      digits += 1;
+    } else if *c == ' ' as u8 || *c == '\t' as u8 {
+      trailing_space = true;
+    } else {
+      return None;
+    }
+  }
+
+  if digits > 0 { Some(length) } else { None }
+}
+
+fn method_from_bytes(bytes: &[u8]) -> Option<Method> {
+  let methods = [
+    ("DELETE", DELETE), ("GET", GET), ("HEAD", HEAD), ("POST", POST),
+    ("PUT", PUT), ("CONNECT", CONNECT), ("OPTIONS", OPTIONS),
+    ("TRACE", TRACE), ("COPY", COPY), ("LOCK", LOCK), ("MKCOL", MKCOL),
+    ("MOVE", MOVE), ("PROPFIND", PROPFIND), ("PROPPATCH", PROPPATCH),
+    ("SEARCH", SEARCH), ("UNLOCK", UNLOCK), ("REPORT", REPORT),
+    ("MKACTIVITY", MKACTIVITY), ("CHECKOUT", CHECKOUT), ("MERGE", MERGE),
+    ("M-SEARCH", MSEARCH), ("NOTIFY", NOTIFY), ("SUBSCRIBE", SUBSCRIBE),
+    ("UNSUBSCRIBE", UNSUBSCRIBE), ("PATCH", PATCH), ("PURGE", PURGE)
+  ];
+
+  for methods.each |entry| {
+    match *entry {
+      (name, method) => {
+        if name.to_bytes() == vec::from_slice(bytes) {
+          return Some(method);
+        }
+      }
+    }
+  }
+
+  None
+}
+
+fn new_parser(parser_type: ParserType) -> Parser {
+  Parser {
+    parser_type: parser_type,
+    state: START,
+    result: ParseResult::new(),
+    error: None,
+    http_major: 0,
+    http_minor: 0,
+    status_code: 0,
+    token: ~[],
+    header_bytes: 0,
+    content_length: None,
+    chunked: false,
+    trailing: false,
+    upgrade_header: false,
+    connection_close: false,
+    connection_keep_alive: false,
+    upgrade: false,
+    remaining: 0,
+    offset: 0,
+    consumed: 0
+  }
+}
+
+pub fn initial_parser() -> Parser {
+  new_parser(REQUEST)
+}
+
+pub fn initial_response_parser() -> Parser {
+  new_parser(RESPONSE)
+}
+
+#[test]
+fn parse_version_test() {
+  assert!(parse_version("HTTP/1.1".to_bytes()) == Ok((1,1)));
+  assert!(parse_version("HTTP/10.0".to_bytes()) == Ok((10,0)));
+  assert!(parse_version("HTTX/1.1".to_bytes()) == Err(INVALID_CONSTANT));
+  assert!(parse_version("HTTP/1".to_bytes()) == Err(INVALID_VERSION));
+  assert!(parse_version("HTTP/1.1.1".to_bytes()) == Err(INVALID_VERSION));
+  assert!(parse_version("HTTP/1000.1".to_bytes()) == Err(INVALID_VERSION));
+}
+
+#[test]
+fn parse_content_length_test() {
+  assert!(parse_content_length("42".to_bytes()) == Some(42));
+  assert!(parse_content_length("42 ".to_bytes()) == Some(42));
+  assert!(parse_content_length("4 2".to_bytes()) == None);
+  assert!(parse_content_length("".to_bytes()) == None);
+  assert!(parse_content_length("-1".to_bytes()) == None);
+  assert!(parse_content_length("99999999999999999999999".to_bytes()) == None);
+}
+
+#[test]
+fn method_from_bytes_test() {
+  assert!(method_from_bytes("GET".to_bytes()) == Some(GET));
+  assert!(method_from_bytes("M-SEARCH".to_bytes()) == Some(MSEARCH));
+  assert!(method_from_bytes("YURT".to_bytes()) == None);
+}
+
+#[test]
+fn hex_value_test() {
+  assert!(hex_value('7' as u8) == Some(7));
+  assert!(hex_value('a' as u8) == Some(10));
+  assert!(hex_value('F' as u8) == Some(15));
+  assert!(hex_value('g' as u8) == None);
+}
@@ -1,82 +1,139 @@
 extern mod std;
 
 use http_parser;
-use http_parser::{http_parser_init, http_parser_execute, HTTP_REQUEST};
-use core::ptr::{null, to_unsafe_ptr};
-use core::libc::{c_int, c_char, size_t, c_void, c_uint};
-use core::cast::{reinterpret_cast};
+use http_parser::{http_parser_init, http_parser_execute, http_parser_pause};
+use http_parser::{http_should_keep_alive, HTTP_REQUEST, HTTP_RESPONSE};
+use core::ptr::{null, to_unsafe_ptr, to_mut_unsafe_ptr};
+use core::libc::{c_int, c_schar, size_t, c_void, c_uint};
+use core::cast::{transmute};
+use http::errors::*;
 use http::headers::*;
 use http::request::*;
 
 pub struct Parser {
   parser: ~http_parser::http_parser,
   result: ParseResult,
-  offset: uint
+  offset: uint,
+  consumed: uint
 }
 
 struct ParseResult {
-  url: Option<~str>,
+  url: Option<~[u8]>,
   method: Option<Method>,
   headers: HeaderMap,
-  partial_header_field: Option<~str>,
-  partial_header_value: Option<~str>
+  partial_header_field: Option<~[u8]>,
+  partial_header_value: Option<~[u8]>,
+  header_count: uint,
+  largest_header: uint,
+  body: ~[u8],
+  message_begun: bool,
+  message_complete: bool
 }
 
 impl Parser {
-  pub fn parse(&self, input: &str) -> Parser {
-    let mut result = self.result.clone();
+  pub fn parse(&self, input: &[u8]) -> Parser {
+    let mut parser = self.clone();
+    parser.execute(input);
+    parser
+  }
+
+  pub fn execute(&mut self, input: &[u8]) -> uint {
+    if self.result.message_complete {
+      self.offset = 0;
+      return 0;
+    }
 
     let s = http_parser::Struct_http_parser_settings {
-      on_message_begin: null(),
+      on_message_begin: on_message_begin,
       on_url: on_url,
       on_status_complete: null(),
       on_header_field: on_header_field,
       on_header_value: on_header_value,
       on_headers_complete: on_headers_complete,
-      on_body: null(),
-      on_message_complete: null(),
+      on_body: on_body,
+      on_message_complete: on_message_complete,
     };
 
-    let mut p = ~*self.parser;
     let mut offset = 0;
-    p.data = to_unsafe_ptr(&result) as *c_void;
+    self.parser.data = to_mut_unsafe_ptr(&mut self.result) as *c_void;
 
-    do str::as_c_str(input) |buf| {
+    do vec::as_imm_buf(input) |buf, len| {
       unsafe {
-        offset = http_parser_execute(to_unsafe_ptr(p),
+        offset = http_parser_execute(to_unsafe_ptr(self.parser),
                                      &s,
-                                     buf,
-                                     input.len() as u64);
+                                     buf as *c_schar,
+                                     len as u64);
       }
     }
 
-    Parser {
-      parser: p,
-      result: result,
-      offset: offset as uint
+    self.parser.data = null();
+
+    if self.errno() == http_parser::HPE_PAUSED {
+      unsafe { http_parser_pause(to_unsafe_ptr(self.parser), 0); }
     }
+
+    self.offset = offset as uint;
+    self.consumed += self.offset;
+    self.offset
   }
 
   pub fn finish(&self) -> Parser {
-    self.parse("")
+    self.parse([])
+  }
+
+  pub fn message_begun(&self) -> bool {
+    self.result.message_begun
+  }
+
+  pub fn message_complete(&self) -> bool {
+    self.result.message_complete
+  }
+
+  pub fn next_message(&mut self) -> bool {
+    if self.result.message_complete {
+      self.result = ParseResult::new();
+      true
+    } else {
+      false
+    }
+  }
+
+  pub fn should_keep_alive(&self) -> bool {
+    unsafe { http_should_keep_alive(to_unsafe_ptr(self.parser)) != 0 }
+  }
+
+  pub fn body(&self) -> ~[u8] {
+    self.result.body.clone()
+  }
+
+  pub fn take_body(&mut self) -> ~[u8] {
+    let mut body = ~[];
+    body <-> self.result.body;
+    body
   }
 
   pub fn success(&self) -> bool {
     self.errno() == http_parser::HPE_OK
   }
 
-  fn error_name(&self) -> ~str {
-    unsafe {
-      let c_str = http_parser::http_errno_name(self.errno());
-      str::raw::from_c_str(c_str)
+  pub fn error(&self) -> Option<HttpParseError> {
+    if self.success() {
+      None
+    } else {
+      Some(HttpParseError::from_errno(self.errno()))
     }
   }
 
+  pub fn error_offset(&self) -> Option<uint> {
+    self.error().map(|_| self.consumed)
+  }
+
+  fn error_name(&self) -> ~str {
+    HttpParseError::from_errno(self.errno()).name()
+  }
+
   fn error_description(&self) -> ~str {
-    unsafe {
-      let c_str = http_parser::http_errno_description(self.errno());
-      str::raw::from_c_str(c_str)
-    }
+    HttpParseError::from_errno(self.errno()).description()
   }
 
   priv fn errno(&self) -> u32 {
@@ -86,6 +143,107 @@ impl Parser {
   pub fn upgrade(&self) -> bool {
     (self.parser.http_errno_upgrade & 0x80) == 0x80
   }
+
+  pub fn status_code(&self) -> Option<uint> {
+    match self.parser.status_code {
+      0 => None,
+      code => Some(code as uint)
+    }
+  }
+
+  pub fn url_bytes(&self) -> Option<~[u8]> {
+    self.result.url.clone()
+  }
+
+  pub fn headers(&self) -> &'self HeaderMap {
+    &self.result.headers
+  }
+
+  pub fn bytes_consumed(&self) -> uint {
+    self.consumed
+  }
+
+  pub fn header_count(&self) -> uint {
+    self.result.header_count
+  }
+
+  pub fn largest_header_size(&self) -> uint {
+    let partial_size =
+      self.result.partial_header_field.map_default(0, |f| f.len()) +
+      self.result.partial_header_value.map_default(0, |v| v.len());
+
+    cmp::max(self.result.largest_header, partial_size)
+  }
+}
+
+impl HttpParseError {
+  pub fn from_errno(errno: u32) -> HttpParseError {
+    match errno {
+      http_parser::HPE_CB_message_begin => CB_MESSAGE_BEGIN,
+      http_parser::HPE_CB_status_complete => CB_STATUS_COMPLETE,
+      http_parser::HPE_CB_url => CB_URL,
+      http_parser::HPE_CB_header_field => CB_HEADER_FIELD,
+      http_parser::HPE_CB_header_value => CB_HEADER_VALUE,
+      http_parser::HPE_CB_headers_complete => CB_HEADERS_COMPLETE,
+      http_parser::HPE_CB_body => CB_BODY,
+      http_parser::HPE_CB_message_complete => CB_MESSAGE_COMPLETE,
+      http_parser::HPE_INVALID_EOF_STATE => INVALID_EOF_STATE,
+      http_parser::HPE_HEADER_OVERFLOW => HEADER_OVERFLOW,
+      http_parser::HPE_CLOSED_CONNECTION => CLOSED_CONNECTION,
+      http_parser::HPE_INVALID_VERSION => INVALID_VERSION,
+      http_parser::HPE_INVALID_STATUS => INVALID_STATUS,
+      http_parser::HPE_INVALID_METHOD => INVALID_METHOD,
+      http_parser::HPE_INVALID_URL => INVALID_URL,
+      http_parser::HPE_INVALID_HOST => INVALID_HOST,
+      http_parser::HPE_INVALID_PORT => INVALID_PORT,
+      http_parser::HPE_INVALID_PATH => INVALID_PATH,
+      http_parser::HPE_INVALID_QUERY_STRING => INVALID_QUERY_STRING,
+      http_parser::HPE_INVALID_FRAGMENT => INVALID_FRAGMENT,
+      http_parser::HPE_LF_EXPECTED => LF_EXPECTED,
+      http_parser::HPE_INVALID_HEADER_TOKEN => INVALID_HEADER_TOKEN,
+      http_parser::HPE_INVALID_CONTENT_LENGTH => INVALID_CONTENT_LENGTH,
+      http_parser::HPE_INVALID_CHUNK_SIZE => INVALID_CHUNK_SIZE,
+      http_parser::HPE_INVALID_CONSTANT => INVALID_CONSTANT,
+      http_parser::HPE_INVALID_INTERNAL_STATE => INVALID_INTERNAL_STATE,
+      http_parser::HPE_STRICT => STRICT,
+      http_parser::HPE_PAUSED => PAUSED,
+      _ => UNKNOWN
+    }
+  }
+
+  pub fn to_errno(&self) -> u32 {
+    match *self {
+      CB_MESSAGE_BEGIN => http_parser::HPE_CB_message_begin,
+      CB_STATUS_COMPLETE => http_parser::HPE_CB_status_complete,
+      CB_URL => http_parser::HPE_CB_url,
+      CB_HEADER_FIELD => http_parser::HPE_CB_header_field,
+      CB_HEADER_VALUE => http_parser::HPE_CB_header_value,
+      CB_HEADERS_COMPLETE => http_parser::HPE_CB_headers_complete,
+      CB_BODY => http_parser::HPE_CB_body,
+      CB_MESSAGE_COMPLETE => http_parser::HPE_CB_message_complete,
+      INVALID_EOF_STATE => http_parser::HPE_INVALID_EOF_STATE,
+      HEADER_OVERFLOW => http_parser::HPE_HEADER_OVERFLOW,
+      CLOSED_CONNECTION => http_parser::HPE_CLOSED_CONNECTION,
+      INVALID_VERSION => http_parser::HPE_INVALID_VERSION,
+      INVALID_STATUS => http_parser::HPE_INVALID_STATUS,
+      INVALID_METHOD => http_parser::HPE_INVALID_METHOD,
+      INVALID_URL => http_parser::HPE_INVALID_URL,
+      INVALID_HOST => http_parser::HPE_INVALID_HOST,
+      INVALID_PORT => http_parser::HPE_INVALID_PORT,
+      INVALID_PATH => http_parser::HPE_INVALID_PATH,
+      INVALID_QUERY_STRING => http_parser::HPE_INVALID_QUERY_STRING,
+      INVALID_FRAGMENT => http_parser::HPE_INVALID_FRAGMENT,
+      LF_EXPECTED => http_parser::HPE_LF_EXPECTED,
+      INVALID_HEADER_TOKEN => http_parser::HPE_INVALID_HEADER_TOKEN,
+      INVALID_CONTENT_LENGTH => http_parser::HPE_INVALID_CONTENT_LENGTH,
+      INVALID_CHUNK_SIZE => http_parser::HPE_INVALID_CHUNK_SIZE,
+      INVALID_CONSTANT => http_parser::HPE_INVALID_CONSTANT,
+      INVALID_INTERNAL_STATE => http_parser::HPE_INVALID_INTERNAL_STATE,
+      STRICT => http_parser::HPE_STRICT,
+      PAUSED => http_parser::HPE_PAUSED,
+      UNKNOWN => http_parser::HPE_UNKNOWN
+    }
+  }
 }
 
 impl Request for Parser {
@@ -93,6 +251,13 @@ impl Request for Parser {
     self.result.method
   }
 
+  fn url(&self) -> Option<~str> {
+    match self.result.url {
+      Some(ref bytes) => checked_str(*bytes),
+      None => None
+    }
+  }
+
   fn http_version(&self) -> Option<HttpVersion> {
     if self.parser.http_major > 0 {
       Some(HttpVersion(self.parser.http_major,
@@ -108,6 +273,18 @@ impl Headers for Parser {
     self.result.headers.get_header(name)
   }
 
+  fn get_all(&self, name: &str) -> Option<~[~str]> {
+    self.result.headers.get_all(name)
+  }
+
+  fn get_header_bytes(&self, name: &str) -> Option<~[u8]> {
+    self.result.headers.get_header_bytes(name)
+  }
+
+  fn get_all_bytes(&self, name: &str) -> ~[~[u8]] {
+    self.result.headers.get_all_bytes(name)
+  }
+
   fn has_header(&self, name: &str) -> bool {
     self.result.headers.has_header(name)
   }
@@ -124,7 +301,23 @@ impl ParseResult {
        method: None,
        headers: HeaderMap::new(),
        partial_header_field: None,
-       partial_header_value: None
+       partial_header_value: None,
+       header_count: 0,
+       largest_header: 0,
+       body: ~[],
+       message_begun: false,
+       message_complete: false
+    }
+  }
+}
+
+impl Clone for Parser {
+  fn clone(&self) -> Parser {
+    Parser {
+      parser: ~*self.parser,
+      result: self.result.clone(),
+      offset: self.offset,
+      consumed: self.consumed
     }
   }
 }
@@ -134,49 +327,63 @@ impl Clone for ParseResult {
     ParseResult {
        url: self.url.clone(),
        method: self.method,
-       headers: copy_headers(&self.headers),
+       headers: self.headers.clone(),
        partial_header_field: self.partial_header_field.clone(),
-       partial_header_value: self.partial_header_value.clone()
+       partial_header_value: self.partial_header_value.clone(),
+       header_count: self.header_count,
+       largest_header: self.largest_header,
+       body: self.body.clone(),
+       message_begun: self.message_begun,
+       message_complete: self.message_complete
     }
   }
 }
 
-fn copy_headers(headers: &HeaderMap) -> HeaderMap {
-  let mut copied = HeaderMap::new();
+fn with_result_in_callback(p: *http_parser::Struct_http_parser,
+                           f: &fn(&mut ParseResult) -> bool) -> c_int {
+  unsafe {
+    let data = (*p).data;
+
+    if data.is_null() {
+      return callback_status(false);
+    }
 
-  for headers.each_key() |k| {
-    let v = headers.find(k).expect("Invalid key while iterating over headers");
-    copied.set_header(*k, *v);
+    let result: &mut ParseResult = transmute(data);
+    callback_status(f(result))
   }
+}
 
-  copied
+fn bytes_in_callback(at: *u8, length: size_t) -> ~[u8] {
+  unsafe { vec::from_buf(at, length as uint) }
 }
 
+fn append_bytes(target: &mut Option<~[u8]>, bytes: &[u8]) {
+  let mut current: Option<~[u8]> = None;
+  current <-> *target;
 
-fn result_in_callback(p: *http_parser::Struct_http_parser) -> &mut ParseResult {
-  unsafe { reinterpret_cast(&(*p).data) }
-}
+  let mut so_far = current.get_or_default(~[]);
+  so_far.push_all(bytes);
 
-fn string_in_callback(at: *u8, length: size_t) -> ~str {
-  unsafe { str::raw::from_c_str_len(at as *c_char, length as uint) }
+  *target = Some(so_far);
 }
 
 extern fn on_url(p: *http_parser::Struct_http_parser,
                  at: *u8,
                  length: size_t) -> c_int {
-  let url = string_in_callback(at, length);
-  let result = result_in_callback(p);
+  let url = bytes_in_callback(at, length);
 
-  let mut current_url: Option<~str> = None;
-  current_url <-> result.url;
-  let new_url = current_url.get_or_default(~"") + url;
-  result.url = Some(new_url);
+  do with_result_in_callback(p) |result| {
+    append_bytes(&mut result.url, url);
+    true
+  }
+}
 
-  0
+fn callback_status(ok: bool) -> c_int {
+  if ok { 0 } else { 1 }
 }
 
-fn complete_partial_header(result: &mut ParseResult) {
-  if result.partial_header_value.is_none() { return; }
+fn complete_partial_header(result: &mut ParseResult) -> bool {
+  if result.partial_header_value.is_none() { return true; }
 
   let mut new_field = None;
   let mut new_value = None;
@@ -184,50 +391,93 @@ fn complete_partial_header(result: &mut ParseResult) {
   new_field <-> result.partial_header_field;
   new_value <-> result.partial_header_value;
 
-  result.headers.set_header(
-    new_field.expect("Got header value without header name!"),
-    new_value.expect("Header value lied about is_some()!"));
+  match (new_field, new_value) {
+    (Some(field), Some(value)) => {
+      match checked_str(field) {
+        Some(name) => {
+          result.header_count += 1;
+          result.largest_header = cmp::max(result.largest_header,
+                                           name.len() + value.len());
+          result.headers.add_header_bytes(name, value).is_ok()
+        }
+
+        None => false
+      }
+    }
+
+    _ => false
+  }
 }
 
 extern fn on_header_field(p: *http_parser::Struct_http_parser,
                           at: *u8,
                           length: size_t) -> c_int {
-  let result = result_in_callback(p);
-  complete_partial_header(result);
-
-  let field = string_in_callback(at, length);
-
-  let mut current_field: Option<~str> = None;
-
-  current_field <-> result.partial_header_field;
-  result.partial_header_field = Some(current_field.get_or_default(~"") + field);
+  let field = bytes_in_callback(at, length);
 
-  0
+  do with_result_in_callback(p) |result| {
+    if complete_partial_header(result) {
+      append_bytes(&mut result.partial_header_field, field);
+      true
+    } else {
+      false
+    }
+  }
 }
 
 extern fn on_header_value(p: *http_parser::Struct_http_parser,
                           at: *u8,
                           length: size_t) -> c_int {
-  let result = result_in_callback(p);
-  let value = string_in_callback(at, length);
-  let mut current_value: Option<~str> = None;
-
-  current_value <-> result.partial_header_value;
-  result.partial_header_value = Some(current_value.get_or_default(~"") + value);
+  let value = bytes_in_callback(at, length);
 
-  0
+  do with_result_in_callback(p) |result| {
+    append_bytes(&mut result.partial_header_value, value);
+    true
+  }
 }
 
 extern fn on_headers_complete(p: *http_parser::Struct_http_parser)
                               -> c_int {
-  let result = result_in_callback(p);
-  complete_partial_header(result);
-
   let raw_method = unsafe { (*p).method as c_uint };
 
-  result.method = http_method_const_to_enum(raw_method);
+  do with_result_in_callback(p) |result| {
+    if complete_partial_header(result) {
+      result.method = http_method_const_to_enum(raw_method);
+      true
+    } else {
+      false
+    }
+  }
+}
 
-  0
+extern fn on_message_begin(p: *http_parser::Struct_http_parser) -> c_int {
+  do with_result_in_callback(p) |result| {
+    result.message_begun = true;
+    true
+  }
+}
+
+extern fn on_body(p: *http_parser::Struct_http_parser,
+                  at: *u8,
+                  length: size_t) -> c_int {
+  let body = bytes_in_callback(at, length);
+
+  do with_result_in_callback(p) |result| {
+    result.body.push_all(body);
+    true
+  }
+}
+
+extern fn on_message_complete(p: *http_parser::Struct_http_parser) -> c_int {
+  let status = do with_result_in_callback(p) |result| {
+    result.message_complete = true;
+    true
+  };
+
+  if status == 0 {
+    unsafe { http_parser_pause(p, 1); }
+  }
+
+  status
 }
 
 fn http_method_const_to_enum(raw_method: c_uint) -> Option<Method> {
@@ -262,7 +512,7 @@ fn http_method_const_to_enum(raw_method: c_uint) -> Option<Method> {
   }
 }
 
-pub fn initial_parser() -> Parser {
+fn new_parser(parser_type: c_uint) -> Parser {
   let mut p = http_parser::Struct_http_parser {
     _type_flags: 0,
     state: 0,
@@ -278,117 +528,76 @@ pub fn initial_parser() -> Parser {
     data: null()
   };
 
-  unsafe { http_parser_init(&p, HTTP_REQUEST); }
+  unsafe { http_parser_init(&p, parser_type); }
 
   let result = ParseResult::new();
-  Parser { parser: ~p, result: result, offset: 0 }
+  Parser { parser: ~p, result: result, offset: 0, consumed: 0 }
 }
 
-#[test]
-fn http_version_on_initial_parser() {
-  let p = initial_parser();
-  assert!(p.http_version() == None);
+pub fn initial_parser() -> Parser {
+  new_parser(HTTP_REQUEST)
 }
 
-#[test]
-fn http_version_after_parsing() {
-  let request = "GET /foo HTTP/1.1\n\n";
-  let r = initial_parser().parse(request);
-  assert!(r.http_version() == Some(HttpVersion(1,1)));
+pub fn initial_response_parser() -> Parser {
+  new_parser(HTTP_RESPONSE)
 }
 
 #[test]
-fn parse_simple_GET() {
-  let request = "GET /foo HTTP/1.1\n\n";
-  let p = initial_parser();
-
-  let r = p.parse(request);
+fn execute_clears_callback_data() {
+  let mut p = initial_parser();
+  p.execute("GET /foo HTTP/1.1\n".to_bytes());
 
-  assert!(r.success());
-  assert!(r.result.url == Some(~"/foo"));
-  assert!(r.result.method == Some(GET));
+  assert!(p.parser.data.is_null());
 }
 
 #[test]
-fn parse_simple_GET_in_multiple_chunks() {
-  let chunk_1 = "GET /fo";
-  let chunk_2 = "o HTTP/1.1\n\n";
+fn with_result_in_callback_fails_without_data() {
   let p = initial_parser();
-  let r = p.parse(chunk_1)
-           .parse(chunk_2);
 
-  assert!(r.result.url == Some(~"/foo"));
+  let status = do with_result_in_callback(to_unsafe_ptr(p.parser)) |_| { true };
+
+  assert!(status != 0);
 }
 
 #[test]
-fn parse_headers() {
-  let request = "\
-  GET /foo HTTP/1.1\n\
-  Header-1: pants\n\
-  Header-2: bar\n\
-  \n\
-  ";
+fn http_parse_error_errno_mapping() {
+  let mut errno = http_parser::HPE_CB_message_begin;
 
-  let p = initial_parser();
-  let r = p.parse(request);
+  while errno <= http_parser::HPE_UNKNOWN {
+    assert!(HttpParseError::from_errno(errno).to_errno() == errno);
+    errno += 1;
+  }
 
-  assert!(r.result.header("Header-1") == Some(~"pants"));
-  assert!(r.result.header("Header-2") == Some(~"bar"));
-  assert!(r.result.header("Non-Header") == None);
+  assert!(HttpParseError::from_errno(http_parser::HPE_INVALID_METHOD) == INVALID_METHOD);
+  assert!(HttpParseError::from_errno(-1) == UNKNOWN);
 }
 
 #[test]
-fn parse_headers_in_multiple_chunks() {
-  let chunk_1 = "GET /foo HTTP/1.1\nHe";
-  let chunk_2 = "ader-1: pan";
-  let chunk_3 = "ts\nHead";
-  let chunk_4 = "er-2:";
-  let chunk_5 = " bar\n\n";
+fn http_parse_error_names_match_c_parser() {
+  let mut errno = http_parser::HPE_CB_message_begin;
 
-  let p = initial_parser();
-  let r = p.parse(chunk_1)
-           .parse(chunk_2)
-           .parse(chunk_3)
-           .parse(chunk_4)
-           .parse(chunk_5);
+  while errno <= http_parser::HPE_UNKNOWN {
+    let error = HttpParseError::from_errno(errno);
 
-  assert!(r.result.header("Header-1") == Some(~"pants"));
-  assert!(r.result.header("Header-2") == Some(~"bar"));
-  assert!(r.result.header("Non-Header") == None);
-}
+    unsafe {
+      let name = str::raw::from_c_str(http_parser::http_errno_name(errno));
+      let description = str::raw::from_c_str(http_parser::http_errno_description(errno));
 
-#[test]
-fn parse_error() {
-  let request = "YURT /foo HTTP/1.1\n\n";
-  let p = initial_parser();
-  let r = p.parse(request).finish();
+      assert!(error.name() == name);
+      assert!(error.description() == description);
+    }
 
-  assert!(!r.success());
-  assert!(!r.upgrade());
-  assert!(~"HPE_INVALID_METHOD" == r.error_name());
-  assert!(~"invalid HTTP method" == r.error_description());
+    errno += 1;
+  }
 }
 
 #[test]
-fn parse_upgrade() {
-  let request = "\
-  GET /demo HTTP/1.1\n\
-  Upgrade: WebSocket\n\
-  Connection: Upgrade\n\
-  Host: example.com\n\
-  Origin: http://example.com\n\
-  WebSocket-Protocol: sample\n\n\
-  start of non-http content\
-  ";
-
-  let p = initial_parser();
-  let r = p.parse(request);
-  let offset = r.offset;
-  let content = request.slice(offset, request.len());
+fn complete_partial_header_without_name_fails() {
+  let mut result = ParseResult::new();
+  result.partial_header_value = Some(~"pants");
 
-  assert!(r.success());
-  assert!(r.upgrade());
-  assert!(content == ~"start of non-http content");
+  assert!(!complete_partial_header(&mut result));
+  assert!(result.headers.get_header("") == None);
 }
 
 #[test]
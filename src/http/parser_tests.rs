@@ -0,0 +1,451 @@
+use http::errors::*;
+use http::headers::*;
+use http::parser::*;
+use http::request::*;
+
+#[test]
+fn http_version_on_initial_parser() {
+  let p = initial_parser();
+  assert!(p.http_version() == None);
+}
+
+#[test]
+fn http_version_after_parsing() {
+  let request = "GET /foo HTTP/1.1\n\n";
+  let r = initial_parser().parse(request.to_bytes());
+  assert!(r.http_version() == Some(HttpVersion(1,1)));
+}
+
+#[test]
+fn parse_simple_GET() {
+  let request = "GET /foo HTTP/1.1\n\n";
+  let p = initial_parser();
+
+  let r = p.parse(request.to_bytes());
+
+  assert!(r.success());
+  assert!(r.url() == Some(~"/foo"));
+  assert!(r.result.method == Some(GET));
+}
+
+#[test]
+fn parse_simple_GET_in_multiple_chunks() {
+  let chunk_1 = "GET /fo";
+  let chunk_2 = "o HTTP/1.1\n\n";
+  let p = initial_parser();
+  let r = p.parse(chunk_1.to_bytes())
+           .parse(chunk_2.to_bytes());
+
+  assert!(r.url() == Some(~"/foo"));
+}
+
+#[test]
+fn parse_headers() {
+  let request = "\
+  GET /foo HTTP/1.1\n\
+  Header-1: pants\n\
+  Header-2: bar\n\
+  \n\
+  ";
+
+  let p = initial_parser();
+  let r = p.parse(request.to_bytes());
+
+  assert!(r.result.header("Header-1") == Some(~"pants"));
+  assert!(r.result.header("Header-2") == Some(~"bar"));
+  assert!(r.result.header("Non-Header") == None);
+}
+
+#[test]
+fn parse_headers_in_multiple_chunks() {
+  let chunk_1 = "GET /foo HTTP/1.1\nHe";
+  let chunk_2 = "ader-1: pan";
+  let chunk_3 = "ts\nHead";
+  let chunk_4 = "er-2:";
+  let chunk_5 = " bar\n\n";
+
+  let p = initial_parser();
+  let r = p.parse(chunk_1.to_bytes())
+           .parse(chunk_2.to_bytes())
+           .parse(chunk_3.to_bytes())
+           .parse(chunk_4.to_bytes())
+           .parse(chunk_5.to_bytes());
+
+  assert!(r.result.header("Header-1") == Some(~"pants"));
+  assert!(r.result.header("Header-2") == Some(~"bar"));
+  assert!(r.result.header("Non-Header") == None);
+}
+
+#[test]
+fn parse_duplicate_headers() {
+  let request = "\
+  GET /foo HTTP/1.1\n\
+  Sec-WebSocket-Protocol: chat\n\
+  Header-1: pants\n\
+  sec-websocket-protocol: superchat\n\
+  \n\
+  ";
+
+  let r = initial_parser().parse(request.to_bytes()).parse([]);
+
+  assert!(r.get_all("Sec-WebSocket-Protocol") == Some(~[~"chat", ~"superchat"]));
+  assert!(r.get_header("Sec-WebSocket-Protocol") == Some(~"chat, superchat"));
+}
+
+#[test]
+fn parse_preserves_header_case_and_order() {
+  let request = "\
+  GET /foo HTTP/1.1\n\
+  Host: example.com\n\
+  x-custom-Header: pants\n\
+  ACCEPT: */*\n\
+  \n\
+  ";
+
+  let r = initial_parser().parse(request.to_bytes());
+
+  assert!(r.headers().to_wire_bytes() ==
+          "Host: example.com\r\nx-custom-Header: pants\r\nACCEPT: */*\r\n".to_bytes());
+}
+
+#[test]
+fn parse_binary_header_value() {
+  let request = "GET /foo HTTP/1.1\nX-Binary: ".to_bytes() +
+                ~[0xC3, 0x28, 0xFF] +
+                "\nHeader-1: pants\n\n".to_bytes();
+
+  let r = initial_parser().parse(request);
+
+  assert!(r.success());
+  assert!(r.get_header("X-Binary") == None);
+  assert!(r.get_header_bytes("X-Binary") == Some(~[0xC3, 0x28, 0xFF]));
+  assert!(r.get_header("Header-1") == Some(~"pants"));
+}
+
+#[test]
+fn parse_multibyte_header_value_split_across_chunks() {
+  let request = "GET /foo HTTP/1.1\nX-Love: i ♥ u\n\n".to_bytes();
+  let split = request.len() - 6;
+
+  let r = initial_parser()
+          .parse(request.slice(0, split))
+          .parse(request.slice(split, request.len()));
+
+  assert!(r.get_header("X-Love") == Some(~"i ♥ u"));
+}
+
+#[test]
+fn parse_tracks_header_count_and_size() {
+  let request = "\
+  GET /foo HTTP/1.1\n\
+  Header-1: pants\n\
+  Header-2: trousers\n\
+  Header-3: sh";
+
+  let r = initial_parser().parse(request.to_bytes());
+
+  assert!(r.header_count() == 2);
+  assert!(r.largest_header_size() == 16);
+
+  let r = r.parse("orts-and-more-shorts\n".to_bytes());
+
+  assert!(r.largest_header_size() == 30);
+  assert!(r.bytes_consumed() == request.len() + 21);
+}
+
+#[test]
+fn execute_in_place_byte_by_byte() {
+  let request = "\
+  GET /foo HTTP/1.1\n\
+  Header-1: pants\n\
+  Header-2: bar\n\
+  \n\
+  ".to_bytes();
+
+  let mut p = initial_parser();
+
+  for request.each |byte| {
+    assert!(p.execute([*byte]) == 1);
+  }
+
+  assert!(p.success());
+  assert!(p.url() == Some(~"/foo"));
+  assert!(p.method() == Some(GET));
+  assert!(p.get_header("Header-1") == Some(~"pants"));
+  assert!(p.get_header("Header-2") == Some(~"bar"));
+  assert!(p.bytes_consumed() == request.len());
+}
+
+#[test]
+fn parse_leaves_original_parser_untouched() {
+  let p = initial_parser().parse("GET /fo".to_bytes());
+  let r = p.parse("o HTTP/1.1\n\n".to_bytes());
+
+  assert!(p.url() == Some(~"/fo"));
+  assert!(r.url() == Some(~"/foo"));
+}
+
+#[test]
+fn parse_error() {
+  let request = "YURT /foo HTTP/1.1\n\n";
+  let p = initial_parser();
+  let r = p.parse(request.to_bytes()).finish();
+
+  assert!(!r.success());
+  assert!(!r.upgrade());
+  assert!(~"HPE_INVALID_METHOD" == r.error_name());
+  assert!(~"invalid HTTP method" == r.error_description());
+}
+
+#[test]
+fn parse_error_enum_and_offset() {
+  let request = "GET /foo HTTP/1.1\nHeader-1: pants\n";
+  let bad_line = "Bad Header\n\n";
+  let r = initial_parser().parse(request.to_bytes()).parse(bad_line.to_bytes());
+
+  assert!(r.error() == Some(INVALID_HEADER_TOKEN));
+  assert!(r.error_offset() == Some(request.len() + 3));
+}
+
+#[test]
+fn parse_success_has_no_error() {
+  let r = initial_parser().parse("GET /foo HTTP/1.1\n\n".to_bytes());
+
+  assert!(r.error() == None);
+  assert!(r.error_offset() == None);
+}
+
+#[test]
+fn parse_upgrade() {
+  let request = "\
+  GET /demo HTTP/1.1\n\
+  Upgrade: WebSocket\n\
+  Connection: Upgrade\n\
+  Host: example.com\n\
+  Origin: http://example.com\n\
+  WebSocket-Protocol: sample\n\n\
+  start of non-http content\
+  ";
+
+  let p = initial_parser();
+  let r = p.parse(request.to_bytes());
+  let offset = r.offset;
+  let content = request.slice(offset, request.len());
+
+  assert!(r.success());
+  assert!(r.upgrade());
+  assert!(content == ~"start of non-http content");
+}
+
+#[test]
+fn parse_content_length_body() {
+  let request = "\
+  POST /foo HTTP/1.1\r\n\
+  Content-Length: 5\r\n\
+  \r\n\
+  hello";
+
+  let r = initial_parser().parse(request.to_bytes());
+
+  assert!(r.success());
+  assert!(r.method() == Some(POST));
+  assert!(r.message_complete());
+  assert!(r.body() == "hello".to_bytes());
+  assert!(r.bytes_consumed() == request.len());
+  assert!(r.finish().success());
+}
+
+#[test]
+fn parse_chunked_body() {
+  let request = "\
+  POST /foo HTTP/1.1\r\n\
+  Transfer-Encoding: chunked\r\n\
+  \r\n\
+  5;name=value\r\n\
+  hello\r\n\
+  0\r\n\
+  Trailer: pants\r\n\
+  \r\n";
+
+  let r = initial_parser().parse(request.to_bytes());
+
+  assert!(r.success());
+  assert!(r.message_complete());
+  assert!(r.body() == "hello".to_bytes());
+  assert!(r.get_header("Trailer") == Some(~"pants"));
+  assert!(r.bytes_consumed() == request.len());
+  assert!(r.finish().success());
+}
+
+#[test]
+fn parse_invalid_chunk_size() {
+  let request = "\
+  POST /foo HTTP/1.1\r\n\
+  Transfer-Encoding: chunked\r\n\
+  \r\n\
+  zz\r\n";
+
+  let r = initial_parser().parse(request.to_bytes());
+
+  assert!(r.error() == Some(INVALID_CHUNK_SIZE));
+}
+
+#[test]
+fn parse_invalid_content_length() {
+  let request = "\
+  POST /foo HTTP/1.1\r\n\
+  Content-Length: pants\r\n\
+  \r\n";
+
+  let r = initial_parser().parse(request.to_bytes());
+
+  assert!(r.error() == Some(INVALID_CONTENT_LENGTH));
+}
+
+#[test]
+fn parse_eof_inside_headers() {
+  let r = initial_parser().parse("GET /foo HTTP/1.1\r\nHead".to_bytes()).finish();
+
+  assert!(r.error() == Some(INVALID_EOF_STATE));
+}
+
+#[test]
+fn parse_response() {
+  let response = "\
+  HTTP/1.1 101 Switching Protocols\r\n\
+  Upgrade: websocket\r\n\
+  Connection: Upgrade\r\n\
+  \r\n";
+
+  let r = initial_response_parser().parse(response.to_bytes());
+
+  assert!(r.success());
+  assert!(r.upgrade());
+  assert!(r.status_code() == Some(101));
+  assert!(r.http_version() == Some(HttpVersion(1,1)));
+  assert!(r.get_header("Upgrade") == Some(~"websocket"));
+}
+
+#[test]
+fn parse_response_body_until_eof() {
+  let response = "HTTP/1.0 200 OK\r\n\r\nsome body";
+
+  let r = initial_response_parser().parse(response.to_bytes());
+
+  assert!(r.success());
+  assert!(r.status_code() == Some(200));
+  assert!(!r.message_complete());
+  assert!(r.bytes_consumed() == response.len());
+
+  let r = r.finish();
+
+  assert!(r.success());
+  assert!(r.message_complete());
+  assert!(r.body() == "some body".to_bytes());
+  assert!(!r.should_keep_alive());
+}
+
+#[test]
+fn parse_stops_at_message_complete() {
+  let first = "GET /first HTTP/1.1\r\n\r\n";
+  let second = "GET /second HTTP/1.1\r\nHeader-1: pants\r\n\r\n";
+  let pipelined = first.to_bytes() + second.to_bytes();
+
+  let mut p = initial_parser();
+
+  assert!(p.execute(pipelined) == first.len());
+  assert!(p.message_complete());
+  assert!(p.should_keep_alive());
+  assert!(p.url() == Some(~"/first"));
+  assert!(p.execute(pipelined.tailn(first.len())) == 0);
+
+  assert!(p.next_message());
+  assert!(!p.message_begun());
+  assert!(p.execute(pipelined.tailn(first.len())) == second.len());
+  assert!(p.message_complete());
+  assert!(p.url() == Some(~"/second"));
+  assert!(p.get_header("Header-1") == Some(~"pants"));
+  assert!(p.bytes_consumed() == pipelined.len());
+}
+
+#[test]
+fn next_message_waits_for_message_complete() {
+  let mut p = initial_parser();
+  p.execute("GET /foo HTTP/1.1\r\nHeader-1: pa".to_bytes());
+
+  assert!(p.message_begun());
+  assert!(!p.message_complete());
+  assert!(!p.next_message());
+  assert!(p.url() == Some(~"/foo"));
+}
+
+#[test]
+fn take_body_streams_body_chunks() {
+  let mut p = initial_parser();
+  p.execute("POST /foo HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello".to_bytes());
+
+  assert!(!p.message_complete());
+  assert!(p.take_body() == "hello".to_bytes());
+  assert!(p.body().is_empty());
+
+  p.execute("world".to_bytes());
+
+  assert!(p.message_complete());
+  assert!(p.take_body() == "world".to_bytes());
+}
+
+#[test]
+fn parse_chunked_body_across_chunks() {
+  let request = "\
+  POST /foo HTTP/1.1\r\n\
+  Transfer-Encoding: chunked\r\n\
+  \r\n\
+  5\r\n\
+  hello\r\n\
+  6\r\n\
+  \x20world\r\n\
+  0\r\n\
+  \r\n".to_bytes();
+
+  let mut p = initial_parser();
+
+  for request.each |byte| {
+    p.execute([*byte]);
+  }
+
+  assert!(p.success());
+  assert!(p.message_complete());
+  assert!(p.body() == "hello world".to_bytes());
+}
+
+#[test]
+fn http_1_0_closes_after_message() {
+  let mut p = initial_parser();
+  p.execute("GET /foo HTTP/1.0\r\n\r\n".to_bytes());
+
+  assert!(p.message_complete());
+  assert!(!p.should_keep_alive());
+
+  p.next_message();
+  p.execute("GET /bar HTTP/1.0\r\n\r\n".to_bytes());
+
+  assert!(p.error() == Some(CLOSED_CONNECTION));
+}
+
+#[test]
+fn http_1_0_keep_alive() {
+  let request = "GET /foo HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+  let r = initial_parser().parse(request.to_bytes());
+
+  assert!(r.message_complete());
+  assert!(r.should_keep_alive());
+}
+
+#[test]
+fn http_1_1_connection_close() {
+  let request = "GET /foo HTTP/1.1\r\nConnection: close\r\n\r\n";
+  let r = initial_parser().parse(request.to_bytes());
+
+  assert!(r.message_complete());
+  assert!(!r.should_keep_alive());
+}
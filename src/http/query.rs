@@ -0,0 +1,135 @@
+use http::headers::checked_str;
+
+pub fn query_string(url: &str) -> Option<~str> {
+  let without_fragment = match str::find_char(url, '#') {
+    Some(index) => url.slice(0, index),
+    None => url
+  };
+
+  match str::find_char(without_fragment, '?') {
+    Some(index) => Some(str::from_slice(
+      without_fragment.slice(index + 1, without_fragment.len()))),
+    None => None
+  }
+}
+
+pub fn url_path(url: &str) -> ~str {
+  let without_fragment = match str::find_char(url, '#') {
+    Some(index) => url.slice(0, index),
+    None => url
+  };
+
+  match str::find_char(without_fragment, '?') {
+    Some(index) => str::from_slice(without_fragment.slice(0, index)),
+    None => str::from_slice(without_fragment)
+  }
+}
+
+pub fn parse_query(url: &str) -> ~[(~str,~str)] {
+  let mut params = ~[];
+
+  for query_string(url).each |query| {
+    for str::split_char(*query, '&').each |pair| {
+      if pair.is_empty() { loop; }
+
+      let (raw_name, raw_value) = match str::find_char(*pair, '=') {
+        Some(index) => (pair.slice(0, index), pair.slice(index + 1, pair.len())),
+        None => (pair.slice(0, pair.len()), "")
+      };
+
+      match (percent_decode(raw_name), percent_decode(raw_value)) {
+        (Some(name), Some(value)) => params.push((name, value)),
+        _ => {}
+      }
+    }
+  }
+
+  params
+}
+
+pub fn get_query_param(url: &str, name: &str) -> Option<~str> {
+  for parse_query(url).each |param| {
+    match *param {
+      (ref param_name, ref value) => {
+        if *param_name == str::from_slice(name) {
+          return Some(value.clone());
+        }
+      }
+    }
+  }
+
+  None
+}
+
+pub fn percent_decode(encoded: &str) -> Option<~str> {
+  let bytes = encoded.to_bytes();
+  let mut decoded = ~[];
+  let mut pos = 0;
+
+  while pos < bytes.len() {
+    let c = bytes[pos];
+
+    if c == '%' as u8 {
+      if pos + 2 >= bytes.len() {
+        return None;
+      }
+
+      match (hex_digit(bytes[pos + 1]), hex_digit(bytes[pos + 2])) {
+        (Some(high), Some(low)) => decoded.push(high * 16 + low),
+        _ => return None
+      }
+
+      pos += 3;
+    } else {
+      decoded.push(if c == '+' as u8 { ' ' as u8 } else { c });
+      pos += 1;
+    }
+  }
+
+  checked_str(decoded)
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+  if c >= '0' as u8 && c <= '9' as u8 {
+    Some(c - '0' as u8)
+  } else if c >= 'a' as u8 && c <= 'f' as u8 {
+    Some(c - 'a' as u8 + 10)
+  } else if c >= 'A' as u8 && c <= 'F' as u8 {
+    Some(c - 'A' as u8 + 10)
+  } else {
+    None
+  }
+}
+
+#[test]
+fn query_string_test() {
+  assert!(query_string("/chat?token=abc&room=1#top") == Some(~"token=abc&room=1"));
+  assert!(query_string("/chat?") == Some(~""));
+  assert!(query_string("/chat#?token=abc") == None);
+  assert!(query_string("/chat") == None);
+}
+
+#[test]
+fn url_path_test() {
+  assert!(url_path("/chat?token=abc#top") == ~"/chat");
+  assert!(url_path("/chat#top?x") == ~"/chat");
+  assert!(url_path("/") == ~"/");
+}
+
+#[test]
+fn parse_query_test() {
+  assert!(parse_query("/chat?token=abc&flag&&name=a%20b+c") ==
+          ~[(~"token", ~"abc"), (~"flag", ~""), (~"name", ~"a b c")]);
+}
+
+#[test]
+fn parse_query_skips_invalid_escapes() {
+  assert!(parse_query("/chat?bad=%zz&short=%4&token=abc") == ~[(~"token", ~"abc")]);
+}
+
+#[test]
+fn get_query_param_test() {
+  assert!(get_query_param("/chat?room=1&access_token=s%2Fecret", "access_token") ==
+          Some(~"s/ecret"));
+  assert!(get_query_param("/chat?room=1", "access_token") == None);
+}
@@ -12,4 +12,5 @@ pub struct HttpVersion(u16,u16);
 pub trait Request {
   fn http_version(&self) -> Option<HttpVersion>;
   fn method(&self) -> Option<Method>;
+  fn url(&self) -> Option<~str>;
 }
@@ -407,6 +407,7 @@ pub struct Struct_Unnamed3 {
     pub off: uint16_t,
     pub len: uint16_t,
 }
+#[link_args = "-lhttp_parser"]
 pub extern "C" {
     fn select(__nfds: c_int, __readfds: *fd_set, __writefds: *fd_set,
               __exceptfds: *fd_set, __timeout: *Struct_timeval) -> c_int;
@@ -0,0 +1,79 @@
+/* Hand-written bindings for the subset of OpenSSL (1.1 and later) used by
+   websockets::tls. Everything is driven through memory BIOs, so no OpenSSL
+   call ever touches a file descriptor. */
+
+use core::libc::*;
+
+pub type SSL_CTX = c_void;
+pub type SSL = c_void;
+pub type SSL_METHOD = c_void;
+pub type BIO = c_void;
+pub type BIO_METHOD = c_void;
+
+pub type servername_cb = *u8;
+pub type alpn_select_cb = *u8;
+
+pub static SSL_FILETYPE_PEM: c_int = 1;
+
+pub static SSL_ERROR_NONE: c_int = 0;
+pub static SSL_ERROR_SSL: c_int = 1;
+pub static SSL_ERROR_WANT_READ: c_int = 2;
+pub static SSL_ERROR_WANT_WRITE: c_int = 3;
+pub static SSL_ERROR_SYSCALL: c_int = 5;
+pub static SSL_ERROR_ZERO_RETURN: c_int = 6;
+
+pub static SSL_CTRL_SET_TLSEXT_SERVERNAME_CB: c_int = 53;
+pub static SSL_CTRL_SET_TLSEXT_SERVERNAME_ARG: c_int = 54;
+pub static SSL_CTRL_SET_TLSEXT_HOSTNAME: c_int = 55;
+pub static TLSEXT_NAMETYPE_host_name: c_int = 0;
+
+pub static SSL_TLSEXT_ERR_OK: c_int = 0;
+pub static SSL_TLSEXT_ERR_ALERT_FATAL: c_int = 2;
+pub static SSL_TLSEXT_ERR_NOACK: c_int = 3;
+
+pub static OPENSSL_NPN_NEGOTIATED: c_int = 1;
+
+pub extern "C" {
+    fn TLS_server_method() -> *SSL_METHOD;
+    fn TLS_client_method() -> *SSL_METHOD;
+
+    fn SSL_CTX_new(method: *SSL_METHOD) -> *SSL_CTX;
+    fn SSL_CTX_free(ctx: *SSL_CTX);
+    fn SSL_CTX_use_certificate_chain_file(ctx: *SSL_CTX, file: *c_char) -> c_int;
+    fn SSL_CTX_use_PrivateKey_file(ctx: *SSL_CTX, file: *c_char, _type: c_int) -> c_int;
+    fn SSL_CTX_check_private_key(ctx: *SSL_CTX) -> c_int;
+    fn SSL_CTX_ctrl(ctx: *SSL_CTX, cmd: c_int, larg: c_long, parg: *c_void) -> c_long;
+    fn SSL_CTX_callback_ctrl(ctx: *SSL_CTX, cmd: c_int, cb: servername_cb) -> c_long;
+    fn SSL_CTX_set_alpn_select_cb(ctx: *SSL_CTX, cb: alpn_select_cb, arg: *c_void);
+
+    fn SSL_new(ctx: *SSL_CTX) -> *SSL;
+    fn SSL_free(ssl: *SSL);
+    fn SSL_ctrl(ssl: *SSL, cmd: c_int, larg: c_long, parg: *c_void) -> c_long;
+    fn SSL_set_bio(ssl: *SSL, rbio: *BIO, wbio: *BIO);
+    fn SSL_set_accept_state(ssl: *SSL);
+    fn SSL_set_connect_state(ssl: *SSL);
+    fn SSL_do_handshake(ssl: *SSL) -> c_int;
+    fn SSL_is_init_finished(ssl: *SSL) -> c_int;
+    fn SSL_read(ssl: *SSL, buf: *c_void, num: c_int) -> c_int;
+    fn SSL_write(ssl: *SSL, buf: *c_void, num: c_int) -> c_int;
+    fn SSL_shutdown(ssl: *SSL) -> c_int;
+    fn SSL_get_error(ssl: *SSL, ret: c_int) -> c_int;
+    fn SSL_get_servername(ssl: *SSL, _type: c_int) -> *c_char;
+    fn SSL_get_SSL_CTX(ssl: *SSL) -> *SSL_CTX;
+    fn SSL_set_SSL_CTX(ssl: *SSL, ctx: *SSL_CTX) -> *SSL_CTX;
+    fn SSL_set_alpn_protos(ssl: *SSL, protos: *u8, protos_len: c_uint) -> c_int;
+    fn SSL_get0_alpn_selected(ssl: *SSL, data: **u8, len: *c_uint);
+    fn SSL_select_next_proto(out: **u8, outlen: *u8,
+                             server: *u8, server_len: c_uint,
+                             client: *u8, client_len: c_uint) -> c_int;
+
+    fn BIO_s_mem() -> *BIO_METHOD;
+    fn BIO_new(method: *BIO_METHOD) -> *BIO;
+    fn BIO_read(bio: *BIO, data: *c_void, len: c_int) -> c_int;
+    fn BIO_write(bio: *BIO, data: *c_void, len: c_int) -> c_int;
+    fn BIO_ctrl_pending(bio: *BIO) -> size_t;
+
+    fn ERR_get_error() -> c_ulong;
+    fn ERR_error_string_n(e: c_ulong, buf: *c_char, len: size_t);
+    fn ERR_clear_error();
+}
@@ -0,0 +1,40 @@
+/* Turns SIGTERM and SIGINT into bytes on a pipe so that a Rust task can
+   wait for them with poll() instead of running code in a signal handler. */
+
+#include <fcntl.h>
+#include <signal.h>
+#include <string.h>
+#include <unistd.h>
+
+static int signal_pipe[2] = { -1, -1 };
+
+static void on_shutdown_signal(int signum) {
+  unsigned char byte = (unsigned char) signum;
+  ssize_t ignored = write(signal_pipe[1], &byte, 1);
+  (void) ignored;
+}
+
+int dolittle_watch_shutdown_signals(void) {
+  struct sigaction action;
+
+  if (signal_pipe[0] >= 0) {
+    return signal_pipe[0];
+  }
+
+  if (pipe(signal_pipe) != 0) {
+    return -1;
+  }
+
+  fcntl(signal_pipe[1], F_SETFL, O_NONBLOCK);
+
+  memset(&action, 0, sizeof(action));
+  action.sa_handler = on_shutdown_signal;
+  sigemptyset(&action.sa_mask);
+  action.sa_flags = SA_RESTART;
+
+  if (sigaction(SIGTERM, &action, NULL) != 0 || sigaction(SIGINT, &action, NULL) != 0) {
+    return -1;
+  }
+
+  return signal_pipe[0];
+}
@@ -0,0 +1,7 @@
+/* Binding for the shutdown signal pipe in signals.c. */
+
+use core::libc::*;
+
+pub extern "C" {
+    fn dolittle_watch_shutdown_signals() -> c_int;
+}
@@ -0,0 +1,49 @@
+/* Hand-written bindings for the POSIX calls needed by websockets::unix.
+   Constants and struct layouts are the Linux ones. */
+
+use core::libc::*;
+
+pub static AF_UNIX: c_int = 1;
+pub static SOCK_STREAM: c_int = 1;
+pub static O_RDONLY: c_int = 0;
+pub static O_NONBLOCK: c_int = 0x800;
+pub static POLLIN: c_short = 1;
+pub static POLLOUT: c_short = 4;
+pub static MSG_NOSIGNAL: c_int = 0x4000;
+pub static SHUT_RDWR: c_int = 2;
+
+pub static EINTR: int = 4;
+pub static ENXIO: int = 6;
+pub static EAGAIN: int = 11;
+pub static ENOENT: int = 2;
+pub static ECONNREFUSED: int = 111;
+
+pub static SUN_PATH_SIZE: uint = 108;
+
+pub struct sockaddr_un {
+    pub sun_family: c_ushort,
+    pub sun_path: [u8, ..108u],
+}
+
+pub struct pollfd {
+    pub fd: c_int,
+    pub events: c_short,
+    pub revents: c_short,
+}
+
+pub extern "C" {
+    fn socket(domain: c_int, _type: c_int, protocol: c_int) -> c_int;
+    fn bind(fd: c_int, addr: *sockaddr_un, len: c_uint) -> c_int;
+    fn listen(fd: c_int, backlog: c_int) -> c_int;
+    fn accept(fd: c_int, addr: *c_void, len: *c_uint) -> c_int;
+    fn connect(fd: c_int, addr: *sockaddr_un, len: c_uint) -> c_int;
+    fn poll(fds: *pollfd, nfds: c_ulong, timeout: c_int) -> c_int;
+    fn open(path: *c_char, flags: c_int) -> c_int;
+    fn read(fd: c_int, buf: *c_void, count: size_t) -> ssize_t;
+    fn write(fd: c_int, buf: *c_void, count: size_t) -> ssize_t;
+    fn send(fd: c_int, buf: *c_void, len: size_t, flags: c_int) -> ssize_t;
+    fn shutdown(fd: c_int, how: c_int) -> c_int;
+    fn close(fd: c_int) -> c_int;
+    fn unlink(path: *c_char) -> c_int;
+    fn umask(mask: c_uint) -> c_uint;
+}
@@ -0,0 +1,602 @@
+use websockets::framing::types::CLOSE_POLICY_VIOLATION;
+use websockets::outbound::{OverflowPolicy, DISCONNECT, DEFAULT_QUEUE_LIMIT};
+use websockets::websocket::HandshakeLimits;
+
+pub static DEFAULT_PORT: uint = 12345;
+pub static DEFAULT_BACKLOG: uint = 10;
+pub static DEFAULT_MAX_CONNECTIONS: uint = 256;
+pub static DEFAULT_SHUTDOWN_TIMEOUT_MS: uint = 5000;
+pub static DEFAULT_TOPIC_HISTORY: uint = 0;
+
+#[deriving(Eq,Clone)]
+pub enum ListenAddress {
+  TcpAddress(~str, uint),
+  UnixPath(~str),
+  InheritedSocket(int),
+}
+
+#[deriving(Eq,Clone)]
+pub struct TlsCertificate {
+  server_name: Option<~str>,
+  cert_file: ~str,
+  key_file: ~str,
+}
+
+#[deriving(Eq,Clone)]
+pub struct TlsConfig {
+  certificates: ~[TlsCertificate],
+  alpn_protocols: ~[~str],
+}
+
+/* Unix and inherited listeners run every connection on its own thread, so
+   they stop accepting while max_connections are open. TCP connections share
+   the libuv loop and are not limited. */
+#[deriving(Eq,Clone)]
+pub struct ListenerConfig {
+  address: ListenAddress,
+  backlog: uint,
+  limits: HandshakeLimits,
+  tls: Option<TlsConfig>,
+  socket_mode: Option<uint>,
+  unlink_stale: bool,
+  subprotocols: ~[~str],
+  queue_limit: uint,
+  queue_policy: OverflowPolicy,
+  max_connections: uint,
+}
+
+#[deriving(Eq,Clone)]
+pub struct ServerConfig {
+  listeners: ~[ListenerConfig],
+  shutdown_timeout_ms: uint,
+  topic_history: uint,
+}
+
+impl ListenAddress {
+  pub fn parse(spec: &str) -> Result<ListenAddress,~str> {
+    if spec.starts_with("fd:") {
+      return match int::from_str(spec.slice(3, spec.len())) {
+        Some(fd) if fd >= 0 => Ok(InheritedSocket(fd)),
+        _ => Err(~"Invalid inherited socket: " + spec)
+      };
+    }
+
+    if spec.starts_with("unix:") {
+      return if spec.len() > 5 {
+        Ok(UnixPath(str::from_slice(spec.slice(5, spec.len()))))
+      } else {
+        Err(~"Missing unix socket path: " + spec)
+      };
+    }
+
+    let (host, port) = if spec.starts_with("[") {
+      match str::find_str(spec, "]:") {
+        Some(index) => (spec.slice(1, index), spec.slice(index + 2, spec.len())),
+        None => return Err(~"Invalid listen address: " + spec)
+      }
+    } else {
+      match str::rfind_char(spec, ':') {
+        Some(index) => (spec.slice(0, index), spec.slice(index + 1, spec.len())),
+        None => return Err(~"Missing port in listen address: " + spec)
+      }
+    };
+
+    if host.is_empty() || (!spec.starts_with("[") && str::contains_char(host, ':')) {
+      return Err(~"Invalid listen address: " + spec);
+    }
+
+    match uint::from_str(port) {
+      Some(port) if port <= 65535 => Ok(TcpAddress(str::from_slice(host), port)),
+      _ => Err(~"Invalid port in listen address: " + spec)
+    }
+  }
+
+  pub fn to_str(&self) -> ~str {
+    match *self {
+      TcpAddress(ref host, port) if str::contains_char(*host, ':') => {
+        ~"[" + *host + "]:" + port.to_str()
+      }
+      TcpAddress(ref host, port) => *host + ":" + port.to_str(),
+      UnixPath(ref path) => ~"unix:" + *path,
+      InheritedSocket(fd) => ~"fd:" + fd.to_str()
+    }
+  }
+}
+
+impl TlsConfig {
+  pub fn new() -> TlsConfig {
+    TlsConfig { certificates: ~[], alpn_protocols: ~[] }
+  }
+
+  pub fn default_certificate(&self) -> Option<TlsCertificate> {
+    for self.certificates.each |certificate| {
+      if certificate.server_name.is_none() {
+        return Some(certificate.clone());
+      }
+    }
+
+    None
+  }
+
+  pub fn validate(&self) -> Result<(),~str> {
+    let mut defaults = 0;
+
+    for self.certificates.each |certificate| {
+      if certificate.cert_file.is_empty() || certificate.key_file.is_empty() {
+        return Err(~"TLS certificates need both a cert and a key file");
+      }
+
+      if certificate.server_name.is_none() {
+        defaults += 1;
+      }
+    }
+
+    if defaults != 1 {
+      return Err(~"TLS listeners need exactly one default cert and key");
+    }
+
+    for self.alpn_protocols.each |protocol| {
+      if protocol.is_empty() || protocol.len() > 255 {
+        return Err(~"Invalid ALPN protocol: " + *protocol);
+      }
+    }
+
+    Ok(())
+  }
+
+  priv fn set_default_file(&mut self, is_cert: bool, file: &str) {
+    let mut index = self.certificates.len();
+
+    for self.certificates.eachi |i, certificate| {
+      if certificate.server_name.is_none() {
+        index = i;
+      }
+    }
+
+    if index == self.certificates.len() {
+      self.certificates.push(TlsCertificate { server_name: None, cert_file: ~"", key_file: ~"" });
+    }
+
+    if is_cert {
+      self.certificates[index].cert_file = str::from_slice(file);
+    } else {
+      self.certificates[index].key_file = str::from_slice(file);
+    }
+  }
+
+  priv fn add_server_name(&mut self, spec: &str) -> Result<(),~str> {
+    let parts = str::split_char(spec, ':');
+
+    if parts.len() != 3 || parts[0].is_empty() {
+      return Err(~"Expected sni=NAME:CERT:KEY, got " + spec);
+    }
+
+    self.certificates.push(TlsCertificate {
+      server_name: Some(parts[0].to_lower()),
+      cert_file: copy parts[1],
+      key_file: copy parts[2],
+    });
+
+    Ok(())
+  }
+}
+
+impl ListenerConfig {
+  pub fn new(address: ListenAddress) -> ListenerConfig {
+    ListenerConfig {
+      address: address,
+      backlog: DEFAULT_BACKLOG,
+      limits: HandshakeLimits::default(),
+      tls: None,
+      socket_mode: None,
+      unlink_stale: true,
+      subprotocols: ~[],
+      queue_limit: DEFAULT_QUEUE_LIMIT,
+      queue_policy: DISCONNECT(CLOSE_POLICY_VIOLATION),
+      max_connections: DEFAULT_MAX_CONNECTIONS,
+    }
+  }
+
+  pub fn validate(&self) -> Result<(),~str> {
+    match self.tls {
+      Some(ref tls) => tls.validate(),
+      None => Ok(())
+    }
+  }
+
+  pub fn parse(spec: &str) -> Result<ListenerConfig,~str> {
+    let mut parts = ~[];
+
+    for str::split_char(spec.trim(), ' ').each |part| {
+      if !part.is_empty() {
+        parts.push(copy *part);
+      }
+    }
+
+    if parts.is_empty() {
+      return Err(~"Missing listen address");
+    }
+
+    let mut listener = match ListenAddress::parse(parts[0]) {
+      Ok(address) => ListenerConfig::new(address),
+      Err(error) => return Err(error)
+    };
+
+    for parts.tail().each |option| {
+      let result = match str::find_char(*option, '=') {
+        Some(index) => listener.set_option(option.slice(0, index),
+                                           option.slice(index + 1, option.len())),
+        None => Err(~"Invalid listener option: " + *option)
+      };
+
+      if result.is_err() {
+        return Err(result.get_err());
+      }
+    }
+
+    Ok(listener)
+  }
+
+  pub fn set_option(&mut self, name: &str, value: &str) -> Result<(),~str> {
+    if name == "cert" || name == "key" || name == "sni" || name == "alpn" {
+      return self.set_tls_option(name, value);
+    } else if name == "mode" {
+      return match uint::from_str_radix(value, 8) {
+        Some(mode) if mode <= 4095 => { self.socket_mode = Some(mode); Ok(()) }
+        _ => Err(~"Invalid socket mode: " + value)
+      };
+    } else if name == "unlink_stale" {
+      return match bool::from_str(value) {
+        Some(unlink_stale) => { self.unlink_stale = unlink_stale; Ok(()) }
+        None => Err(~"Invalid value for unlink_stale: " + value)
+      };
+    } else if name == "subprotocols" {
+      for str::split_char(value, ',').each |protocol| {
+        if !protocol.is_empty() && !self.subprotocols.contains(protocol) {
+          self.subprotocols.push(copy *protocol);
+        }
+      }
+
+      return Ok(());
+    } else if name == "queue_policy" {
+      return match OverflowPolicy::from_str(value) {
+        Some(policy) => { self.queue_policy = policy; Ok(()) }
+        None => Err(~"Invalid queue policy: " + value)
+      };
+    }
+
+    let number = match uint::from_str(value) {
+      Some(number) => number,
+      None => return Err(~"Invalid value for " + name + ": " + value)
+    };
+
+    if name == "backlog" {
+      self.backlog = number;
+    } else if name == "max_bytes" {
+      self.limits.max_bytes = number;
+    } else if name == "max_headers" {
+      self.limits.max_headers = number;
+    } else if name == "max_header_size" {
+      self.limits.max_header_size = number;
+    } else if name == "timeout_ms" {
+      self.limits.timeout_ms = number;
+    } else if name == "queue_limit" && number > 0 {
+      self.queue_limit = number;
+    } else if name == "queue_limit" {
+      return Err(~"Invalid value for queue_limit: " + value);
+    } else if name == "max_connections" && number > 0 {
+      self.max_connections = number;
+    } else if name == "max_connections" {
+      return Err(~"Invalid value for max_connections: " + value);
+    } else {
+      return Err(~"Unknown listener option: " + name);
+    }
+
+    Ok(())
+  }
+
+  priv fn set_tls_option(&mut self, name: &str, value: &str) -> Result<(),~str> {
+    let mut tls = match self.tls {
+      Some(ref tls) => tls.clone(),
+      None => TlsConfig::new()
+    };
+
+    if name == "cert" {
+      tls.set_default_file(true, value);
+    } else if name == "key" {
+      tls.set_default_file(false, value);
+    } else if name == "sni" {
+      match tls.add_server_name(value) {
+        Ok(()) => {}
+        Err(error) => return Err(error)
+      }
+    } else {
+      for str::split_char(value, ',').each |protocol| {
+        tls.alpn_protocols.push(copy *protocol);
+      }
+    }
+
+    self.tls = Some(tls);
+    Ok(())
+  }
+}
+
+impl ServerConfig {
+  pub fn default() -> ServerConfig {
+    ServerConfig::listening_on(DEFAULT_PORT)
+  }
+
+  pub fn listening_on(port: uint) -> ServerConfig {
+    ServerConfig {
+      listeners: ~[ListenerConfig::new(TcpAddress(~"0.0.0.0", port))],
+      shutdown_timeout_ms: DEFAULT_SHUTDOWN_TIMEOUT_MS,
+      topic_history: DEFAULT_TOPIC_HISTORY,
+    }
+  }
+
+  pub fn from_str(config: &str) -> Result<ServerConfig,~str> {
+    let mut listeners = ~[];
+    let mut shutdown_timeout_ms = DEFAULT_SHUTDOWN_TIMEOUT_MS;
+    let mut topic_history = DEFAULT_TOPIC_HISTORY;
+
+    for str::split_char(config, '\n').eachi |index, line| {
+      let line = line.trim();
+
+      if line.is_empty() || line.starts_with("#") {
+        loop;
+      }
+
+      let line_error = ~"Line " + (index + 1).to_str() + ": ";
+
+      let (key, value) = match str::find_char(line, '=') {
+        Some(position) => (line.slice(0, position).trim(),
+                           line.slice(position + 1, line.len()).trim()),
+        None => return Err(line_error + "Expected key = value")
+      };
+
+      if key == "shutdown_timeout_ms" {
+        match uint::from_str(value) {
+          Some(timeout) => shutdown_timeout_ms = timeout,
+          None => return Err(line_error + "Invalid value for shutdown_timeout_ms: " + value)
+        }
+
+        loop;
+      } else if key == "topic_history" {
+        match uint::from_str(value) {
+          Some(limit) => topic_history = limit,
+          None => return Err(line_error + "Invalid value for topic_history: " + value)
+        }
+
+        loop;
+      } else if key != "listen" {
+        return Err(line_error + "Unknown setting " + key);
+      }
+
+      match ListenerConfig::parse(value) {
+        Ok(listener) => listeners.push(listener),
+        Err(error) => return Err(line_error + error)
+      }
+
+      match listeners.last().validate() {
+        Ok(()) => {}
+        Err(error) => return Err(line_error + error)
+      }
+    }
+
+    if listeners.is_empty() {
+      Err(~"No listeners configured")
+    } else {
+      Ok(ServerConfig {
+        listeners: listeners,
+        shutdown_timeout_ms: shutdown_timeout_ms,
+        topic_history: topic_history,
+      })
+    }
+  }
+
+  pub fn from_file(path: &str) -> Result<ServerConfig,~str> {
+    match io::read_whole_file_str(&Path(path)) {
+      Ok(contents) => ServerConfig::from_str(contents),
+      Err(error) => Err(~"Could not read " + path + ": " + error)
+    }
+  }
+
+  pub fn from_args(args: &[~str]) -> Result<ServerConfig,~str> {
+    let mut listeners: ~[ListenerConfig] = ~[];
+    let mut shutdown_timeout_ms = None;
+    let mut topic_history = None;
+    let mut index = 0;
+
+    while index < args.len() {
+      let arg = copy args[index];
+
+      if index + 1 >= args.len() {
+        return Err(~"Missing value for " + arg);
+      }
+
+      let value = copy args[index + 1];
+
+      if arg == ~"--config" {
+        match ServerConfig::from_file(value) {
+          Ok(config) => {
+            if shutdown_timeout_ms.is_none() {
+              shutdown_timeout_ms = Some(config.shutdown_timeout_ms);
+            }
+
+            if topic_history.is_none() {
+              topic_history = Some(config.topic_history);
+            }
+
+            listeners.push_all_move(config.listeners);
+          }
+          Err(error) => return Err(error)
+        }
+      } else if arg == ~"--shutdown-timeout-ms" {
+        match uint::from_str(value) {
+          Some(timeout) => shutdown_timeout_ms = Some(timeout),
+          None => return Err(~"Invalid value for " + arg + ": " + value)
+        }
+      } else if arg == ~"--topic-history" {
+        match uint::from_str(value) {
+          Some(limit) => topic_history = Some(limit),
+          None => return Err(~"Invalid value for " + arg + ": " + value)
+        }
+      } else if arg == ~"--listen" {
+        match ListenAddress::parse(value) {
+          Ok(address) => listeners.push(ListenerConfig::new(address)),
+          Err(error) => return Err(error)
+        }
+      } else if arg.starts_with("--") && !listeners.is_empty() {
+        let last = listeners.len() - 1;
+        let name = str::replace(arg.slice(2, arg.len()), "-", "_");
+
+        match listeners[last].set_option(name, value) {
+          Ok(()) => {}
+          Err(error) => return Err(error)
+        }
+      } else {
+        return Err(~"Unknown argument: " + arg);
+      }
+
+      index += 2;
+    }
+
+    for listeners.each |listener| {
+      match listener.validate() {
+        Ok(()) => {}
+        Err(error) => return Err(error)
+      }
+    }
+
+    let mut config = if listeners.is_empty() {
+      ServerConfig::default()
+    } else {
+      ServerConfig {
+        listeners: listeners,
+        shutdown_timeout_ms: DEFAULT_SHUTDOWN_TIMEOUT_MS,
+        topic_history: DEFAULT_TOPIC_HISTORY,
+      }
+    };
+
+    for shutdown_timeout_ms.each |timeout| {
+      config.shutdown_timeout_ms = *timeout;
+    }
+
+    for topic_history.each |limit| {
+      config.topic_history = *limit;
+    }
+
+    Ok(config)
+  }
+}
+
+#[test]
+fn parse_listen_addresses() {
+  assert!(ListenAddress::parse("0.0.0.0:12345") == Ok(TcpAddress(~"0.0.0.0", 12345)));
+  assert!(ListenAddress::parse("[::1]:8080") == Ok(TcpAddress(~"::1", 8080)));
+  assert!(ListenAddress::parse("fd:3") == Ok(InheritedSocket(3)));
+  assert!(ListenAddress::parse("unix:/run/dolittle.sock") == Ok(UnixPath(~"/run/dolittle.sock")));
+  assert!(ListenAddress::parse("unix:").is_err());
+  assert!(ListenAddress::parse("::1:8080").is_err());
+  assert!(ListenAddress::parse("127.0.0.1").is_err());
+  assert!(ListenAddress::parse("127.0.0.1:70000").is_err());
+  assert!(ListenAddress::parse("fd:-1").is_err());
+}
+
+#[test]
+fn listen_address_to_str() {
+  assert!(TcpAddress(~"::", 80).to_str() == ~"[::]:80");
+  assert!(TcpAddress(~"127.0.0.1", 80).to_str() == ~"127.0.0.1:80");
+  assert!(InheritedSocket(3).to_str() == ~"fd:3");
+  assert!(UnixPath(~"/run/dolittle.sock").to_str() == ~"unix:/run/dolittle.sock");
+}
+
+#[test]
+fn parse_listener_options() {
+  let listener = ListenerConfig::parse("[::]:9000 backlog=128 max_headers=32").get();
+
+  assert!(listener.address == TcpAddress(~"::", 9000));
+  assert!(listener.backlog == 128);
+  assert!(listener.limits.max_headers == 32);
+  assert!(listener.limits.timeout_ms == HandshakeLimits::default().timeout_ms);
+  assert!(listener.subprotocols.is_empty());
+  assert!(ListenerConfig::parse("[::]:9000 subprotocols=chat,,pubsub").get().subprotocols ==
+          ~[~"chat", ~"pubsub"]);
+  assert!(listener.queue_limit == DEFAULT_QUEUE_LIMIT);
+  assert!(listener.queue_policy == DISCONNECT(CLOSE_POLICY_VIOLATION));
+
+  let queued = ListenerConfig::parse("[::]:9000 queue_limit=64 queue_policy=drop_oldest").get();
+  assert!(queued.queue_limit == 64);
+  assert!(queued.queue_policy == websockets::outbound::DROP_OLDEST);
+  assert!(ListenerConfig::parse("[::]:9000 queue_limit=0").is_err());
+  assert!(ListenerConfig::parse("[::]:9000 queue_policy=wait").is_err());
+  assert!(listener.max_connections == DEFAULT_MAX_CONNECTIONS);
+  assert!(ListenerConfig::parse("fd:3 max_connections=16").get().max_connections == 16);
+  assert!(ListenerConfig::parse("fd:3 max_connections=0").is_err());
+  assert!(ListenerConfig::parse("[::]:9000 backlog").is_err());
+  assert!(ListenerConfig::parse("[::]:9000 colour=blue").is_err());
+}
+
+#[test]
+fn parse_unix_listener_options() {
+  let listener = ListenerConfig::parse("unix:/run/dolittle.sock mode=660 unlink_stale=false").get();
+
+  assert!(listener.address == UnixPath(~"/run/dolittle.sock"));
+  assert!(listener.socket_mode == Some(432));
+  assert!(!listener.unlink_stale);
+  assert!(ListenerConfig::parse("unix:/run/dolittle.sock mode=999").is_err());
+  assert!(ListenerConfig::parse("unix:/run/dolittle.sock unlink_stale=maybe").is_err());
+}
+
+#[test]
+fn parse_tls_listener_options() {
+  let listener = ListenerConfig::parse(
+    "[::]:443 cert=site.crt key=site.key sni=Chat.example.com:chat.crt:chat.key alpn=http/1.1").get();
+  let tls = listener.tls.get();
+
+  assert!(listener.validate().is_ok());
+  assert!(tls.default_certificate() ==
+          Some(TlsCertificate { server_name: None, cert_file: ~"site.crt", key_file: ~"site.key" }));
+  assert!(tls.certificates[1].server_name == Some(~"chat.example.com"));
+  assert!(tls.alpn_protocols == ~[~"http/1.1"]);
+  assert!(ListenerConfig::parse("[::]:443 cert=site.crt").get().validate().is_err());
+  assert!(ListenerConfig::parse("[::]:443 sni=chat:chat.crt:chat.key").get().validate().is_err());
+  assert!(ListenerConfig::parse("[::]:443 sni=chat.crt").is_err());
+}
+
+#[test]
+fn server_config_from_str() {
+  let config = ServerConfig::from_str(
+    "# public and local listeners\n\
+     listen = 0.0.0.0:80 backlog=64\n\
+     \n\
+     listen = fd:3\n").get();
+
+  assert!(config.listeners.len() == 2);
+  assert!(config.listeners[0].backlog == 64);
+  assert!(config.listeners[1].address == InheritedSocket(3));
+  assert!(config.shutdown_timeout_ms == DEFAULT_SHUTDOWN_TIMEOUT_MS);
+  assert!(ServerConfig::from_str("shutdown_timeout_ms = 250\nlisten = fd:3\n").get()
+          .shutdown_timeout_ms == 250);
+  assert!(config.topic_history == DEFAULT_TOPIC_HISTORY);
+  assert!(ServerConfig::from_str("topic_history = 100\nlisten = fd:3\n").get().topic_history == 100);
+  assert!(ServerConfig::from_str("port = 80\n") == Err(~"Line 1: Unknown setting port"));
+  assert!(ServerConfig::from_str("# nothing\n").is_err());
+}
+
+#[test]
+fn server_config_from_args() {
+  let config = ServerConfig::from_args([~"--listen", ~"127.0.0.1:8080",
+                                        ~"--timeout-ms", ~"500",
+                                        ~"--listen", ~"[::1]:8080"]).get();
+
+  assert!(config.listeners.len() == 2);
+  assert!(config.listeners[0].limits.timeout_ms == 500);
+  assert!(config.listeners[1].address == TcpAddress(~"::1", 8080));
+  assert!(ServerConfig::from_args([]) == Ok(ServerConfig::default()));
+  assert!(ServerConfig::from_args([~"--backlog", ~"5"]).is_err());
+  assert!(ServerConfig::from_args([~"--listen"]).is_err());
+  assert!(ServerConfig::from_args([~"--shutdown-timeout-ms", ~"100"]).get().shutdown_timeout_ms == 100);
+  assert!(ServerConfig::from_args([~"--topic-history", ~"50"]).get().topic_history == 50);
+  assert!(ServerConfig::from_args([~"--topic-history", ~"lots"]).is_err());
+}
@@ -0,0 +1,769 @@
+use core::comm::{Chan, Port, SharedChan, stream};
+use std::arc;
+use std::json::Json;
+use std::net_tcp::TcpErrData;
+use std::time::precise_time_ns;
+use std::timer;
+use std::uv_global_loop;
+use websockets::framing::types::{Frame, CLOSE_GOING_AWAY};
+use websockets::pubsub::{Broker, valid_topic};
+use websockets::registry::*;
+use websockets::rooms::{Rooms, PresenceEvent, MEMBER_JOINED, MEMBER_LEFT, MEMBER_DISCONNECTED};
+use websockets::rooms::presence_frame;
+use websockets::websocket::{ConnectionCommand, SendShared, Disconnect, Abort};
+
+static SHUTDOWN_POLL_MS: uint = 50;
+
+pub enum ListenerHandle {
+  TcpListenerHandle(SharedChan<Option<TcpErrData>>),
+  PollingListenerHandle(Chan<()>),
+}
+
+pub enum ServerEvent {
+  ListenerStarted(ListenerHandle),
+  ListenerStopped(Result<(),~str>),
+  ConnectionOpened(ConnectionEntry, Chan<ConnectionId>),
+  ConnectionClosed(ConnectionId),
+  ConnectionLookup(ConnectionId, Chan<Option<ConnectionInfo>>),
+  ConnectionListing(Chan<~[ConnectionInfo]>),
+  ConnectionDisconnected(ConnectionId, u16, ~str, Chan<bool>),
+  QueuesRequested(Recipients, Chan<~[RecipientQueue]>),
+  TopicSubscribed(ConnectionId, ~str, Chan<bool>),
+  TopicUnsubscribed(ConnectionId, ~str, Chan<bool>),
+  TopicPublished(~str, Json, Chan<(ConnectionCommand,~[RecipientQueue])>),
+  TopicResumed(ConnectionId, ~str, u64, Chan<Option<(uint,bool)>>),
+  RoomJoined(ConnectionId, ~str, Chan<Option<~[RecipientQueue]>>),
+  RoomLeft(ConnectionId, ~str, Chan<Option<~[RecipientQueue]>>),
+  RoomListing(~str, Chan<~[ConnectionId]>),
+  RoomQueuesRequested(~str, Chan<~[RecipientQueue]>),
+  ShutdownRequested,
+}
+
+/* A control bound to a connection with for_connection never waits for room
+   in that connection's own outbound queue, since only its task can make
+   room. Handlers should use one when they send from a connection. */
+pub struct ServerControl {
+  events: SharedChan<ServerEvent>,
+  sender: Option<ConnectionId>,
+}
+
+impl ListenerHandle {
+  pub fn stop(&self) {
+    match *self {
+      TcpListenerHandle(ref kill_ch) => { kill_ch.try_send(None); }
+      PollingListenerHandle(ref stop_ch) => { stop_ch.try_send(()); }
+    }
+  }
+}
+
+impl Clone for ServerControl {
+  fn clone(&self) -> ServerControl {
+    ServerControl { events: self.events.clone(), sender: self.sender }
+  }
+}
+
+impl ServerControl {
+  pub fn new(events: Chan<ServerEvent>) -> ServerControl {
+    ServerControl { events: SharedChan(events), sender: None }
+  }
+
+  pub fn for_connection(&self, id: ConnectionId) -> ServerControl {
+    ServerControl { events: self.events.clone(), sender: Some(id) }
+  }
+
+  pub fn shutdown(&self) {
+    self.events.try_send(ShutdownRequested);
+  }
+
+  pub fn listener_started(&self, handle: ListenerHandle) {
+    self.events.try_send(ListenerStarted(handle));
+  }
+
+  pub fn listener_stopped(&self, result: Result<(),~str>) {
+    self.events.try_send(ListenerStopped(result));
+  }
+
+  pub fn connection_opened(&self, entry: ConnectionEntry) -> Option<ConnectionId> {
+    let (reply_po, reply_ch) = stream();
+    self.request(ConnectionOpened(entry, reply_ch), &reply_po)
+  }
+
+  /* The other members of every room the connection was in hear that it
+     disconnected. */
+  pub fn connection_closed(&self, id: ConnectionId) {
+    self.events.try_send(ConnectionClosed(id));
+  }
+
+  pub fn connection(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+    let (reply_po, reply_ch) = stream();
+
+    match self.request(ConnectionLookup(id, reply_ch), &reply_po) {
+      Some(info) => info,
+      None => None
+    }
+  }
+
+  pub fn connections(&self) -> ~[ConnectionInfo] {
+    let (reply_po, reply_ch) = stream();
+
+    match self.request(ConnectionListing(reply_ch), &reply_po) {
+      Some(infos) => infos,
+      None => ~[]
+    }
+  }
+
+  /* Returns false when the connection is not (or no longer) registered, or
+     its outbound queue refused the frame. */
+  pub fn send_frame(&self, id: ConnectionId, frame: &Frame) -> bool {
+    self.send_frame_to_each([id], frame) == 1
+  }
+
+  pub fn disconnect(&self, id: ConnectionId, code: u16, reason: &str) -> bool {
+    let (reply_po, reply_ch) = stream();
+    let event = ConnectionDisconnected(id, code, str::from_slice(reason), reply_ch);
+
+    match self.request(event, &reply_po) {
+      Some(sent) => sent,
+      None => false
+    }
+  }
+
+  /* The frame is composed once and shared between all recipients. Returns how
+     many outbound queues accepted it; see OverflowPolicy for what happens
+     when a queue is full. */
+  pub fn send_frame_to_each(&self, ids: &[ConnectionId], frame: &Frame) -> uint {
+    let (reply_po, reply_ch) = stream();
+    let event = QueuesRequested(SomeConnections(vec::from_slice(ids)), reply_ch);
+    enqueue(self.request(event, &reply_po), shared_frame(frame), self.sender)
+  }
+
+  pub fn broadcast_frame(&self, frame: &Frame) -> uint {
+    let (reply_po, reply_ch) = stream();
+    let queues = self.request(QueuesRequested(AllConnections, reply_ch), &reply_po);
+    enqueue(queues, shared_frame(frame), self.sender)
+  }
+
+  /* Returns false when the connection is not registered. */
+  pub fn subscribe(&self, id: ConnectionId, pattern: &str) -> bool {
+    let (reply_po, reply_ch) = stream();
+
+    match self.request(TopicSubscribed(id, str::from_slice(pattern), reply_ch), &reply_po) {
+      Some(subscribed) => subscribed,
+      None => false
+    }
+  }
+
+  /* Returns false when the connection was not subscribed to the pattern. */
+  pub fn unsubscribe(&self, id: ConnectionId, pattern: &str) -> bool {
+    let (reply_po, reply_ch) = stream();
+
+    match self.request(TopicUnsubscribed(id, str::from_slice(pattern), reply_ch), &reply_po) {
+      Some(unsubscribed) => unsubscribed,
+      None => false
+    }
+  }
+
+  /* Sends data to every connection with a matching subscription, returning
+     how many received it. Invalid topics reach nobody. */
+  pub fn publish(&self, topic: &str, data: &Json) -> uint {
+    if !valid_topic(topic) {
+      return 0;
+    }
+
+    let (reply_po, reply_ch) = stream();
+
+    match self.request(TopicPublished(str::from_slice(topic), copy *data, reply_ch), &reply_po) {
+      Some((command, queues)) => enqueue(Some(queues), command, self.sender),
+      None => 0
+    }
+  }
+
+  /* Subscribes the connection to a single topic and queues the messages it
+     missed since last_seq ahead of anything published later. Returns how many
+     were replayed and whether some could not be because they are no longer
+     retained, or None when the connection is not registered. */
+  pub fn resume(&self, id: ConnectionId, topic: &str, last_seq: u64) -> Option<(uint,bool)> {
+    if !valid_topic(topic) {
+      return None;
+    }
+
+    let (reply_po, reply_ch) = stream();
+
+    match self.request(TopicResumed(id, str::from_slice(topic), last_seq, reply_ch), &reply_po) {
+      Some(resumed) => resumed,
+      None => None
+    }
+  }
+
+  /* Returns false when the connection is not registered or already in the
+     room. The other members are told about the newcomer. */
+  pub fn join_room(&self, id: ConnectionId, room: &str) -> bool {
+    let (reply_po, reply_ch) = stream();
+
+    match self.request(RoomJoined(id, str::from_slice(room), reply_ch), &reply_po) {
+      Some(Some(members)) => { self.announce(members, room, MEMBER_JOINED, id); true }
+      _ => false
+    }
+  }
+
+  /* Returns false when the connection was not in the room. */
+  pub fn leave_room(&self, id: ConnectionId, room: &str) -> bool {
+    let (reply_po, reply_ch) = stream();
+
+    match self.request(RoomLeft(id, str::from_slice(room), reply_ch), &reply_po) {
+      Some(Some(members)) => { self.announce(members, room, MEMBER_LEFT, id); true }
+      _ => false
+    }
+  }
+
+  pub fn room_members(&self, room: &str) -> ~[ConnectionId] {
+    let (reply_po, reply_ch) = stream();
+
+    match self.request(RoomListing(str::from_slice(room), reply_ch), &reply_po) {
+      Some(members) => members,
+      None => ~[]
+    }
+  }
+
+  pub fn broadcast_to_room(&self, room: &str, frame: &Frame) -> uint {
+    let (reply_po, reply_ch) = stream();
+    let queues = self.request(RoomQueuesRequested(str::from_slice(room), reply_ch), &reply_po);
+    enqueue(queues, shared_frame(frame), self.sender)
+  }
+
+  priv fn announce(&self,
+                   members: ~[RecipientQueue],
+                   room: &str,
+                   event: PresenceEvent,
+                   id: ConnectionId) -> uint {
+    enqueue(Some(members), shared_frame(&presence_frame(room, event, id)), self.sender)
+  }
+
+  priv fn request<R: Owned>(&self, event: ServerEvent, reply_po: &Port<R>) -> Option<R> {
+    if self.events.try_send(event) {
+      reply_po.try_recv()
+    } else {
+      None
+    }
+  }
+}
+
+/* Runs until shutdown has been requested (or every listener has stopped) and
+   all connections are gone. Connections still open when the shutdown timeout
+   expires are aborted. Up to topic_history messages are kept per topic for
+   clients that resume. */
+pub fn coordinate(events: &Port<ServerEvent>,
+                  listener_count: uint,
+                  shutdown_timeout_ms: uint,
+                  topic_history: uint) {
+  let iotask = uv_global_loop::get();
+  let mut listeners = ~[];
+  let mut connections = Registry::new();
+  let mut broker = Broker::with_history(topic_history);
+  let mut rooms = Rooms::new();
+  let mut running_listeners = listener_count;
+  let mut deadline = None;
+
+  loop {
+    match deadline {
+      Some(deadline_ns) => {
+        if connections.is_empty() && running_listeners == 0 {
+          return;
+        }
+
+        if !events.peek() {
+          if precise_time_ns() >= deadline_ns {
+            connections.send_control_all(&Abort);
+            return;
+          }
+
+          timer::sleep(&iotask, SHUTDOWN_POLL_MS);
+          loop;
+        }
+      }
+
+      None => {}
+    }
+
+    match events.recv() {
+      ListenerStarted(handle) => {
+        if deadline.is_some() {
+          handle.stop();
+        } else {
+          listeners.push(handle);
+        }
+      }
+
+      ListenerStopped(result) => {
+        running_listeners -= 1;
+
+        match result {
+          Ok(()) => {}
+          Err(error) => error!("%s", error)
+        }
+
+        if running_listeners == 0 && deadline.is_none() {
+          deadline = Some(begin_shutdown(&mut listeners, &connections, shutdown_timeout_ms));
+        }
+      }
+
+      ConnectionOpened(entry, reply) => {
+        let id = connections.register(entry);
+        reply.try_send(id);
+
+        if deadline.is_some() {
+          connections.send_control(id, going_away());
+        }
+      }
+
+      ConnectionClosed(id) => {
+        connections.unregister(id);
+        broker.remove_connection(id);
+        announce_disconnect(&connections, &mut rooms, id);
+      }
+
+      ConnectionLookup(id, reply) => {
+        reply.try_send(connections.lookup(id));
+      }
+
+      ConnectionListing(reply) => {
+        reply.try_send(connections.list());
+      }
+
+      ConnectionDisconnected(id, code, reason, reply) => {
+        reply.try_send(connections.send_control(id, Disconnect(code, reason)));
+      }
+
+      QueuesRequested(recipients, reply) => {
+        reply.try_send(connections.queues(&recipients));
+      }
+
+      TopicSubscribed(id, pattern, reply) => {
+        let registered = connections.contains(id);
+
+        if registered {
+          broker.subscribe(id, pattern);
+        }
+
+        reply.try_send(registered);
+      }
+
+      TopicUnsubscribed(id, pattern, reply) => {
+        reply.try_send(broker.unsubscribe(id, pattern));
+      }
+
+      TopicPublished(topic, data, reply) => {
+        let command = broker.publish(topic, &data);
+        reply.try_send((command, connections.queues(&SomeConnections(broker.subscribers(topic)))));
+      }
+
+      TopicResumed(id, topic, last_seq, reply) => {
+        reply.try_send(resume(&connections, &mut broker, id, topic, last_seq));
+      }
+
+      RoomJoined(id, room, reply) => {
+        if connections.contains(id) && rooms.join(id, room) {
+          reply.try_send(Some(other_members(&connections, &rooms, room, id)));
+        } else {
+          reply.try_send(None);
+        }
+      }
+
+      RoomLeft(id, room, reply) => {
+        if rooms.leave(id, room) {
+          reply.try_send(Some(other_members(&connections, &rooms, room, id)));
+        } else {
+          reply.try_send(None);
+        }
+      }
+
+      RoomListing(room, reply) => {
+        reply.try_send(rooms.members(room));
+      }
+
+      RoomQueuesRequested(room, reply) => {
+        reply.try_send(connections.queues(&SomeConnections(rooms.members(room))));
+      }
+
+      ShutdownRequested => {
+        if deadline.is_none() {
+          deadline = Some(begin_shutdown(&mut listeners, &connections, shutdown_timeout_ms));
+        }
+      }
+    }
+  }
+}
+
+fn begin_shutdown(listeners: &mut ~[ListenerHandle],
+                  connections: &Registry,
+                  shutdown_timeout_ms: uint) -> u64 {
+  info!("Shutting down, waiting up to %ums for %u connections",
+        shutdown_timeout_ms, connections.len());
+
+  for listeners.each |listener| {
+    listener.stop();
+  }
+
+  *listeners = ~[];
+
+  connections.send_control_all(&going_away());
+
+  precise_time_ns() + (shutdown_timeout_ms as u64) * 1000000
+}
+
+/* The replay is pushed here rather than by the caller so that nothing
+   published afterwards can overtake it. It bypasses the overflow policy
+   because the coordinator must never wait on a queue. */
+fn resume(connections: &Registry,
+          broker: &mut Broker,
+          id: ConnectionId,
+          topic: &str,
+          last_seq: u64) -> Option<(uint,bool)> {
+  let queues = connections.queues(&SomeConnections(~[id]));
+
+  if queues.is_empty() {
+    return None;
+  }
+
+  let queue = &queues[0].queue;
+
+  broker.subscribe(id, topic);
+  let (missed, gap) = broker.replay(topic, last_seq);
+  let mut replayed = 0;
+
+  for missed.each |command| {
+    if queue.push_unbounded(command.clone()) {
+      replayed += 1;
+    }
+  }
+
+  Some((replayed, gap))
+}
+
+/* Nobody waits for a closing connection, so the presence frames are pushed
+   here, bypassing the overflow policy like resume does. */
+fn announce_disconnect(connections: &Registry, rooms: &mut Rooms, id: ConnectionId) {
+  for rooms.remove_connection(id).each |room| {
+    let frame = shared_frame(&presence_frame(*room, MEMBER_DISCONNECTED, id));
+
+    for other_members(connections, rooms, *room, id).each |member| {
+      member.queue.push_unbounded(frame.clone());
+    }
+  }
+}
+
+fn other_members(connections: &Registry,
+                 rooms: &Rooms,
+                 room: &str,
+                 id: ConnectionId) -> ~[RecipientQueue] {
+  let members = rooms.members(room).filtered(|member| *member != id);
+  connections.queues(&SomeConnections(members))
+}
+
+/* Runs in the sending task, which is the only one a BLOCK_PRODUCER queue
+   can hold up. The sender's own queue is never waited on. */
+fn enqueue(queues: Option<~[RecipientQueue]>,
+           command: ConnectionCommand,
+           sender: Option<ConnectionId>) -> uint {
+  let mut queued = 0;
+
+  for queues.each |queues| {
+    for queues.each |recipient| {
+      let pushed = if sender == Some(recipient.id) {
+        recipient.queue.push_without_waiting(command.clone())
+      } else {
+        recipient.queue.push(command.clone())
+      };
+
+      if pushed {
+        queued += 1;
+      }
+    }
+  }
+
+  queued
+}
+
+fn shared_frame(frame: &Frame) -> ConnectionCommand {
+  SendShared(arc::ARC(frame.compose()))
+}
+
+fn going_away() -> ConnectionCommand {
+  Disconnect(CLOSE_GOING_AWAY, ~"Server shutting down")
+}
+
+#[cfg(test)]
+fn test_entry() -> (ConnectionEntry, websockets::outbound::OutboundQueue) {
+  use websockets::outbound::{OutboundQueue, DROP_NEWEST};
+
+  let queue = OutboundQueue::new(16, DROP_NEWEST);
+  (ConnectionEntry::new("127.0.0.1", "/chat", None, TrafficCounter::new(), queue.clone()), queue)
+}
+
+#[test]
+fn server_control_looks_up_and_addresses_connections() {
+  let (events_po, events_ch) = stream();
+  let (entry, queue) = test_entry();
+  let control = ServerControl::new(events_ch);
+
+  do task::spawn {
+    coordinate(&events_po, 0, 10000, 0);
+  }
+
+  let id = control.connection_opened(entry).get();
+
+  assert!(control.connection(id).map(|info| info.path.clone()) == Some(~"/chat"));
+  assert!(control.connection(id + 1).is_none());
+  assert!(control.connections().map(|info| info.id) == ~[id]);
+
+  assert!(control.send_frame(id, &Frame::text("hi")));
+  assert!(control.disconnect(id, CLOSE_GOING_AWAY, "bye"));
+  assert!(!control.send_frame(id + 1, &Frame::text("hi")));
+
+  assert!(queue.pop() == Some(shared_frame(&Frame::text("hi"))));
+  assert!(queue.pop() == Some(Disconnect(CLOSE_GOING_AWAY, ~"bye")));
+
+  control.connection_closed(id);
+  assert!(control.connections().is_empty());
+  control.shutdown();
+}
+
+#[test]
+fn server_control_broadcasts_frames() {
+  let (events_po, events_ch) = stream();
+  let (first_entry, first_queue) = test_entry();
+  let (second_entry, second_queue) = test_entry();
+  let control = ServerControl::new(events_ch);
+
+  do task::spawn {
+    coordinate(&events_po, 0, 10000, 0);
+  }
+
+  let first = control.connection_opened(first_entry).get();
+  let second = control.connection_opened(second_entry).get();
+
+  assert!(control.broadcast_frame(&Frame::text("all")) == 2);
+  assert!(control.send_frame_to_each([second, second + 1], &Frame::text("some")) == 1);
+
+  assert!(first_queue.pop() == Some(shared_frame(&Frame::text("all"))));
+  assert!(second_queue.pop() == Some(shared_frame(&Frame::text("all"))));
+  assert!(second_queue.pop() == Some(shared_frame(&Frame::text("some"))));
+  assert!(first_queue.pop() == None);
+
+  control.connection_closed(first);
+  control.connection_closed(second);
+  control.shutdown();
+}
+
+#[cfg(test)]
+fn shared_json(command: Option<ConnectionCommand>) -> Json {
+  match command {
+    Some(SendShared(bytes)) => websockets::pubsub::frame_json(copy *arc::get(&bytes)),
+    _ => fail!(~"Expected a shared frame")
+  }
+}
+
+#[test]
+fn server_control_publishes_to_subscribers() {
+  use std::json::String;
+  use websockets::pubsub::{frame_json, message_frame};
+
+  let (events_po, events_ch) = stream();
+  let (first_entry, first_queue) = test_entry();
+  let (second_entry, second_queue) = test_entry();
+  let control = ServerControl::new(events_ch);
+
+  do task::spawn {
+    coordinate(&events_po, 0, 10000, 0);
+  }
+
+  let first = control.connection_opened(first_entry).get();
+  let second = control.connection_opened(second_entry).get();
+
+  assert!(control.subscribe(first, "news.#"));
+  assert!(control.subscribe(second, "news.weather"));
+  assert!(!control.subscribe(second + 1, "news.#"));
+
+  assert!(control.publish("news.sports", &String(~"goal")) == 1);
+  assert!(control.publish("news.weather", &String(~"rain")) == 2);
+  assert!(control.publish("news.*", &String(~"invalid")) == 0);
+
+  assert!(control.unsubscribe(first, "news.#"));
+  assert!(!control.unsubscribe(first, "news.#"));
+  control.connection_closed(second);
+  assert!(control.publish("news.weather", &String(~"sun")) == 0);
+
+  let goal = frame_json(message_frame("news.sports", 1, &String(~"goal")).compose());
+  let rain = frame_json(message_frame("news.weather", 1, &String(~"rain")).compose());
+
+  assert!(shared_json(first_queue.pop()) == goal);
+  assert!(shared_json(first_queue.pop()) == rain);
+  assert!(shared_json(second_queue.pop()) == rain);
+  assert!(first_queue.pop() == None);
+
+  control.connection_closed(first);
+  control.shutdown();
+}
+
+#[test]
+fn publisher_never_waits_for_its_own_full_queue() {
+  use std::json::String;
+  use websockets::outbound::{OutboundQueue, BLOCK_PRODUCER};
+
+  let (events_po, events_ch) = stream();
+  let queue = OutboundQueue::new(1, BLOCK_PRODUCER);
+  let entry = ConnectionEntry::new("127.0.0.1", "/chat", None, TrafficCounter::new(), queue.clone());
+  let control = ServerControl::new(events_ch);
+
+  do task::spawn {
+    coordinate(&events_po, 0, 10000, 0);
+  }
+
+  let id = control.connection_opened(entry).get();
+  let publisher = control.for_connection(id);
+
+  assert!(publisher.subscribe(id, "news"));
+  assert!(publisher.publish("news", &String(~"first")) == 1);
+  assert!(publisher.publish("news", &String(~"second")) == 0);
+  assert!(publisher.broadcast_frame(&Frame::text("all")) == 0);
+  assert!(queue.stats().dropped == 2);
+  assert!(queue.stats().depth == 1);
+
+  control.connection_closed(id);
+  control.shutdown();
+}
+
+#[test]
+fn server_control_replays_missed_messages() {
+  use std::json::Number;
+  use websockets::pubsub::{frame_json, message_frame};
+
+  let (events_po, events_ch) = stream();
+  let (entry, queue) = test_entry();
+  let control = ServerControl::new(events_ch);
+
+  do task::spawn {
+    coordinate(&events_po, 0, 10000, 2);
+  }
+
+  let id = control.connection_opened(entry).get();
+  let message = |seq: u64| frame_json(message_frame("news", seq, &Number(seq as float)).compose());
+
+  for uint::range(1, 4) |seq| {
+    assert!(control.publish("news", &Number(seq as float)) == 0);
+  }
+
+  assert!(control.resume(id, "news", 1) == Some((2, false)));
+  assert!(control.publish("news", &Number(4f)) == 1);
+  assert!(control.resume(id, "news", 0) == Some((2, true)));
+  assert!(control.resume(id + 1, "news", 0) == None);
+  assert!(control.resume(id, "news.*", 0) == None);
+
+  assert!(shared_json(queue.pop()) == message(2));
+  assert!(shared_json(queue.pop()) == message(3));
+  assert!(shared_json(queue.pop()) == message(4));
+  assert!(shared_json(queue.pop()) == message(3));
+  assert!(shared_json(queue.pop()) == message(4));
+  assert!(queue.pop() == None);
+
+  control.connection_closed(id);
+  control.shutdown();
+}
+
+#[test]
+fn server_control_manages_rooms_and_presence() {
+  use websockets::pubsub::frame_json;
+
+  let (events_po, events_ch) = stream();
+  let (first_entry, first_queue) = test_entry();
+  let (second_entry, second_queue) = test_entry();
+  let control = ServerControl::new(events_ch);
+
+  do task::spawn {
+    coordinate(&events_po, 0, 10000, 0);
+  }
+
+  let first = control.connection_opened(first_entry).get();
+  let second = control.connection_opened(second_entry).get();
+  let presence = |event: PresenceEvent, id: ConnectionId| {
+    frame_json(presence_frame("lobby", event, id).compose())
+  };
+
+  assert!(control.join_room(first, "lobby"));
+  assert!(!control.join_room(first, "lobby"));
+  assert!(!control.join_room(second + 1, "lobby"));
+  assert!(control.join_room(second, "lobby"));
+  assert!(control.room_members("lobby") == ~[first, second]);
+
+  assert!(control.broadcast_to_room("lobby", &Frame::text("hi")) == 2);
+  assert!(control.broadcast_to_room("kitchen", &Frame::text("hi")) == 0);
+
+  assert!(control.leave_room(second, "lobby"));
+  assert!(!control.leave_room(second, "lobby"));
+  assert!(control.join_room(second, "lobby"));
+  control.connection_closed(first);
+  assert!(control.room_members("lobby") == ~[second]);
+
+  assert!(shared_json(first_queue.pop()) == presence(MEMBER_JOINED, second));
+  assert!(first_queue.pop() == Some(shared_frame(&Frame::text("hi"))));
+  assert!(shared_json(first_queue.pop()) == presence(MEMBER_LEFT, second));
+  assert!(shared_json(first_queue.pop()) == presence(MEMBER_JOINED, second));
+  assert!(first_queue.pop() == None);
+
+  assert!(second_queue.pop() == Some(shared_frame(&Frame::text("hi"))));
+  assert!(shared_json(second_queue.pop()) == presence(MEMBER_DISCONNECTED, first));
+  assert!(second_queue.pop() == None);
+
+  control.connection_closed(second);
+  assert!(control.room_members("lobby").is_empty());
+  control.shutdown();
+}
+
+#[test]
+fn shutdown_sends_going_away_and_waits_for_connections() {
+  let (events_po, events_ch) = stream();
+  let (entry, queue) = test_entry();
+  let (reply_po, reply_ch) = stream();
+  events_ch.send(ConnectionOpened(entry, reply_ch));
+  events_ch.send(ShutdownRequested);
+  events_ch.send(ConnectionClosed(0));
+
+  coordinate(&events_po, 0, 10000, 0);
+
+  assert!(reply_po.recv() == 0);
+  assert!(queue.pop() == Some(Disconnect(CLOSE_GOING_AWAY, ~"Server shutting down")));
+  assert!(queue.pop() == None);
+}
+
+#[test]
+fn shutdown_aborts_connections_after_timeout() {
+  let (events_po, events_ch) = stream();
+  let (entry, queue) = test_entry();
+  let (_reply_po, reply_ch) = stream();
+  events_ch.send(ConnectionOpened(entry, reply_ch));
+  events_ch.send(ShutdownRequested);
+
+  coordinate(&events_po, 0, 0, 0);
+
+  assert!(queue.pop() == Some(Disconnect(CLOSE_GOING_AWAY, ~"Server shutting down")));
+  assert!(queue.pop() == Some(Abort));
+}
+
+#[test]
+fn connections_opened_during_shutdown_are_closed() {
+  let (events_po, events_ch) = stream();
+  let (entry, queue) = test_entry();
+  let (reply_po, reply_ch) = stream();
+  let (stop_po, stop_ch) = stream();
+  events_ch.send(ListenerStarted(PollingListenerHandle(stop_ch)));
+  events_ch.send(ShutdownRequested);
+  events_ch.send(ConnectionOpened(entry, reply_ch));
+  events_ch.send(ConnectionClosed(0));
+  events_ch.send(ListenerStopped(Ok(())));
+
+  coordinate(&events_po, 1, 10000, 0);
+
+  assert!(stop_po.recv() == ());
+  assert!(reply_po.recv() == 0);
+  assert!(queue.pop() == Some(Disconnect(CLOSE_GOING_AWAY, ~"Server shutting down")));
+}
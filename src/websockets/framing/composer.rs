@@ -77,7 +77,7 @@ fn compose_base_case_1() {
   };
 
   let parser = FrameParser::new();
-  let result = parser.parse(frame.compose());
+  let result = parser.parse(frame.compose()).unwrap();
 
   assert!(result.make_frame_done() == frame);
 }
@@ -93,7 +93,7 @@ fn compose_base_case_2() {
   };
 
   let parser = FrameParser::new();
-  let result = parser.parse(frame.compose());
+  let result = parser.parse(frame.compose()).unwrap();
 
   assert!(result.make_frame_done() == frame);
 }
@@ -118,7 +118,7 @@ fn test_compose_long_payload(length: uint) {
   };
 
   let parser = FrameParser::new();
-  let result = parser.parse(frame.compose());
+  let result = parser.parse(frame.compose()).unwrap();
 
   assert!(result.make_frame_done() == frame);
 }
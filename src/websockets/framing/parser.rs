@@ -10,6 +10,17 @@ enum ParserState {
   DONE,
 }
 
+#[deriving(Eq,Clone)]
+pub enum FrameError {
+  NON_MINIMAL_LENGTH,
+  LENGTH_MOST_SIGNIFICANT_BIT_SET,
+  LENGTH_EXCEEDS_UINT,
+  RESERVED_OP_CODE(u8),
+  FRAGMENTED_CONTROL_FRAME,
+  CONTROL_FRAME_TOO_LONG,
+  INVALID_PARSER_STATE,
+}
+
 #[deriving(Eq,Clone)]
 struct Buffer {
   bytes_read: u8,
@@ -19,6 +30,7 @@ struct Buffer {
 #[deriving(Clone)]
 pub struct FrameParser {
   state: ParserState,
+  strict: bool,
   byte_one: Option<ByteOne>,
   byte_two: Option<ByteTwo>,
   payload_length: Option<PayloadLength>,
@@ -31,10 +43,23 @@ pub struct ParseResult {
   bytes_parsed: uint,
 }
 
+pub type FrameParseResult = Result<ParseResult,FrameError>;
+
+impl FrameError {
+  pub fn close_code(&self) -> u16 {
+    match *self {
+      LENGTH_EXCEEDS_UINT => CLOSE_MESSAGE_TOO_BIG,
+      INVALID_PARSER_STATE => CLOSE_INTERNAL_ERROR,
+      _ => CLOSE_PROTOCOL_ERROR
+    }
+  }
+}
+
 impl FrameParser {
   pub fn new() -> FrameParser {
     FrameParser {
       state: INITIAL,
+      strict: false,
       byte_one: None,
       byte_two: None,
       payload_length: None,
@@ -43,24 +68,36 @@ impl FrameParser {
     }
   }
 
-  pub fn parse(&self, bytes: &[u8]) -> ParseResult {
+  pub fn new_strict() -> FrameParser {
+    FrameParser {
+      strict: true,
+      ..
+      FrameParser::new()
+    }
+  }
+
+  pub fn parse(&self, bytes: &[u8]) -> FrameParseResult {
     let initial = ParseResult {
       parser: self.clone(),
       bytes_parsed: 0,
     };
 
-    let result = FrameParser::parse_bytewise(initial, bytes);
+    match FrameParser::parse_bytewise(initial, bytes) {
+      Ok(result) => {
+        if result.parser.state == READING_PAYLOAD {
+          result.parser.consume_payload_bytes(result.bytes_parsed,
+                                              bytes)
+        } else {
+          Ok(result)
+        }
+      }
 
-    if result.parser.state == READING_PAYLOAD {
-      result.parser.consume_payload_bytes(result.bytes_parsed,
-                                          bytes)
-    } else {
-      result
+      Err(error) => Err(error)
     }
   }
 
   fn parse_all(&self, bytes: &[u8]) -> FrameParser {
-    let result = self.parse(bytes);
+    let result = self.parse(bytes).unwrap();
 
     assert!(result.bytes_parsed == bytes.len());
 
@@ -71,126 +108,218 @@ impl FrameParser {
     self.state == DONE
   }
 
-  priv fn parse_bytewise(initial: ParseResult, bytes: &[u8]) -> ParseResult {
+  priv fn parse_bytewise(initial: ParseResult, bytes: &[u8]) -> FrameParseResult {
     let mut result = initial;
 
     for vec::each(bytes) |byte| {
       if result.parser.state == DONE ||
          result.parser.state == READING_PAYLOAD {
         break;
-      } else {
-        result = ParseResult {
-          parser: result.parser.parse_byte(*byte),
-          bytes_parsed: result.bytes_parsed + 1,
+      }
+
+      match result.parser.parse_byte(*byte) {
+        Ok(parser) => {
+          result = ParseResult {
+            parser: parser,
+            bytes_parsed: result.bytes_parsed + 1,
+          }
         }
+
+        Err(error) => return Err(error)
       }
     }
 
-    result
+    Ok(result)
   }
 
-  priv fn parse_byte(&self, byte: u8) -> FrameParser {
+  priv fn parse_byte(&self, byte: u8) -> Result<FrameParser,FrameError> {
     match self.state {
 
       INITIAL => self.parse_byte_one(byte),
       AWAITING_BYTE_TWO => self.parse_byte_two(byte),
       READING_PAYLOAD_LENGTH(buf) => self.parse_payload_length_byte(buf, byte),
       READING_MASK(buf) => self.parse_mask_byte(buf, byte),
-      READING_PAYLOAD => fail!(~"Attempt to read payload one byte at a time!"),
-      DONE => self.clone(),
+      READING_PAYLOAD => Err(INVALID_PARSER_STATE),
+      DONE => Ok(self.clone()),
     }
   }
 
-  priv fn parse_byte_one(self, byte: u8) -> FrameParser {
-    FrameParser {
-      byte_one: Some(ByteOne(byte)),
-      state: AWAITING_BYTE_TWO,
-      ..
-      self
+  priv fn parse_byte_one(self, byte: u8) -> Result<FrameParser,FrameError> {
+    let byte_one = ByteOne(byte);
+
+    match self.check_byte_one(byte_one) {
+      Some(error) => Err(error),
+      None => Ok(FrameParser {
+        byte_one: Some(byte_one),
+        state: AWAITING_BYTE_TWO,
+        ..
+        self
+      })
     }
   }
 
-  priv fn parse_byte_two(self, byte: u8) -> FrameParser {
+  priv fn check_byte_one(&self, byte_one: ByteOne) -> Option<FrameError> {
+    if !self.strict {
+      return None;
+    }
+
+    match byte_one.op_code() {
+      RESERVED_NON_CONTROL(byte) => Some(RESERVED_OP_CODE(byte)),
+      RESERVED_CONTROL(byte) => Some(RESERVED_OP_CODE(byte)),
+      op_code if op_code.is_control() && !byte_one.is_fin() => Some(FRAGMENTED_CONTROL_FRAME),
+      _ => None
+    }
+  }
+
+  priv fn parse_byte_two(self, byte: u8) -> Result<FrameParser,FrameError> {
     let byte_two = ByteTwo(byte);
     let payload_length = match byte_two.payload_length() {
       Length(len) => Some(len as u64),
       _ => None
     };
 
-    FrameParser {
-      byte_two: Some(byte_two),
-      payload_length: payload_length,
-      state: next_state_after_byte_two(byte_two),
-      ..
-      self
+    match self.check_byte_two(byte_two) {
+      Some(error) => Err(error),
+      None => Ok(FrameParser {
+        byte_two: Some(byte_two),
+        payload_length: payload_length,
+        state: next_state_after_byte_two(byte_two),
+        ..
+        self
+      })
     }
   }
 
-  priv fn parse_payload_length_byte(self, buf: Buffer, byte: u8) -> FrameParser {
+  priv fn check_byte_two(&self, byte_two: ByteTwo) -> Option<FrameError> {
+    if !self.strict {
+      return None;
+    }
+
+    match self.byte_one {
+      Some(byte_one) if byte_one.op_code().is_control() &&
+                        byte_two.is_extended_payload_length() => {
+        Some(CONTROL_FRAME_TOO_LONG)
+      }
+
+      Some(_) => None,
+      None => Some(INVALID_PARSER_STATE)
+    }
+  }
+
+  priv fn parse_payload_length_byte(self, buf: Buffer, byte: u8) -> Result<FrameParser,FrameError> {
     let new_buf = buf.add_byte(byte);
-    let byte_two = self.byte_two.expect("Parsing payload without byte two!");
+    let byte_two = match self.byte_two {
+      Some(byte_two) => byte_two,
+      None => return Err(INVALID_PARSER_STATE)
+    };
     let bytes_to_read = byte_two.payload_bytes_to_read();
 
-    assert!(new_buf.bytes_read <= bytes_to_read);
+    if new_buf.bytes_read > bytes_to_read {
+      return Err(INVALID_PARSER_STATE);
+    }
 
     if new_buf.bytes_read == bytes_to_read {
-      FrameParser {
-        state: next_state_after_payload_length(byte_two),
-        payload_length: Some(new_buf.buf_value),
-        ..
-        self
+      let length = new_buf.buf_value;
+
+      match self.check_extended_length(byte_two, length) {
+        Some(error) => Err(error),
+        None => Ok(FrameParser {
+          state: next_state_after_payload_length(byte_two),
+          payload_length: Some(length),
+          ..
+          self
+        })
       }
     } else {
-      FrameParser {
+      Ok(FrameParser {
         state: READING_PAYLOAD_LENGTH(new_buf),
         ..
         self
-      }
+      })
+    }
+  }
+
+  /* RFC 6455 5.2 forbids the most significant bit of a 64-bit length in
+     every mode; non-minimal encodings are only rejected when strict. */
+  priv fn check_extended_length(&self, byte_two: ByteTwo, length: u64) -> Option<FrameError> {
+    if length & (1 << 63) != 0 {
+      return Some(LENGTH_MOST_SIGNIFICANT_BIT_SET);
+    }
+
+    if payload_length_to_uint(length).is_none() {
+      return Some(LENGTH_EXCEEDS_UINT);
+    }
+
+    if !self.strict {
+      return None;
+    }
+
+    match byte_two.payload_length() {
+      NextTwoBytesAreLength if length < 126 => Some(NON_MINIMAL_LENGTH),
+      NextEightBytesAreLength if length <= 0xFFFF => Some(NON_MINIMAL_LENGTH),
+      _ => None
     }
   }
 
-  priv fn parse_mask_byte(self, buf: Buffer, byte: u8) -> FrameParser {
+  priv fn parse_mask_byte(self, buf: Buffer, byte: u8) -> Result<FrameParser,FrameError> {
     let new_buf = buf.add_byte(byte);
     let bytes_to_read = 4;
 
-    assert!(new_buf.bytes_read <= bytes_to_read);
+    if new_buf.bytes_read > bytes_to_read {
+      return Err(INVALID_PARSER_STATE);
+    }
 
     if new_buf.bytes_read == bytes_to_read {
-      FrameParser {
+      Ok(FrameParser {
         masking_key: Some(MaskingKey(new_buf.buf_value as u32)),
         state: READING_PAYLOAD,
         ..
         self
-      }
+      })
     } else {
-      FrameParser {
+      Ok(FrameParser {
         state: READING_MASK(new_buf),
         ..
         self
-      }
+      })
     }
   }
 
-  priv fn consume_payload_bytes(self, read_so_far: uint, bytes: &[u8]) -> ParseResult {
+  priv fn consume_payload_bytes(self, read_so_far: uint, bytes: &[u8]) -> FrameParseResult {
     let len_read_so_far = self.payload_data.length();
-    let total_len = self.payload_length.expect("Got to parsing payload without length!") as uint;
-    let len_left = cmp::max(0,total_len - len_read_so_far);
+    let total_len = match self.payload_length_uint() {
+      Ok(len) => len,
+      Err(error) => return Err(error)
+    };
+
+    if len_read_so_far > total_len {
+      return Err(INVALID_PARSER_STATE);
+    }
+
+    let len_left = total_len - len_read_so_far;
 
     let remaining_bytes = bytes.tailn(read_so_far);
     let len_to_read = cmp::min(len_left, remaining_bytes.len());
     let bytes_to_read = remaining_bytes.slice(0,len_to_read);
 
-    ParseResult {
-      parser: self.parse_payload_bytes(bytes_to_read),
+    Ok(ParseResult {
+      parser: self.parse_payload_bytes(bytes_to_read, total_len),
       bytes_parsed: read_so_far + bytes_to_read.len(),
+    })
+  }
+
+  priv fn payload_length_uint(&self) -> Result<uint,FrameError> {
+    match self.payload_length {
+      Some(length) => match payload_length_to_uint(length) {
+        Some(len) => Ok(len),
+        None => Err(LENGTH_EXCEEDS_UINT)
+      },
+      None => Err(INVALID_PARSER_STATE)
     }
   }
 
-  priv fn parse_payload_bytes(self, bytes: &[u8]) -> FrameParser {
+  priv fn parse_payload_bytes(self, bytes: &[u8], max_length: uint) -> FrameParser {
     let payload_data = self.payload_data.add_bytes(bytes);
-    let max_length = self.payload_length.expect("Got to parsing payload without length!") as uint;
-
-    assert!(payload_data.length() <= max_length);
 
     let state = if payload_data.length() == max_length {
       DONE
@@ -207,6 +336,14 @@ impl FrameParser {
   }
 }
 
+fn payload_length_to_uint(length: u64) -> Option<uint> {
+  if length > (uint::max_value as u64) {
+    None
+  } else {
+    Some(length as uint)
+  }
+}
+
 fn next_state_after_byte_two(byte_two: ByteTwo) -> ParserState {
   if byte_two.is_extended_payload_length() {
     READING_PAYLOAD_LENGTH(Buffer::new())
@@ -376,7 +513,7 @@ fn parse_with_extra_bytes() {
                        0x01, // length 1
                        0x55, // data
                        0x44,0x33,0x22,0x11 // extra
-                       ]);
+                       ]).unwrap();
 
   assert!(result.parser.is_done());
   assert!(result.bytes_parsed == 3);
@@ -388,7 +525,8 @@ fn parse_with_no_bytes_parsed() {
                .parse_all([0x00,
                            0x02, // length 1
                            0x55, 0x44]) // data
-               .parse([0x44,0x33,0x22,0x11]); // extra
+               .parse([0x44,0x33,0x22,0x11]) // extra
+               .unwrap();
 
   assert!(result.bytes_parsed == 0);
 }
@@ -402,10 +540,130 @@ fn parse_payload_in_multiple_chunks() {
                .parse_all([3,4,5,6]) // more data
                .parse_all([7,8]) // more data
                .parse([9,10, // more data
-                       0x22,0x11]); // extra
+                       0x22,0x11]) // extra
+               .unwrap();
 
   assert!(result.bytes_parsed == 2);
   assert!(result.parser.payload_data ==
           MaskedPayload(PayloadData(@[1,2,3,4,5,6,7,8,9,10])));
 }
 
+
+#[test]
+fn parse_non_minimal_two_byte_length_is_allowed_when_not_strict() {
+  let p = FrameParser::new()
+          .parse_all([0x00, 126, 0x00, 0x05]);
+
+  assert!(p.payload_length == Some(5));
+}
+
+#[test]
+fn parse_strict_rejects_non_minimal_two_byte_length() {
+  let result = FrameParser::new_strict()
+               .parse([0x00, 126, 0x00, 0x7D, 0x55]);
+
+  assert!(result.get_err() == NON_MINIMAL_LENGTH);
+}
+
+#[test]
+fn parse_strict_accepts_minimal_two_byte_length() {
+  let p = FrameParser::new_strict()
+          .parse_all([0x00, 126, 0x00, 0x7E]);
+
+  assert!(p.payload_length == Some(126));
+}
+
+#[test]
+fn parse_strict_rejects_non_minimal_eight_byte_length() {
+  let result = FrameParser::new_strict()
+               .parse([0x00, 127,
+                       0x00,0x00,0x00,0x00,0x00,0x00,0xFF,0xFF]);
+
+  assert!(result.get_err() == NON_MINIMAL_LENGTH);
+}
+
+#[test]
+fn parse_strict_accepts_minimal_eight_byte_length() {
+  let p = FrameParser::new_strict()
+          .parse_all([0x00, 127,
+                      0x00,0x00,0x00,0x00,0x00,0x01,0x00,0x00]);
+
+  assert!(p.payload_length == Some(0x010000));
+}
+
+#[test]
+fn parse_stops_consuming_after_length_error() {
+  let p = FrameParser::new_strict()
+          .parse_all([0x00, 126, 0x00]);
+  let result = p.parse([0x01, 0x55, 0x44]);
+
+  assert!(result.get_err() == NON_MINIMAL_LENGTH);
+  assert!(p.parse([0x7E, 0x55]).unwrap().bytes_parsed == 2);
+}
+
+#[test]
+fn parse_strict_rejects_eight_byte_length_with_msb_set() {
+  let result = FrameParser::new_strict()
+               .parse([0x00, 127,
+                       0x80,0x00,0x00,0x00,0x00,0x01,0x00,0x00]);
+
+  assert!(result.get_err() == LENGTH_MOST_SIGNIFICANT_BIT_SET);
+}
+
+#[test]
+fn parse_rejects_eight_byte_length_with_msb_set_when_not_strict() {
+  let result = FrameParser::new()
+               .parse([0x00, 127,
+                       0x80,0x00,0x00,0x00,0x00,0x00,0x00,0x00]);
+
+  assert!(result.get_err() == LENGTH_MOST_SIGNIFICANT_BIT_SET);
+}
+
+#[test]
+fn parse_strict_rejects_reserved_op_codes() {
+  let non_control = FrameParser::new_strict().parse([0x83]);
+  let control = FrameParser::new_strict().parse([0x8B]);
+
+  assert!(non_control.get_err() == RESERVED_OP_CODE(0x3));
+  assert!(control.get_err() == RESERVED_OP_CODE(0xB));
+}
+
+#[test]
+fn parse_strict_rejects_fragmented_control_frames() {
+  let result = FrameParser::new_strict().parse([0x09, 0x00]);
+
+  assert!(result.get_err() == FRAGMENTED_CONTROL_FRAME);
+}
+
+#[test]
+fn parse_strict_rejects_long_control_frames() {
+  let result = FrameParser::new_strict().parse([0x89, 126, 0x00, 0x7E]);
+
+  assert!(result.get_err() == CONTROL_FRAME_TOO_LONG);
+}
+
+#[test]
+fn parse_strict_accepts_short_control_frames() {
+  let p = FrameParser::new_strict()
+          .parse_all([0x89, 0x02, 0x55, 0x44]);
+
+  assert!(p.is_done());
+}
+
+#[test]
+fn frame_error_close_codes() {
+  assert!(NON_MINIMAL_LENGTH.close_code() == CLOSE_PROTOCOL_ERROR);
+  assert!(RESERVED_OP_CODE(0x3).close_code() == CLOSE_PROTOCOL_ERROR);
+  assert!(LENGTH_EXCEEDS_UINT.close_code() == CLOSE_MESSAGE_TOO_BIG);
+  assert!(INVALID_PARSER_STATE.close_code() == CLOSE_INTERNAL_ERROR);
+}
+
+#[test]
+fn payload_length_to_uint_checks_range() {
+  assert!(payload_length_to_uint(0x7A4B) == Some(0x7A4B));
+  assert!(payload_length_to_uint(uint::max_value as u64) == Some(uint::max_value));
+
+  if uint::bits < 64 {
+    assert!(payload_length_to_uint((uint::max_value as u64) + 1) == None);
+  }
+}
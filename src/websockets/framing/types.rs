@@ -1,4 +1,4 @@
-use websockets::messaging::{Fragment,FragmentType,Text,Data,Continuation};
+use websockets::messaging::{Fragment,FragmentType,Text,Data,Continuation,Control,Reserved};
 
 #[deriving(Eq,Clone)]
 pub struct Frame {
@@ -21,6 +21,50 @@ impl Frame {
   pub fn is_reserved(&self) -> bool {
     self.reserved
   }
+
+  pub fn new(op_code: OpCode, payload: &[u8]) -> Frame {
+    Frame {
+      fin: true,
+      reserved: false,
+      op_code: op_code,
+      masking_key: None,
+      payload_data: MaskedPayload(PayloadData::from_bytes(payload)),
+    }
+  }
+
+  pub fn text(text: &str) -> Frame {
+    Frame::new(TEXT, text.to_bytes())
+  }
+
+  pub fn binary(data: &[u8]) -> Frame {
+    Frame::new(BINARY, data)
+  }
+
+  pub fn pong(payload: &[u8]) -> Frame {
+    Frame::new(PONG, payload)
+  }
+
+  pub fn close(code: u16, reason: &str) -> Frame {
+    let payload = ~[(code >> 8) as u8, code as u8] + reason.to_bytes();
+    Frame::new(CONNECTION_CLOSE, payload)
+  }
+
+  pub fn close_status(&self) -> (u16, ~str) {
+    let payload = self.unmasked_payload().to_bytes();
+
+    if payload.len() < 2 {
+      return (CLOSE_NO_STATUS, ~"");
+    }
+
+    let code = (payload[0] as u16 << 8) | payload[1] as u16;
+    let reason = payload.tailn(2);
+
+    if str::is_utf8(reason) {
+      (code, str::from_bytes(reason))
+    } else {
+      (code, ~"")
+    }
+  }
 }
 
 impl Fragment for Frame {
@@ -29,8 +73,8 @@ impl Fragment for Frame {
       CONTINUATION => Continuation,
       TEXT => Text,
       BINARY => Data,
-      _ => fail!(~"Invalid opcode for fragmenting: " +
-                 self.op_code.to_byte().to_str() + ~"!")
+      RESERVED_NON_CONTROL(_) => Reserved,
+      _ => Control
     }
   }
 
@@ -55,6 +99,17 @@ pub static OP_CODE_MASK: u8 = 0x0F;
 pub static MASK_MASK: u8 = 0x80;
 pub static PAYLOAD_LENGTH_MASK: u8 = 0x7F;
 
+pub static CLOSE_NORMAL: u16 = 1000;
+pub static CLOSE_GOING_AWAY: u16 = 1001;
+pub static CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub static CLOSE_NO_STATUS: u16 = 1005;
+pub static CLOSE_ABNORMAL: u16 = 1006;
+pub static CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub static CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub static CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+pub static CLOSE_INTERNAL_ERROR: u16 = 1011;
+pub static CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
 impl ByteOne {
   fn is_fin(&self) -> bool {
     (**self) & FIN_MASK != 0
@@ -352,6 +407,49 @@ fn frame_byte_2_bits_1234567_is_extended_payload_length() {
   assert!(ByteTwo(127).is_extended_payload_length());
 }
 
+#[test]
+fn frame_fragment_type() {
+  let mut frame = Frame::close(CLOSE_NORMAL, "");
+
+  assert!(frame.fragment_type() == Control);
+
+  frame.op_code = TEXT;
+  assert!(frame.fragment_type() == Text);
+
+  frame.op_code = RESERVED_NON_CONTROL(0x3);
+  assert!(frame.fragment_type() == Reserved);
+
+  frame.op_code = RESERVED_CONTROL(0xB);
+  assert!(frame.fragment_type() == Control);
+}
+
+#[test]
+fn close_frame_payload() {
+  let frame = Frame::close(CLOSE_PROTOCOL_ERROR, "bad");
+
+  assert!(frame.fin);
+  assert!(frame.op_code == CONNECTION_CLOSE);
+  assert!(frame.unmasked_payload() == PayloadData(@[0x03,0xEA,98,97,100]));
+}
+
+#[test]
+fn close_frame_status() {
+  assert!(Frame::close(CLOSE_GOING_AWAY, "bye").close_status() == (CLOSE_GOING_AWAY, ~"bye"));
+  assert!(Frame::new(CONNECTION_CLOSE, []).close_status() == (CLOSE_NO_STATUS, ~""));
+}
+
+#[test]
+fn data_frame_constructors() {
+  let text = Frame::text("hi");
+  let pong = Frame::pong([1, 2]);
+
+  assert!(text.op_code == TEXT);
+  assert!(text.unmasked_payload() == PayloadData(@[104, 105]));
+  assert!(Frame::binary([7]).op_code == BINARY);
+  assert!(pong.op_code == PONG);
+  assert!(pong.unmasked_payload() == PayloadData(@[1, 2]));
+}
+
 #[test]
 fn masking_key_byte_mask() {
   let key = MaskingKey(0xFFF00F00);
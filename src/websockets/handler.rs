@@ -0,0 +1,30 @@
+use http::parser::Parser;
+use websockets::framing::parser::FrameError;
+use websockets::messaging::ReceptionError;
+use websockets::websocket::{WebSocket, Transport};
+
+#[deriving(Eq)]
+pub enum ConnectionError {
+  INVALID_FRAME(FrameError),
+  INVALID_MESSAGE(ReceptionError),
+  CONNECTION_LOST(~str),
+}
+
+pub trait Handler {
+  fn on_open<T: Transport>(&mut self, socket: &WebSocket<T>, request: &Parser);
+  fn on_text<T: Transport>(&mut self, socket: &WebSocket<T>, text: &str);
+  fn on_binary<T: Transport>(&mut self, socket: &WebSocket<T>, data: &[u8]);
+  fn on_ping<T: Transport>(&mut self, socket: &WebSocket<T>, payload: &[u8]);
+  fn on_close<T: Transport>(&mut self, socket: &WebSocket<T>, code: u16, reason: &str);
+  fn on_error<T: Transport>(&mut self, socket: &WebSocket<T>, error: &ConnectionError);
+}
+
+impl ConnectionError {
+  pub fn to_str(&self) -> ~str {
+    match *self {
+      INVALID_FRAME(ref error) => ~"Invalid frame: " + sys::log_str(error),
+      INVALID_MESSAGE(ref error) => ~"Invalid message: " + sys::log_str(error),
+      CONNECTION_LOST(ref message) => message.clone(),
+    }
+  }
+}
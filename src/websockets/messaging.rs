@@ -22,13 +22,16 @@ struct TextMessage(@str);
 enum ReceptionError {
   CONTINUATION_AS_FIRST_FRAME,
   INVALID_MESSAGE_TYPE(FragmentType),
+  INVALID_UTF8,
 }
 
 #[deriving(Eq)]
 pub enum FragmentType {
   Text,
   Data,
-  Continuation
+  Continuation,
+  Control,
+  Reserved
 }
 
 pub trait Fragment {
@@ -41,6 +44,12 @@ impl Receiver {
   pub fn new() -> Receiver { Unstarted }
 
   fn next_fragment<F: Fragment>(&self, fragment: F) -> Reception {
+    match fragment.fragment_type() {
+      Control => return ReceptionError(INVALID_MESSAGE_TYPE(Control)),
+      Reserved => return ReceptionError(INVALID_MESSAGE_TYPE(Reserved)),
+      _ => {}
+    }
+
     let (msg_type, message_so_far) =
       match *self {
         InProgress(t,msg) => (t,msg),
@@ -70,10 +79,11 @@ fn handle_fragment<F: Fragment>(msg_type: FragmentType,
 fn build_message_reception(msg_type: FragmentType, message: @[u8]) -> Reception {
   match msg_type {
     Data => Received(Left(DataMessage(message))),
-    Text => {
+    Text if str::is_utf8(message) => {
       let text = str::from_bytes(message).to_managed();
       Received(Right(TextMessage(text)))
     },
+    Text => ReceptionError(INVALID_UTF8),
     t => ReceptionError(INVALID_MESSAGE_TYPE(t)),
   }
 }
@@ -114,6 +124,16 @@ fn test_assemble_text_message_in_multiple_fragment() {
   assert!(result == Received(Right(TextMessage(@"i ♥ u"))));
 }
 
+#[test]
+fn test_error_when_text_message_is_not_utf8() {
+  let receiver = assert_receiving(
+                  Receiver::new()
+                  .next_fragment((Text,false,@[105,32,226 as u8])));
+
+  let result = receiver.next_fragment((Continuation,true,@[153,32,117 as u8]));
+  assert!(result == ReceptionError(INVALID_UTF8));
+}
+
 #[test]
 fn test_error_when_initial_frame_is_continuation() {
   let result = Receiver::new()
@@ -122,6 +142,22 @@ fn test_error_when_initial_frame_is_continuation() {
   assert!(result == ReceptionError(CONTINUATION_AS_FIRST_FRAME));
 }
 
+#[test]
+fn test_error_when_fragment_is_control() {
+  let result = Receiver::new()
+               .next_fragment((Control,true,@[0 as u8]));
+
+  assert!(result == ReceptionError(INVALID_MESSAGE_TYPE(Control)));
+}
+
+#[test]
+fn test_error_when_fragment_is_reserved() {
+  let result = Receiver::new()
+               .next_fragment((Reserved,true,@[0 as u8]));
+
+  assert!(result == ReceptionError(INVALID_MESSAGE_TYPE(Reserved)));
+}
+
 impl Fragment for (FragmentType,bool,@[u8]) {
   fn fragment_type(&self) -> FragmentType {
     match *self { (fragment_type,_,_) => fragment_type }
@@ -0,0 +1,418 @@
+use std::arc;
+use std::time::precise_time_ns;
+use std::timer;
+use std::uv_global_loop;
+use websockets::framing::types::{CLOSE_POLICY_VIOLATION, CLOSE_TRY_AGAIN_LATER};
+use websockets::websocket::{ConnectionCommand, SendBytes, SendShared, Disconnect};
+
+pub static DEFAULT_QUEUE_LIMIT: uint = 1024;
+
+/* How long BLOCK_PRODUCER waits for room before the consumer is treated as
+   stuck and disconnected with CLOSE_TRY_AGAIN_LATER. */
+pub static BLOCK_TIMEOUT_MS: uint = 5000;
+
+#[deriving(Eq,Clone)]
+pub enum OverflowPolicy {
+  BLOCK_PRODUCER,
+  DROP_OLDEST,
+  DROP_NEWEST,
+  DISCONNECT(u16),
+}
+
+#[deriving(Eq,Clone)]
+pub struct QueueStats {
+  limit: uint,
+  depth: uint,
+  high_water: uint,
+  dropped: u64,
+}
+
+struct QueueState {
+  commands: ~[ConnectionCommand],
+  depth: uint,
+  accepting: bool,
+  consumer_gone: bool,
+  stats: QueueStats,
+}
+
+/* Messages waiting to be written to one connection. Producers push, the
+   connection's own task pops. Control commands (close and abort) keep their
+   place in line but never count against the limit and are never dropped. */
+pub struct OutboundQueue {
+  state: arc::MutexARC<QueueState>,
+  policy: OverflowPolicy,
+  block_timeout_ms: uint,
+}
+
+impl OverflowPolicy {
+  pub fn from_str(name: &str) -> Option<OverflowPolicy> {
+    if name == "block" {
+      Some(BLOCK_PRODUCER)
+    } else if name == "drop_oldest" {
+      Some(DROP_OLDEST)
+    } else if name == "drop_newest" {
+      Some(DROP_NEWEST)
+    } else if name == "disconnect_1008" {
+      Some(DISCONNECT(CLOSE_POLICY_VIOLATION))
+    } else if name == "disconnect_1013" {
+      Some(DISCONNECT(CLOSE_TRY_AGAIN_LATER))
+    } else {
+      None
+    }
+  }
+}
+
+impl Clone for OutboundQueue {
+  fn clone(&self) -> OutboundQueue {
+    OutboundQueue {
+      state: self.state.clone(),
+      policy: self.policy,
+      block_timeout_ms: self.block_timeout_ms
+    }
+  }
+}
+
+impl OutboundQueue {
+  pub fn new(limit: uint, policy: OverflowPolicy) -> OutboundQueue {
+    let state = QueueState {
+      commands: ~[],
+      depth: 0,
+      accepting: true,
+      consumer_gone: false,
+      stats: QueueStats { limit: limit, depth: 0, high_water: 0, dropped: 0 },
+    };
+
+    OutboundQueue {
+      state: arc::MutexARC(state),
+      policy: policy,
+      block_timeout_ms: BLOCK_TIMEOUT_MS
+    }
+  }
+
+  /* Applies the overflow policy when the queue is full. Only BLOCK_PRODUCER
+     ever waits, and then only the calling task for up to block_timeout_ms.
+     Returns false when the message was not queued. */
+  pub fn push(&self, command: ConnectionCommand) -> bool {
+    self.push_with(command, true)
+  }
+
+  /* For the connection's own task, which is the only one that pops and so
+     would wait for itself forever. BLOCK_PRODUCER drops the message instead. */
+  pub fn push_without_waiting(&self, command: ConnectionCommand) -> bool {
+    self.push_with(command, false)
+  }
+
+  priv fn push_with(&self, command: ConnectionCommand, may_wait: bool) -> bool {
+    let policy = self.policy;
+    let timeout_ms = self.block_timeout_ms;
+    let mut command = Some(command);
+
+    unsafe {
+      do self.state.access_cond |state, cond| {
+        let mut timed_out = false;
+
+        if policy == BLOCK_PRODUCER && may_wait && state.accepting &&
+           state.depth >= state.stats.limit {
+          let deadline = precise_time_ns() + (timeout_ms as u64) * 1000000;
+          wake_after(self.clone(), timeout_ms);
+
+          while state.accepting && state.depth >= state.stats.limit && !timed_out {
+            cond.wait();
+            timed_out = precise_time_ns() >= deadline;
+          }
+        }
+
+        if !state.accepting {
+          false
+        } else if state.depth < state.stats.limit {
+          state.commands.push(command.swap_unwrap());
+          state.depth += 1;
+          state.stats.high_water = uint::max(state.stats.high_water, state.depth);
+          true
+        } else {
+          match policy {
+            DROP_OLDEST => {
+              match vec::position(state.commands, is_message) {
+                Some(index) => { state.commands.remove(index); }
+                None => {}
+              }
+
+              state.commands.push(command.swap_unwrap());
+              state.stats.dropped += 1;
+              true
+            }
+
+            DISCONNECT(code) => {
+              state.disconnect(code);
+              false
+            }
+
+            BLOCK_PRODUCER if timed_out => {
+              state.disconnect(CLOSE_TRY_AGAIN_LATER);
+              false
+            }
+
+            _ => {
+              state.stats.dropped += 1;
+              false
+            }
+          }
+        }
+      }
+    }
+  }
+
+  pub fn push_control(&self, command: ConnectionCommand) -> bool {
+    let mut command = Some(command);
+
+    unsafe {
+      do self.state.access |state| {
+        if !state.consumer_gone {
+          state.commands.push(command.swap_unwrap());
+        }
+
+        !state.consumer_gone
+      }
+    }
+  }
+
+  /* Queues a message without applying the overflow policy, so the caller
+     never waits. Only for bounded bursts such as history replay; the depth
+     may briefly exceed the limit. */
+  pub fn push_unbounded(&self, command: ConnectionCommand) -> bool {
+    let mut command = Some(command);
+
+    unsafe {
+      do self.state.access |state| {
+        if state.accepting {
+          state.commands.push(command.swap_unwrap());
+          state.depth += 1;
+          state.stats.high_water = uint::max(state.stats.high_water, state.depth);
+        }
+
+        state.accepting
+      }
+    }
+  }
+
+  pub fn pop(&self) -> Option<ConnectionCommand> {
+    unsafe {
+      do self.state.access_cond |state, cond| {
+        if state.commands.is_empty() {
+          None
+        } else {
+          let command = state.commands.shift();
+
+          if is_message(&command) {
+            state.depth -= 1;
+            cond.broadcast();
+          }
+
+          Some(command)
+        }
+      }
+    }
+  }
+
+  /* Called by the consumer once the connection is gone. Pending messages are
+     discarded and blocked producers are released. */
+  pub fn close(&self) {
+    unsafe {
+      do self.state.access_cond |state, cond| {
+        state.accepting = false;
+        state.consumer_gone = true;
+        state.commands = ~[];
+        state.depth = 0;
+        cond.broadcast();
+      }
+    }
+  }
+
+  pub fn stats(&self) -> QueueStats {
+    unsafe {
+      do self.state.access |state| {
+        QueueStats { depth: state.depth, .. state.stats.clone() }
+      }
+    }
+  }
+}
+
+impl QueueState {
+  fn disconnect(&mut self, code: u16) {
+    self.stats.dropped += (self.depth + 1) as u64;
+    self.remove_messages();
+    self.commands.push(Disconnect(code, ~"Outbound queue full"));
+    self.accepting = false;
+  }
+
+  fn remove_messages(&mut self) {
+    let mut control = ~[];
+
+    do vec::consume(util::replace(&mut self.commands, ~[])) |_, command| {
+      if !is_message(&command) {
+        control.push(command);
+      }
+    }
+
+    self.commands = control;
+    self.depth = 0;
+  }
+}
+
+/* Condition variables cannot time out, so a helper task wakes the waiting
+   producers, which then check their own deadlines. */
+fn wake_after(queue: OutboundQueue, timeout_ms: uint) {
+  do task::spawn {
+    timer::sleep(&uv_global_loop::get(), timeout_ms);
+
+    unsafe {
+      do queue.state.access_cond |_, cond| {
+        cond.broadcast();
+      }
+    }
+  }
+}
+
+fn is_message(command: &ConnectionCommand) -> bool {
+  match *command {
+    SendBytes(_) | SendShared(_) => true,
+    _ => false
+  }
+}
+
+#[cfg(test)]
+fn test_message(byte: u8) -> ConnectionCommand {
+  websockets::websocket::SendBytes(~[byte])
+}
+
+#[test]
+fn overflow_policies() {
+  assert!(OverflowPolicy::from_str("drop_oldest") == Some(DROP_OLDEST));
+  assert!(OverflowPolicy::from_str("disconnect_1013") == Some(DISCONNECT(CLOSE_TRY_AGAIN_LATER)));
+  assert!(OverflowPolicy::from_str("disconnect") == None);
+}
+
+#[test]
+fn drop_oldest_keeps_newest_messages() {
+  let queue = OutboundQueue::new(2, DROP_OLDEST);
+
+  assert!(queue.push(test_message(1)));
+  assert!(queue.push(test_message(2)));
+  assert!(queue.push(test_message(3)));
+
+  assert!(queue.stats() == QueueStats { limit: 2, depth: 2, high_water: 2, dropped: 1 });
+  assert!(queue.pop() == Some(test_message(2)));
+  assert!(queue.pop() == Some(test_message(3)));
+  assert!(queue.pop() == None);
+}
+
+#[test]
+fn drop_newest_rejects_new_messages() {
+  let queue = OutboundQueue::new(1, DROP_NEWEST);
+
+  assert!(queue.push(test_message(1)));
+  assert!(!queue.push(test_message(2)));
+
+  assert!(queue.stats().dropped == 1);
+  assert!(queue.pop() == Some(test_message(1)));
+  assert!(queue.pop() == None);
+}
+
+#[test]
+fn disconnect_policy_closes_slow_consumer() {
+  let queue = OutboundQueue::new(1, DISCONNECT(CLOSE_POLICY_VIOLATION));
+
+  assert!(queue.push(test_message(1)));
+  assert!(!queue.push(test_message(2)));
+  assert!(!queue.push(test_message(3)));
+
+  assert!(queue.pop() == Some(Disconnect(CLOSE_POLICY_VIOLATION, ~"Outbound queue full")));
+  assert!(queue.pop() == None);
+  assert!(queue.stats().dropped == 2);
+}
+
+#[test]
+fn control_commands_bypass_the_limit() {
+  let queue = OutboundQueue::new(1, DROP_OLDEST);
+
+  assert!(queue.push(test_message(1)));
+  assert!(queue.push_control(websockets::websocket::Abort));
+  assert!(queue.push(test_message(2)));
+
+  assert!(queue.stats().depth == 1);
+  assert!(queue.pop() == Some(websockets::websocket::Abort));
+  assert!(queue.pop() == Some(test_message(2)));
+  assert!(queue.pop() == None);
+
+  queue.close();
+  assert!(!queue.push(test_message(2)));
+  assert!(!queue.push_control(websockets::websocket::Abort));
+}
+
+#[test]
+fn unbounded_push_ignores_the_limit() {
+  let queue = OutboundQueue::new(1, BLOCK_PRODUCER);
+
+  assert!(queue.push_unbounded(test_message(1)));
+  assert!(queue.push_unbounded(test_message(2)));
+
+  assert!(queue.stats() == QueueStats { limit: 1, depth: 2, high_water: 2, dropped: 0 });
+  assert!(queue.pop() == Some(test_message(1)));
+
+  queue.close();
+  assert!(!queue.push_unbounded(test_message(3)));
+}
+
+#[test]
+fn block_policy_waits_for_consumer() {
+  let queue = OutboundQueue::new(1, BLOCK_PRODUCER);
+  let producer_queue = queue.clone();
+  let (done_po, done_ch) = core::comm::stream();
+
+  assert!(queue.push(test_message(1)));
+
+  do task::spawn {
+    done_ch.send(producer_queue.push(test_message(2)));
+  }
+
+  assert!(queue.pop() == Some(test_message(1)));
+  assert!(done_po.recv());
+  assert!(queue.pop() == Some(test_message(2)));
+
+  let closed_queue = queue.clone();
+  let (closed_po, closed_ch) = core::comm::stream();
+  assert!(queue.push(test_message(3)));
+
+  do task::spawn {
+    closed_ch.send(closed_queue.push(test_message(4)));
+  }
+
+  queue.close();
+  assert!(!closed_po.recv());
+}
+
+#[test]
+fn block_policy_disconnects_after_timeout() {
+  let mut queue = OutboundQueue::new(1, BLOCK_PRODUCER);
+  queue.block_timeout_ms = 20;
+
+  assert!(queue.push(test_message(1)));
+  assert!(!queue.push(test_message(2)));
+  assert!(!queue.push(test_message(3)));
+
+  assert!(queue.pop() == Some(Disconnect(CLOSE_TRY_AGAIN_LATER, ~"Outbound queue full")));
+  assert!(queue.pop() == None);
+  assert!(queue.stats().dropped == 2);
+}
+
+#[test]
+fn push_without_waiting_drops_instead_of_blocking() {
+  let queue = OutboundQueue::new(1, BLOCK_PRODUCER);
+
+  assert!(queue.push_without_waiting(test_message(1)));
+  assert!(!queue.push_without_waiting(test_message(2)));
+
+  assert!(queue.stats().dropped == 1);
+  assert!(queue.pop() == Some(test_message(1)));
+  assert!(queue.pop() == None);
+}
@@ -1,5 +1,6 @@
 use std::sha1;
 use std::base64::ToBase64;
+use http::auth::*;
 use http::headers::*;
 use http::request::*;
 
@@ -14,7 +15,9 @@ fn accept_key(key: &str) -> ~str {
 
 #[deriving(Eq)]
 pub struct WebsocketAcceptance {
-  key_accept: ~str
+  key_accept: ~str,
+  identity: Identity,
+  protocol: Option<~str>
 }
 
 #[deriving(Eq)]
@@ -29,11 +32,18 @@ enum WebsocketAcceptError {
   UPGRADE_WEBSOCKET_REQUIRED,
   CONNECTION_REQUIRED,
   CONNECTION_UPGRADE_REQUIRED,
+  AUTHORIZATION_FAILED(AuthError),
 }
 
 pub type AcceptResult = Result<WebsocketAcceptance,WebsocketAcceptError>;
 
 pub fn accept_request<T: Headers+Request>(request: &T) -> AcceptResult {
+  accept_authenticated_request(request, &AnonymousAuthenticator)
+}
+
+pub fn accept_authenticated_request<T: Headers+Request, A: Authenticator>(request: &T,
+                                                                          authenticator: &A)
+   -> AcceptResult {
   if !(request.http_version() == Some(HttpVersion(1,1))) {
     return Err(HTTP_1_PT_1_REQUIRED)
   }
@@ -70,16 +80,36 @@ pub fn accept_request<T: Headers+Request>(request: &T) -> AcceptResult {
     return Err(INVALID_WEBSOCKET_VERSION)
   }
 
-  let key = request.get_header("Sec-WebSocket-Key");
+  let key = match request.get_header("Sec-WebSocket-Key") {
+    Some(key) => key,
+    None => return Err(WEBSOCKET_KEY_REQUIRED)
+  };
 
-  match key {
-    Some(str) => {
-      Ok(WebsocketAcceptance { key_accept: accept_key(str) })
-    }
+  match authenticator.authenticate(request) {
+    Ok(identity) => Ok(WebsocketAcceptance {
+      key_accept: accept_key(key),
+      identity: identity,
+      protocol: None
+    }),
+    Err(error) => Err(AUTHORIZATION_FAILED(error))
+  }
+}
 
-    _ => {
-      Err(WEBSOCKET_KEY_REQUIRED)
+/* Picks the first subprotocol offered by the client that the server
+   supports. A malformed Sec-WebSocket-Protocol header negotiates nothing. */
+pub fn select_protocol<T: Headers>(request: &T, supported: &[~str]) -> Option<~str> {
+  match request.get_header_tokens("Sec-WebSocket-Protocol") {
+    Ok(offered) => {
+      for offered.each |protocol| {
+        if supported.contains(protocol) {
+          return Some(copy *protocol);
+        }
+      }
+
+      None
     }
+
+    Err(_) => None
   }
 }
 
@@ -87,12 +117,20 @@ impl AcceptResult {
   fn to_websocket_response_str(&self) -> ~str {
     if self.is_ok() {
       let acceptance = self.get_ref();
+      let protocol = match acceptance.protocol {
+        Some(ref protocol) => ~"Sec-WebSocket-Protocol: " + *protocol + "\r\n",
+        None => ~""
+      };
+
       ~"HTTP/1.1 101 Switching Protocols\r\n\
         Upgrade: websocket\r\n\
         Connection: Upgrade\r\n\
-        Sec-WebSocket-Accept: " + acceptance.key_accept + "\r\n\r\n"
+        Sec-WebSocket-Accept: " + acceptance.key_accept + "\r\n" + protocol + "\r\n"
     } else {
-      ~"HTTP/1.1 400 Bad Request\r\n\r\n"
+      match *self {
+        Err(AUTHORIZATION_FAILED(ref error)) => error.to_response_str(),
+        _ => ~"HTTP/1.1 400 Bad Request\r\n\r\n"
+      }
     }
   }
 }
@@ -110,7 +148,9 @@ fn accept_request_base_success() {
   let request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
 
   assert!(accept_request(&request) == Ok(WebsocketAcceptance{
-    key_accept: ~"s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    key_accept: ~"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+    identity: Identity::anonymous(),
+    protocol: None
   }));
 }
 
@@ -184,10 +224,20 @@ fn accept_request_missing_key() {
   assert!(accept_request(&request) == Err(WEBSOCKET_KEY_REQUIRED));
 }
 
+#[test]
+fn accept_request_connection_upgrade_in_second_header() {
+  let mut request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
+  request.headers.set_header("Connection", "keep-alive");
+  request.headers.add_header("Connection", "Upgrade");
+  assert!(accept_request(&request).is_ok());
+}
+
 #[test]
 fn ok_accept_response_string() {
   let success: AcceptResult = Ok(WebsocketAcceptance {
-    key_accept: ~"foobarbazbat"
+    key_accept: ~"foobarbazbat",
+    identity: Identity::anonymous(),
+    protocol: None
   });
 
   let expected = "\
@@ -201,6 +251,42 @@ fn ok_accept_response_string() {
   assert!(expected == success.to_websocket_response_str())
 }
 
+#[test]
+fn ok_accept_response_string_with_protocol() {
+  let success: AcceptResult = Ok(WebsocketAcceptance {
+    key_accept: ~"foobarbazbat",
+    identity: Identity::anonymous(),
+    protocol: Some(~"chat")
+  });
+
+  let expected = "\
+  HTTP/1.1 101 Switching Protocols\r\n\
+  Upgrade: websocket\r\n\
+  Connection: Upgrade\r\n\
+  Sec-WebSocket-Accept: foobarbazbat\r\n\
+  Sec-WebSocket-Protocol: chat\r\n\
+  \r\n\
+  ";
+
+  assert!(expected == success.to_websocket_response_str())
+}
+
+#[test]
+fn select_protocol_prefers_client_order() {
+  let mut request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
+  let supported = ~[~"chat", ~"superchat"];
+
+  assert!(select_protocol(&request, supported) == None);
+
+  request.headers.set_header("Sec-WebSocket-Protocol", "v2.chat, superchat");
+  request.headers.add_header("Sec-WebSocket-Protocol", "chat");
+  assert!(select_protocol(&request, supported) == Some(~"superchat"));
+  assert!(select_protocol(&request, []) == None);
+
+  request.headers.set_header("Sec-WebSocket-Protocol", "chat, \"quoted\"");
+  assert!(select_protocol(&request, supported) == None);
+}
+
 #[test]
 fn err_accept_response_string() {
   let success: AcceptResult = Err(UPGRADE_REQUIRED);
@@ -209,9 +295,123 @@ fn err_accept_response_string() {
   assert!(expected == success.to_websocket_response_str())
 }
 
+struct TokenAuthenticator {
+  token: ~str,
+  challenge: ~str
+}
+
+impl Authenticator for TokenAuthenticator {
+  fn authenticate<T: Headers+Request>(&self, request: &T) -> Result<Identity,AuthError> {
+    match request.get_bearer_token("access_token") {
+      Some(ref token) if *token == self.token => Ok(Identity::new(*token)),
+      Some(_) => Err(FORBIDDEN),
+      None => Err(UNAUTHORIZED(copy self.challenge))
+    }
+  }
+}
+
+fn token_authenticator(challenge: &str) -> TokenAuthenticator {
+  TokenAuthenticator { token: ~"letmein", challenge: str::from_slice(challenge) }
+}
+
+#[test]
+fn accept_request_runs_authorization() {
+  let mut request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
+  request.headers.set_header("Authorization", "Bearer letmein");
+
+  let result = accept_authenticated_request(&request, &token_authenticator("Bearer"));
+
+  assert!(result.is_ok());
+  assert!(result.get_ref().identity.subject == Some(~"letmein"));
+}
+
+#[test]
+fn accept_request_ignores_duplicate_authorization() {
+  let mut request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
+  request.headers.add_header("Authorization", "Bearer guest");
+  request.headers.add_header("Authorization", "Bearer letmein");
+
+  assert!(request.get_credentials() == None);
+
+  let result = accept_authenticated_request(&request, &token_authenticator("Bearer"));
+
+  assert!(result == Err(AUTHORIZATION_FAILED(UNAUTHORIZED(~"Bearer"))));
+}
+
+#[test]
+fn accept_request_unauthorized() {
+  let request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
+
+  let result = accept_authenticated_request(&request,
+                                            &token_authenticator("Bearer realm=\"chat\""));
+
+  assert!(result == Err(AUTHORIZATION_FAILED(UNAUTHORIZED(~"Bearer realm=\"chat\""))));
+  assert!(result.to_websocket_response_str() ==
+          ~"HTTP/1.1 401 Unauthorized\r\n\
+            WWW-Authenticate: Bearer realm=\"chat\"\r\n\r\n");
+}
+
+#[test]
+fn accept_request_forbidden_with_query_token() {
+  let mut request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
+  request.url = Some(~"/chat?access_token=guest");
+
+  let result = accept_authenticated_request(&request, &token_authenticator("Bearer"));
+
+  assert!(result.to_websocket_response_str() == ~"HTTP/1.1 403 Forbidden\r\n\r\n");
+}
+
+#[test]
+fn accept_request_validates_handshake_before_authorization() {
+  let mut request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
+  request.headers.remove_header("Host");
+
+  let result = accept_authenticated_request(&request, &token_authenticator("Bearer"));
+
+  assert!(result == Err(HOST_REQUIRED));
+}
+
+struct SessionAuthenticator;
+
+impl Authenticator for SessionAuthenticator {
+  fn authenticate<T: Headers+Request>(&self, request: &T) -> Result<Identity,AuthError> {
+    match request.get_cookie("session") {
+      Some(session) => {
+        let mut identity = Identity::new("alice");
+        identity.add_claim("session", session);
+        Ok(identity)
+      }
+
+      None => Err(UNAUTHORIZED(~"Cookie"))
+    }
+  }
+}
+
+#[test]
+fn accept_request_attaches_identity() {
+  let mut request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
+  request.headers.set_header("Cookie", "theme=dark; session=abc123");
+
+  let result = accept_authenticated_request(&request, &SessionAuthenticator);
+  let identity = &result.get_ref().identity;
+
+  assert!(identity.subject == Some(~"alice"));
+  assert!(identity.get_claim("session") == Some(~"abc123"));
+}
+
+#[test]
+fn accept_request_authenticator_rejects() {
+  let request = acceptable_websocket_request("dGhlIHNhbXBsZSBub25jZQ==");
+
+  let result = accept_authenticated_request(&request, &SessionAuthenticator);
+
+  assert!(result == Err(AUTHORIZATION_FAILED(UNAUTHORIZED(~"Cookie"))));
+}
+
 struct TestRequest {
   http_version: Option<HttpVersion>,
   method: Option<Method>,
+  url: Option<~str>,
   headers: HeaderMap
 }
 
@@ -220,6 +420,10 @@ impl Request for TestRequest {
     self.method
   }
 
+  fn url(&self) -> Option<~str> {
+    self.url.clone()
+  }
+
   fn http_version(&self) -> Option<HttpVersion> {
     self.http_version
   }
@@ -230,6 +434,18 @@ impl Headers for TestRequest {
     self.headers.get_header(name)
   }
 
+  fn get_all(&self, name: &str) -> Option<~[~str]> {
+    self.headers.get_all(name)
+  }
+
+  fn get_header_bytes(&self, name: &str) -> Option<~[u8]> {
+    self.headers.get_header_bytes(name)
+  }
+
+  fn get_all_bytes(&self, name: &str) -> ~[~[u8]] {
+    self.headers.get_all_bytes(name)
+  }
+
   fn has_header(&self, name: &str) -> bool {
     self.headers.has_header(name)
   }
@@ -239,6 +455,7 @@ fn acceptable_websocket_request(key: &str) -> TestRequest {
   let mut req = TestRequest {
     http_version: Some(HttpVersion(1,1)),
     method: Some(GET),
+    url: Some(~"/chat"),
     headers: HeaderMap::new()
   };
 
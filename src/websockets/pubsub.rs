@@ -0,0 +1,672 @@
+use core::hashmap::linear::LinearMap;
+use std::arc;
+use std::json;
+use std::json::{Json, Object, String, Number, Boolean, Null};
+use std::sort;
+use http::parser::Parser;
+use websockets::control::ServerControl;
+use websockets::framing::types::Frame;
+use websockets::handler::*;
+use websockets::registry::ConnectionId;
+use websockets::websocket::{WebSocket, Transport, ConnectionCommand, SendShared};
+
+/* Topics are dot-separated names such as "news.sports". In subscription
+   patterns "*" matches exactly one segment and "#" matches any number of
+   segments, including none. */
+pub static PUBSUB_PROTOCOL: &'static str = "dolittle.pubsub";
+
+/* How many topics may retain messages before the oldest ones nobody is
+   subscribed to are forgotten. */
+static MAX_RETAINED_TOPICS: uint = 1024;
+
+#[deriving(Eq)]
+pub enum PubSubRequest {
+  Subscribe(~str),
+  Unsubscribe(~str),
+  Publish(~str, Json),
+  Resume(~str, u64),
+}
+
+/* Every topic numbers its messages 1, 2, 3, ... and, when history is kept,
+   remembers the latest ones so that reconnecting clients can catch up. A
+   topic is only tracked while it retains messages or has subscribers, so
+   without history its numbering starts over once nobody listens. */
+struct TopicHistory {
+  last_seq: u64,
+  messages: ~[ConnectionCommand],
+}
+
+pub struct Broker {
+  subscriptions: LinearMap<ConnectionId,~[~str]>,
+  topics: LinearMap<~str,TopicHistory>,
+  topic_order: ~[~str],
+  history_limit: uint,
+  topic_limit: uint,
+}
+
+/* Speaks the JSON control protocol on connections that negotiated
+   PUBSUB_PROTOCOL. Requests look like
+     {"type": "subscribe", "topic": "news.*"}
+     {"type": "unsubscribe", "topic": "news.*"}
+     {"type": "publish", "topic": "news.sports", "data": ...}
+     {"type": "resume", "topic": "news.sports", "last_seq": 41}
+   and subscribers receive {"type": "message", "topic": ..., "seq": ..., "data": ...}.
+   Resuming subscribes to a single topic and replays what was published after
+   last_seq; "gap" in the reply is true when some of it is no longer kept. */
+pub struct PubSubHandler {
+  control: ServerControl
+}
+
+impl Broker {
+  pub fn new() -> Broker {
+    Broker::with_history(0)
+  }
+
+  /* Keeps up to history_limit messages per topic for replay. */
+  pub fn with_history(history_limit: uint) -> Broker {
+    Broker {
+      subscriptions: LinearMap::new(),
+      topics: LinearMap::new(),
+      topic_order: ~[],
+      history_limit: history_limit,
+      topic_limit: MAX_RETAINED_TOPICS,
+    }
+  }
+
+  pub fn subscribe(&mut self, id: ConnectionId, pattern: &str) {
+    let pattern = str::from_slice(pattern);
+    let mut patterns = match self.subscriptions.find(&id) {
+      Some(patterns) => copy *patterns,
+      None => ~[]
+    };
+
+    if !patterns.contains(&pattern) {
+      patterns.push(pattern);
+      self.subscriptions.insert(id, patterns);
+    }
+  }
+
+  /* Returns false when the connection was not subscribed to the pattern. */
+  pub fn unsubscribe(&mut self, id: ConnectionId, pattern: &str) -> bool {
+    let (remaining, removed) = match self.subscriptions.find(&id) {
+      Some(patterns) => {
+        let remaining = patterns.filtered(|subscribed| !str::eq_slice(*subscribed, pattern));
+        let removed = remaining.len() < patterns.len();
+        (remaining, removed)
+      }
+      None => return false
+    };
+
+    if remaining.is_empty() {
+      self.subscriptions.remove(&id);
+    } else {
+      self.subscriptions.insert(id, remaining);
+    }
+
+    if removed {
+      self.forget_idle_topics();
+    }
+
+    removed
+  }
+
+  pub fn remove_connection(&mut self, id: ConnectionId) {
+    if self.subscriptions.remove(&id) {
+      self.forget_idle_topics();
+    }
+  }
+
+  /* Numbers the message and returns it ready to be queued. */
+  pub fn publish(&mut self, topic: &str, data: &Json) -> ConnectionCommand {
+    let topic = str::from_slice(topic);
+    let mut history = match self.topics.pop(&topic) {
+      Some(history) => history,
+      None => TopicHistory { last_seq: 0, messages: ~[] }
+    };
+
+    history.last_seq += 1;
+    let command = SendShared(arc::ARC(message_frame(topic, history.last_seq, data).compose()));
+
+    if self.history_limit > 0 {
+      history.messages.push(command.clone());
+
+      if history.messages.len() > self.history_limit {
+        history.messages.shift();
+      }
+    }
+
+    if history.messages.is_empty() && !self.has_subscribers(topic) {
+      self.topic_order = self.topic_order.filtered(|known| *known != topic);
+      return command;
+    }
+
+    if !self.topic_order.contains(&topic) {
+      self.topic_order.push(copy topic);
+    }
+
+    self.topics.insert(topic, history);
+    self.limit_topics();
+    command
+  }
+
+  /* Returns the retained messages published after last_seq, and whether any
+     messages after last_seq are no longer retained. A last_seq beyond the
+     topic's latest message (say from before a restart) also counts as a gap. */
+  pub fn replay(&self, topic: &str, last_seq: u64) -> (~[ConnectionCommand], bool) {
+    let history = match self.topics.find(&str::from_slice(topic)) {
+      Some(history) => history,
+      None => return (~[], last_seq > 0)
+    };
+
+    let first_retained = history.last_seq + 1 - (history.messages.len() as u64);
+    let mut missed = ~[];
+
+    for history.messages.eachi |index, command| {
+      if first_retained + (index as u64) > last_seq {
+        missed.push(command.clone());
+      }
+    }
+
+    (missed, last_seq > history.last_seq || first_retained > last_seq + 1)
+  }
+
+  priv fn has_subscribers(&self, topic: &str) -> bool {
+    for self.subscriptions.each_value |patterns| {
+      if vec::any(*patterns, |pattern| topic_matches(*pattern, topic)) {
+        return true;
+      }
+    }
+
+    false
+  }
+
+  /* Topics without retained messages only carry their numbering, which
+     nobody needs once no subscriber is left. */
+  priv fn forget_idle_topics(&mut self) {
+    let mut idle = ~[];
+
+    for self.topics.each |&(topic, history)| {
+      if history.messages.is_empty() && !self.has_subscribers(*topic) {
+        idle.push(copy *topic);
+      }
+    }
+
+    for idle.each |topic| {
+      self.forget_topic(*topic);
+    }
+  }
+
+  /* Forgets the oldest topics nobody is subscribed to until at most
+     topic_limit are tracked. Subscribed topics are always kept. */
+  priv fn limit_topics(&mut self) {
+    if self.topics.len() <= self.topic_limit {
+      return;
+    }
+
+    let mut excess = self.topics.len() - self.topic_limit;
+    let mut forgotten = ~[];
+
+    for self.topic_order.each |topic| {
+      if excess == 0 {
+        break;
+      }
+
+      if !self.has_subscribers(*topic) {
+        forgotten.push(copy *topic);
+        excess -= 1;
+      }
+    }
+
+    for forgotten.each |topic| {
+      self.forget_topic(*topic);
+    }
+  }
+
+  priv fn forget_topic(&mut self, topic: &str) {
+    self.topics.remove(&str::from_slice(topic));
+    self.topic_order = self.topic_order.filtered(|known| !str::eq_slice(*known, topic));
+  }
+
+  pub fn subscribers(&self, topic: &str) -> ~[ConnectionId] {
+    let mut ids = ~[];
+
+    for self.subscriptions.each |&(id, patterns)| {
+      if vec::any(*patterns, |pattern| topic_matches(*pattern, topic)) {
+        ids.push(*id);
+      }
+    }
+
+    sort::quick_sort(ids, |a, b| *a <= *b);
+    ids
+  }
+}
+
+impl Clone for PubSubHandler {
+  fn clone(&self) -> PubSubHandler {
+    PubSubHandler { control: self.control.clone() }
+  }
+}
+
+impl PubSubHandler {
+  pub fn new(control: ServerControl) -> PubSubHandler {
+    PubSubHandler { control: control }
+  }
+
+  priv fn handle_request(&self, id: ConnectionId, text: &str) -> Frame {
+    let control = self.control.for_connection(id);
+
+    match parse_request(text) {
+      Ok(Subscribe(pattern)) => {
+        if control.subscribe(id, pattern) {
+          reply_frame("subscribed", pattern, ~[])
+        } else {
+          error_frame("Connection is not registered")
+        }
+      }
+
+      Ok(Unsubscribe(pattern)) => {
+        if control.unsubscribe(id, pattern) {
+          reply_frame("unsubscribed", pattern, ~[])
+        } else {
+          error_frame(~"Not subscribed to " + pattern)
+        }
+      }
+
+      Ok(Publish(topic, data)) => {
+        let receivers = control.publish(topic, &data);
+        reply_frame("published", topic, ~[(~"receivers", Number(receivers as float))])
+      }
+
+      Ok(Resume(topic, last_seq)) => {
+        match control.resume(id, topic, last_seq) {
+          Some((replayed, gap)) => {
+            reply_frame("resumed", topic, ~[(~"replayed", Number(replayed as float)),
+                                             (~"gap", Boolean(gap))])
+          }
+          None => error_frame("Connection is not registered")
+        }
+      }
+
+      Err(error) => error_frame(error)
+    }
+  }
+}
+
+impl Handler for PubSubHandler {
+  fn on_open<T: Transport>(&mut self, _: &WebSocket<T>, _: &Parser) {}
+
+  fn on_text<T: Transport>(&mut self, socket: &WebSocket<T>, text: &str) {
+    let reply = match socket.connection_id() {
+      Some(id) => self.handle_request(id, text),
+      None => error_frame("Connection is not registered")
+    };
+
+    socket.send_frame(&reply);
+  }
+
+  fn on_binary<T: Transport>(&mut self, socket: &WebSocket<T>, _: &[u8]) {
+    socket.send_frame(&error_frame("Binary messages are not supported"));
+  }
+
+  fn on_ping<T: Transport>(&mut self, _: &WebSocket<T>, _: &[u8]) {}
+
+  fn on_close<T: Transport>(&mut self, _: &WebSocket<T>, _: u16, _: &str) {}
+
+  fn on_error<T: Transport>(&mut self, _: &WebSocket<T>, _: &ConnectionError) {}
+}
+
+pub fn parse_request(text: &str) -> Result<PubSubRequest,~str> {
+  let fields = match json::from_str(text) {
+    Ok(Object(fields)) => fields,
+    Ok(_) => return Err(~"Expected a JSON object"),
+    Err(error) => return Err(~"Invalid JSON: " + copy *error.msg)
+  };
+
+  let kind = match string_field(fields, "type") {
+    Some(kind) => kind,
+    None => return Err(~"Missing request type")
+  };
+
+  let topic = match string_field(fields, "topic") {
+    Some(topic) => topic,
+    None => return Err(~"Missing topic")
+  };
+
+  if kind == ~"subscribe" || kind == ~"unsubscribe" {
+    if !valid_pattern(topic) {
+      Err(~"Invalid topic pattern: " + topic)
+    } else if kind == ~"subscribe" {
+      Ok(Subscribe(topic))
+    } else {
+      Ok(Unsubscribe(topic))
+    }
+  } else if kind == ~"publish" {
+    if !valid_topic(topic) {
+      return Err(~"Invalid topic: " + topic);
+    }
+
+    match fields.find(&~"data") {
+      Some(data) => Ok(Publish(topic, copy *data)),
+      None => Ok(Publish(topic, Null))
+    }
+  } else if kind == ~"resume" {
+    if !valid_topic(topic) {
+      return Err(~"Invalid topic: " + topic);
+    }
+
+    match fields.find(&~"last_seq") {
+      Some(&Number(seq)) if seq >= 0f && seq == (seq as u64) as float => {
+        Ok(Resume(topic, seq as u64))
+      }
+      _ => Err(~"Missing or invalid last_seq")
+    }
+  } else {
+    Err(~"Unknown request type: " + kind)
+  }
+}
+
+pub fn valid_pattern(pattern: &str) -> bool {
+  if pattern.is_empty() {
+    return false;
+  }
+
+  for str::split_char(pattern, '.').each |segment| {
+    if segment.is_empty() {
+      return false;
+    }
+
+    if *segment != ~"*" && *segment != ~"#" &&
+       (str::contains_char(*segment, '*') || str::contains_char(*segment, '#')) {
+      return false;
+    }
+  }
+
+  true
+}
+
+pub fn valid_topic(topic: &str) -> bool {
+  valid_pattern(topic) && !str::contains_char(topic, '*') && !str::contains_char(topic, '#')
+}
+
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+  segments_match(str::split_char(pattern, '.'), str::split_char(topic, '.'))
+}
+
+fn segments_match(pattern: &[~str], topic: &[~str]) -> bool {
+  if pattern.is_empty() {
+    return topic.is_empty();
+  }
+
+  if pattern[0] == ~"#" {
+    for uint::range(0, topic.len() + 1) |skipped| {
+      if segments_match(pattern.tail(), topic.slice(skipped, topic.len())) {
+        return true;
+      }
+    }
+
+    return false;
+  }
+
+  !topic.is_empty() &&
+    (pattern[0] == ~"*" || pattern[0] == topic[0]) &&
+    segments_match(pattern.tail(), topic.tail())
+}
+
+pub fn message_frame(topic: &str, seq: u64, data: &Json) -> Frame {
+  reply_frame("message", topic, ~[(~"seq", Number(seq as float)), (~"data", copy *data)])
+}
+
+fn reply_frame(kind: &str, topic: &str, extra: ~[(~str,Json)]) -> Frame {
+  let mut fields = ~[(~"type", String(str::from_slice(kind))),
+                     (~"topic", String(str::from_slice(topic)))];
+  fields.push_all_move(extra);
+  json_frame(fields)
+}
+
+fn error_frame(error: &str) -> Frame {
+  json_frame(~[(~"type", String(~"error")), (~"error", String(str::from_slice(error)))])
+}
+
+pub fn json_frame(fields: ~[(~str,Json)]) -> Frame {
+  let mut object = ~LinearMap::new();
+
+  do vec::consume(fields) |_, (name, value)| {
+    object.insert(name, value);
+  }
+
+  Frame::text(json::to_str(&Object(object)))
+}
+
+fn string_field(fields: &json::Object, name: &str) -> Option<~str> {
+  match fields.find(&str::from_slice(name)) {
+    Some(&String(ref value)) => Some(copy *value),
+    _ => None
+  }
+}
+
+/* Decodes an unmasked text frame with a short JSON payload. */
+#[cfg(test)]
+pub fn frame_json(bytes: ~[u8]) -> Json {
+  assert!(bytes.len() >= 2 && bytes[1] < 126);
+  json::from_str(str::from_bytes(bytes.tailn(2))).get()
+}
+
+#[test]
+fn topic_patterns() {
+  assert!(topic_matches("news.sports", "news.sports"));
+  assert!(!topic_matches("news.sports", "news.weather"));
+  assert!(topic_matches("news.*", "news.sports"));
+  assert!(!topic_matches("news.*", "news"));
+  assert!(!topic_matches("news.*", "news.sports.football"));
+  assert!(topic_matches("news.#", "news"));
+  assert!(topic_matches("news.#", "news.sports.football"));
+  assert!(topic_matches("#.football", "news.sports.football"));
+  assert!(topic_matches("*.sports.#", "news.sports"));
+  assert!(!topic_matches("*.sports.#", "sports"));
+
+  assert!(valid_pattern("news.*.#"));
+  assert!(!valid_pattern("news..sports"));
+  assert!(!valid_pattern("news.sp*rts"));
+  assert!(!valid_pattern(""));
+  assert!(valid_topic("news.sports"));
+  assert!(!valid_topic("news.*"));
+}
+
+#[test]
+fn parse_pubsub_requests() {
+  assert!(parse_request("{\"type\": \"subscribe\", \"topic\": \"news.*\"}") ==
+          Ok(Subscribe(~"news.*")));
+  assert!(parse_request("{\"type\": \"unsubscribe\", \"topic\": \"news.#\"}") ==
+          Ok(Unsubscribe(~"news.#")));
+  assert!(parse_request("{\"type\": \"publish\", \"topic\": \"news.sports\", \"data\": \"goal\"}") ==
+          Ok(Publish(~"news.sports", String(~"goal"))));
+  assert!(parse_request("{\"type\": \"publish\", \"topic\": \"news\"}") ==
+          Ok(Publish(~"news", Null)));
+  assert!(parse_request("{\"type\": \"resume\", \"topic\": \"news\", \"last_seq\": 41}") ==
+          Ok(Resume(~"news", 41)));
+
+  assert!(parse_request("{\"type\": \"publish\", \"topic\": \"news.*\"}").is_err());
+  assert!(parse_request("{\"type\": \"subscribe\", \"topic\": \"news..x\"}").is_err());
+  assert!(parse_request("{\"type\": \"resume\", \"topic\": \"news.#\", \"last_seq\": 1}").is_err());
+  assert!(parse_request("{\"type\": \"resume\", \"topic\": \"news\", \"last_seq\": 1.5}").is_err());
+  assert!(parse_request("{\"type\": \"resume\", \"topic\": \"news\"}").is_err());
+  assert!(parse_request("{\"type\": \"dance\", \"topic\": \"news\"}").is_err());
+  assert!(parse_request("{\"topic\": \"news\"}").is_err());
+  assert!(parse_request("[1, 2]").is_err());
+  assert!(parse_request("{").is_err());
+}
+
+#[test]
+fn broker_routes_topics_to_subscribers() {
+  let mut broker = Broker::new();
+  broker.subscribe(2, "news.#");
+  broker.subscribe(1, "news.sports");
+  broker.subscribe(1, "news.sports");
+  broker.subscribe(3, "weather.*");
+
+  assert!(broker.subscribers("news.sports") == ~[1, 2]);
+  assert!(broker.subscribers("news") == ~[2]);
+  assert!(broker.subscribers("weather.today") == ~[3]);
+
+  assert!(broker.unsubscribe(1, "news.sports"));
+  assert!(!broker.unsubscribe(1, "news.sports"));
+  broker.remove_connection(2);
+
+  assert!(broker.subscribers("news.sports").is_empty());
+}
+
+#[test]
+fn broker_numbers_and_replays_messages() {
+  let mut broker = Broker::with_history(2);
+  let first = broker.publish("news", &Number(1f));
+  let second = broker.publish("news", &Number(2f));
+  let third = broker.publish("news", &Number(3f));
+
+  match first {
+    SendShared(bytes) => assert!(frame_json(copy *arc::get(&bytes)) ==
+                                 frame_json(message_frame("news", 1, &Number(1f)).compose())),
+    _ => fail!(~"Expected a shared frame")
+  }
+
+  assert!(broker.replay("news", 3) == (~[], false));
+  assert!(broker.replay("news", 2) == (~[third.clone()], false));
+  assert!(broker.replay("news", 1) == (~[second.clone(), third.clone()], false));
+  assert!(broker.replay("news", 0) == (~[second, third], true));
+  assert!(broker.replay("news", 7) == (~[], true));
+  assert!(broker.replay("weather", 0) == (~[], false));
+
+  let mut forgetful = Broker::new();
+  forgetful.publish("news", &Null);
+
+  assert!(forgetful.topics.is_empty());
+  assert!(forgetful.replay("news", 1) == (~[], true));
+  assert!(forgetful.replay("news", 0) == (~[], false));
+
+  forgetful.subscribe(1, "news.#");
+  forgetful.publish("news", &Null);
+  forgetful.publish("news", &Null);
+
+  assert!(forgetful.replay("news", 2) == (~[], false));
+  assert!(forgetful.replay("news", 1) == (~[], true));
+
+  forgetful.remove_connection(1);
+  assert!(forgetful.topics.is_empty());
+  assert!(forgetful.topic_order.is_empty());
+}
+
+#[test]
+fn broker_forgets_oldest_unsubscribed_topics() {
+  let mut broker = Broker::with_history(1);
+  broker.topic_limit = 2;
+  broker.subscribe(1, "sports");
+
+  let sports = broker.publish("sports", &Null);
+  broker.publish("news", &Null);
+  let weather = broker.publish("weather", &Null);
+
+  assert!(broker.topic_order == ~[~"sports", ~"weather"]);
+  assert!(broker.replay("news", 0) == (~[], false));
+  assert!(broker.replay("weather", 0) == (~[weather], false));
+
+  broker.subscribe(1, "weather");
+  broker.publish("traffic", &Null);
+
+  assert!(broker.topic_order == ~[~"sports", ~"weather"]);
+  assert!(broker.replay("sports", 0) == (~[sports], false));
+  assert!(broker.replay("traffic", 1) == (~[], true));
+}
+
+#[test]
+fn message_frame_is_json() {
+  let frame = message_frame("news.sports", 7, &String(~"goal"));
+  let text = str::from_bytes(frame.unmasked_payload().to_bytes());
+
+  match json::from_str(text) {
+    Ok(Object(fields)) => {
+      assert!(string_field(fields, "type") == Some(~"message"));
+      assert!(string_field(fields, "topic") == Some(~"news.sports"));
+      assert!(fields.find(&~"seq") == Some(&Number(7f)));
+      assert!(string_field(fields, "data") == Some(~"goal"));
+    }
+    _ => fail!(~"Expected a JSON object")
+  }
+}
+
+#[test]
+fn pubsub_handler_answers_requests() {
+  use core::comm::stream;
+  use http::auth::AnonymousAuthenticator;
+  use websockets::control::coordinate;
+  use websockets::outbound::{OutboundQueue, DROP_NEWEST};
+  use websockets::registry::{ConnectionEntry, TrafficCounter};
+  use websockets::websocket::{accept_connection, fake_connection, HandshakeLimits};
+
+  let (events_po, events_ch) = stream();
+  let queue = OutboundQueue::new(16, DROP_NEWEST);
+  let control = ServerControl::new(events_ch);
+
+  do task::spawn {
+    coordinate(&events_po, 0, 10000, 8);
+  }
+
+  let (server_socket, client_socket) = fake_connection();
+  client_socket.write("GET /pubsub HTTP/1.1\r\n\
+                       Host: example.com\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n".to_bytes());
+
+  let (websocket, _, _) =
+    accept_connection(server_socket, &HandshakeLimits::default(), &AnonymousAuthenticator).unwrap();
+  let mut websocket = websocket;
+  let mut handler = PubSubHandler::new(control.clone());
+  let reply_type = || {
+    let reply = frame_json(client_socket.read().get());
+
+    match reply {
+      Object(fields) => string_field(fields, "type").get(),
+      _ => fail!(~"Expected a JSON object")
+    }
+  };
+
+  assert!(str::starts_with(str::from_bytes(client_socket.read().get()), "HTTP/1.1 101"));
+
+  handler.on_text(&websocket, "{\"type\": \"subscribe\", \"topic\": \"news\"}");
+  assert!(reply_type() == ~"error");
+
+  let entry = ConnectionEntry::new("127.0.0.1", "/pubsub", None, TrafficCounter::new(), queue.clone());
+  let id = control.connection_opened(entry).get();
+  websocket.set_connection_id(id);
+
+  handler.on_text(&websocket, "{\"type\": \"subscribe\", \"topic\": \"news\"}");
+  assert!(reply_type() == ~"subscribed");
+
+  handler.on_text(&websocket, "{\"type\": \"publish\", \"topic\": \"news\", \"data\": 1}");
+  assert!(reply_type() == ~"published");
+
+  handler.on_text(&websocket, "{\"type\": \"resume\", \"topic\": \"news\", \"last_seq\": 0}");
+  assert!(reply_type() == ~"resumed");
+
+  handler.on_text(&websocket, "{\"type\": \"unsubscribe\", \"topic\": \"news\"}");
+  assert!(reply_type() == ~"unsubscribed");
+
+  handler.on_text(&websocket, "{\"type\": \"unsubscribe\", \"topic\": \"news\"}");
+  assert!(reply_type() == ~"error");
+
+  handler.on_binary(&websocket, [1, 2, 3]);
+  assert!(reply_type() == ~"error");
+
+  let published = queue.pop();
+  assert!(queue.pop() == published);
+  assert!(queue.pop() == None);
+
+  match published {
+    Some(SendShared(bytes)) => assert!(frame_json(copy *arc::get(&bytes)) ==
+                                 frame_json(message_frame("news", 1, &Number(1f)).compose())),
+    _ => fail!(~"Expected the published message")
+  }
+
+  control.connection_closed(id);
+  control.shutdown();
+}
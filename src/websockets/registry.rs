@@ -0,0 +1,339 @@
+use core::hashmap::linear::LinearMap;
+use std::arc;
+use std::sort;
+use std::time;
+use websockets::outbound::{OutboundQueue, QueueStats};
+use websockets::websocket::{ConnectionCommand, Transport, Error};
+
+pub type ConnectionId = uint;
+
+#[deriving(Eq,Clone)]
+pub struct Traffic {
+  bytes_read: u64,
+  bytes_written: u64,
+}
+
+/* Shared between a connection's transport, which does the counting, and the
+   registry, which only ever reads the totals. */
+pub struct TrafficCounter {
+  traffic: arc::RWARC<Traffic>
+}
+
+pub struct CountingTransport<T> {
+  transport: T,
+  counter: TrafficCounter,
+}
+
+/* source is the peer's IP address for TCP connections. Unix and inherited
+   sockets have no peer address, so theirs names the listener instead. */
+#[deriving(Eq,Clone)]
+pub struct ConnectionInfo {
+  id: ConnectionId,
+  source: ~str,
+  path: ~str,
+  subprotocol: Option<~str>,
+  connected_at: i64,
+  traffic: Traffic,
+  queue: QueueStats,
+}
+
+pub struct ConnectionEntry {
+  source: ~str,
+  path: ~str,
+  subprotocol: Option<~str>,
+  connected_at: i64,
+  traffic: TrafficCounter,
+  queue: OutboundQueue,
+}
+
+pub enum Recipients {
+  AllConnections,
+  SomeConnections(~[ConnectionId]),
+}
+
+pub struct RecipientQueue {
+  id: ConnectionId,
+  queue: OutboundQueue,
+}
+
+pub struct Registry {
+  connections: LinearMap<ConnectionId,ConnectionEntry>,
+  next_id: ConnectionId,
+}
+
+impl Clone for TrafficCounter {
+  fn clone(&self) -> TrafficCounter {
+    TrafficCounter { traffic: self.traffic.clone() }
+  }
+}
+
+impl TrafficCounter {
+  pub fn new() -> TrafficCounter {
+    TrafficCounter { traffic: arc::RWARC(Traffic { bytes_read: 0, bytes_written: 0 }) }
+  }
+
+  pub fn count_read(&self, count: uint) {
+    do self.traffic.write |traffic| { traffic.bytes_read += count as u64; }
+  }
+
+  pub fn count_written(&self, count: uint) {
+    do self.traffic.write |traffic| { traffic.bytes_written += count as u64; }
+  }
+
+  pub fn get(&self) -> Traffic {
+    do self.traffic.read |traffic| { traffic.clone() }
+  }
+}
+
+impl<T: Transport> CountingTransport<T> {
+  pub fn new(transport: T, counter: TrafficCounter) -> CountingTransport<T> {
+    CountingTransport { transport: transport, counter: counter }
+  }
+}
+
+impl<T: Transport> Transport for CountingTransport<T> {
+  fn read(&self) -> Result<~[u8],Error> {
+    let result = self.transport.read();
+
+    match result {
+      Ok(ref bytes) => self.counter.count_read(bytes.len()),
+      Err(_) => {}
+    }
+
+    result
+  }
+
+  fn read_timeout(&self, timeout_ms: uint) -> Result<Option<~[u8]>,Error> {
+    let result = self.transport.read_timeout(timeout_ms);
+
+    match result {
+      Ok(Some(ref bytes)) => self.counter.count_read(bytes.len()),
+      _ => {}
+    }
+
+    result
+  }
+
+  fn write(&self, bytes: ~[u8]) {
+    self.counter.count_written(bytes.len());
+    self.transport.write(bytes);
+  }
+}
+
+impl ConnectionEntry {
+  pub fn new(source: &str,
+             path: &str,
+             subprotocol: Option<~str>,
+             traffic: TrafficCounter,
+             queue: OutboundQueue) -> ConnectionEntry {
+    ConnectionEntry {
+      source: str::from_slice(source),
+      path: str::from_slice(path),
+      subprotocol: subprotocol,
+      connected_at: time::get_time().sec,
+      traffic: traffic,
+      queue: queue,
+    }
+  }
+
+  fn info(&self, id: ConnectionId) -> ConnectionInfo {
+    ConnectionInfo {
+      id: id,
+      source: self.source.clone(),
+      path: self.path.clone(),
+      subprotocol: self.subprotocol.clone(),
+      connected_at: self.connected_at,
+      traffic: self.traffic.get(),
+      queue: self.queue.stats(),
+    }
+  }
+}
+
+impl Registry {
+  pub fn new() -> Registry {
+    Registry { connections: LinearMap::new(), next_id: 0 }
+  }
+
+  pub fn register(&mut self, entry: ConnectionEntry) -> ConnectionId {
+    let id = self.next_id;
+    self.next_id += 1;
+    self.connections.insert(id, entry);
+    id
+  }
+
+  pub fn unregister(&mut self, id: ConnectionId) -> bool {
+    self.connections.remove(&id)
+  }
+
+  pub fn len(&self) -> uint {
+    self.connections.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.connections.is_empty()
+  }
+
+  pub fn contains(&self, id: ConnectionId) -> bool {
+    self.connections.contains_key(&id)
+  }
+
+  pub fn lookup(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+    self.connections.find(&id).map(|entry| entry.info(id))
+  }
+
+  pub fn each(&self, f: &fn(&ConnectionInfo) -> bool) {
+    for self.connections.each |&(id, entry)| {
+      if !f(&entry.info(*id)) {
+        return;
+      }
+    }
+  }
+
+  /* A snapshot of every registered connection, oldest first. */
+  pub fn list(&self) -> ~[ConnectionInfo] {
+    let mut infos = ~[];
+
+    for self.each |info| {
+      infos.push(info.clone());
+    }
+
+    sort::quick_sort(infos, |a, b| a.id <= b.id);
+    infos
+  }
+
+  /* Queues a control command (close or abort), which is never subject to
+     the overflow policy. Returns false when there is no such connection. */
+  pub fn send_control(&self, id: ConnectionId, command: ConnectionCommand) -> bool {
+    match self.connections.find(&id) {
+      Some(entry) => entry.queue.push_control(command),
+      None => false
+    }
+  }
+
+  pub fn send_control_all(&self, command: &ConnectionCommand) {
+    for self.connections.each_value |entry| {
+      entry.queue.push_control(command.clone());
+    }
+  }
+
+  /* Producers push to these themselves, so a full queue only ever holds up
+     the task that is sending. Unknown and duplicate IDs are skipped. */
+  pub fn queues(&self, recipients: &Recipients) -> ~[RecipientQueue] {
+    let mut queues = ~[];
+
+    match *recipients {
+      AllConnections => {
+        for self.connections.each |&(id, entry)| {
+          queues.push(RecipientQueue { id: *id, queue: entry.queue.clone() });
+        }
+      }
+
+      SomeConnections(ref ids) => {
+        for ids.eachi |index, id| {
+          if ids.slice(0, index).contains(id) {
+            loop;
+          }
+
+          match self.connections.find(id) {
+            Some(entry) => queues.push(RecipientQueue { id: *id, queue: entry.queue.clone() }),
+            None => {}
+          }
+        }
+      }
+    }
+
+    queues
+  }
+}
+
+#[cfg(test)]
+fn test_entry(path: &str) -> (ConnectionEntry, OutboundQueue) {
+  let queue = OutboundQueue::new(16, websockets::outbound::DROP_NEWEST);
+  (ConnectionEntry::new("127.0.0.1", path, None, TrafficCounter::new(), queue.clone()), queue)
+}
+
+#[test]
+fn registry_assigns_ids_and_describes_connections() {
+  let mut registry = Registry::new();
+  let (first, _first_queue) = test_entry("/chat");
+  let (second, second_queue) = test_entry("/feed");
+
+  assert!(registry.register(first) == 0);
+  assert!(registry.register(second) == 1);
+  assert!(registry.len() == 2);
+
+  second_queue.push(websockets::websocket::SendBytes(~[1]));
+
+  let info = registry.lookup(1).get();
+  assert!(info.id == 1);
+  assert!(info.source == ~"127.0.0.1");
+  assert!(info.path == ~"/feed");
+  assert!(info.subprotocol == None);
+  assert!(info.traffic == Traffic { bytes_read: 0, bytes_written: 0 });
+  assert!(info.queue.depth == 1);
+
+  assert!(registry.list().map(|info| info.path.clone()) == ~[~"/chat", ~"/feed"]);
+
+  assert!(registry.unregister(0));
+  assert!(!registry.unregister(0));
+  assert!(registry.lookup(0).is_none());
+  assert!(registry.len() == 1);
+}
+
+#[test]
+fn registry_sends_control_commands() {
+  use websockets::websocket::{Disconnect, Abort};
+
+  let mut registry = Registry::new();
+  let (first, first_queue) = test_entry("/chat");
+  let (second, second_queue) = test_entry("/chat");
+  let first_id = registry.register(first);
+  registry.register(second);
+
+  assert!(registry.send_control(first_id, Disconnect(1001, ~"bye")));
+  assert!(!registry.send_control(7, Abort));
+  registry.send_control_all(&Abort);
+
+  assert!(first_queue.pop() == Some(Disconnect(1001, ~"bye")));
+  assert!(first_queue.pop() == Some(Abort));
+  assert!(second_queue.pop() == Some(Abort));
+  assert!(second_queue.pop() == None);
+}
+
+#[test]
+fn registry_finds_queues_for_recipients() {
+  use websockets::websocket::SendBytes;
+
+  let mut registry = Registry::new();
+  let (first, first_queue) = test_entry("/chat");
+  let (second, _second_queue) = test_entry("/chat");
+  let (third, third_queue) = test_entry("/chat");
+  let first_id = registry.register(first);
+  registry.register(second);
+  let third_id = registry.register(third);
+
+  let queues = registry.queues(&SomeConnections(~[third_id, first_id, third_id, 42]));
+  assert!(queues.map(|recipient| recipient.id) == ~[third_id, first_id]);
+  assert!(registry.queues(&AllConnections).len() == 3);
+
+  for queues.each |recipient| {
+    recipient.queue.push(SendBytes(~[1]));
+  }
+
+  assert!(first_queue.pop() == Some(SendBytes(~[1])));
+  assert!(third_queue.pop() == Some(SendBytes(~[1])));
+  assert!(third_queue.pop() == None);
+}
+
+#[test]
+fn counting_transport_reports_traffic() {
+  let (client, server) = websockets::websocket::fake_connection();
+  let counter = TrafficCounter::new();
+  let transport = CountingTransport::new(server, counter.clone());
+
+  client.write(~[1, 2, 3]);
+  transport.read();
+  transport.write(~[4, 5]);
+
+  assert!(counter.get() == Traffic { bytes_read: 3, bytes_written: 2 });
+}
@@ -0,0 +1,152 @@
+use core::hashmap::linear::LinearMap;
+use std::json::{String, Number};
+use std::sort;
+use websockets::framing::types::Frame;
+use websockets::pubsub::json_frame;
+use websockets::registry::ConnectionId;
+
+/* Named groups of connections. The other members of a room are told when
+   someone joins, leaves or disconnects with a text frame like
+     {"type": "presence", "room": "lobby", "event": "join", "connection": 7} */
+#[deriving(Eq)]
+pub enum PresenceEvent {
+  MEMBER_JOINED,
+  MEMBER_LEFT,
+  MEMBER_DISCONNECTED,
+}
+
+pub struct Rooms {
+  memberships: LinearMap<~str,~[ConnectionId]>
+}
+
+impl PresenceEvent {
+  pub fn name(&self) -> &'static str {
+    match *self {
+      MEMBER_JOINED => "join",
+      MEMBER_LEFT => "leave",
+      MEMBER_DISCONNECTED => "disconnect"
+    }
+  }
+}
+
+impl Rooms {
+  pub fn new() -> Rooms {
+    Rooms { memberships: LinearMap::new() }
+  }
+
+  /* Returns false when the connection is already a member. */
+  pub fn join(&mut self, id: ConnectionId, room: &str) -> bool {
+    if room.is_empty() {
+      return false;
+    }
+
+    let room = str::from_slice(room);
+    let mut members = match self.memberships.find(&room) {
+      Some(members) => copy *members,
+      None => ~[]
+    };
+
+    if members.contains(&id) {
+      return false;
+    }
+
+    members.push(id);
+    self.memberships.insert(room, members);
+    true
+  }
+
+  /* Returns false when the connection was not a member. Empty rooms are
+     forgotten. */
+  pub fn leave(&mut self, id: ConnectionId, room: &str) -> bool {
+    let room = str::from_slice(room);
+    let remaining = match self.memberships.find(&room) {
+      Some(members) if members.contains(&id) => members.filtered(|member| *member != id),
+      _ => return false
+    };
+
+    if remaining.is_empty() {
+      self.memberships.remove(&room);
+    } else {
+      self.memberships.insert(room, remaining);
+    }
+
+    true
+  }
+
+  /* Returns the rooms the connection was in. */
+  pub fn remove_connection(&mut self, id: ConnectionId) -> ~[~str] {
+    let mut left = ~[];
+
+    for self.memberships.each |&(room, members)| {
+      if members.contains(&id) {
+        left.push(copy *room);
+      }
+    }
+
+    for left.each |room| {
+      self.leave(id, *room);
+    }
+
+    sort::quick_sort(left, |a, b| *a <= *b);
+    left
+  }
+
+  pub fn members(&self, room: &str) -> ~[ConnectionId] {
+    let mut ids = match self.memberships.find(&str::from_slice(room)) {
+      Some(members) => copy *members,
+      None => ~[]
+    };
+
+    sort::quick_sort(ids, |a, b| *a <= *b);
+    ids
+  }
+}
+
+pub fn presence_frame(room: &str, event: PresenceEvent, id: ConnectionId) -> Frame {
+  json_frame(~[(~"type", String(~"presence")),
+               (~"room", String(str::from_slice(room))),
+               (~"event", String(str::from_slice(event.name()))),
+               (~"connection", Number(id as float))])
+}
+
+#[test]
+fn rooms_track_members() {
+  let mut rooms = Rooms::new();
+
+  assert!(rooms.join(3, "lobby"));
+  assert!(rooms.join(1, "lobby"));
+  assert!(!rooms.join(1, "lobby"));
+  assert!(rooms.join(1, "games"));
+  assert!(!rooms.join(1, ""));
+
+  assert!(rooms.members("lobby") == ~[1, 3]);
+  assert!(rooms.members("games") == ~[1]);
+  assert!(rooms.members("kitchen").is_empty());
+
+  assert!(rooms.leave(3, "lobby"));
+  assert!(!rooms.leave(3, "lobby"));
+  assert!(!rooms.leave(3, "kitchen"));
+  assert!(rooms.members("lobby") == ~[1]);
+
+  assert!(rooms.remove_connection(1) == ~[~"games", ~"lobby"]);
+  assert!(rooms.remove_connection(1).is_empty());
+  assert!(rooms.memberships.is_empty());
+}
+
+#[test]
+fn presence_frame_is_json() {
+  use std::json;
+  use std::json::Object;
+
+  let frame = presence_frame("lobby", MEMBER_LEFT, 7);
+
+  match json::from_str(str::from_bytes(frame.unmasked_payload().to_bytes())) {
+    Ok(Object(fields)) => {
+      assert!(fields.find(&~"type") == Some(&String(~"presence")));
+      assert!(fields.find(&~"room") == Some(&String(~"lobby")));
+      assert!(fields.find(&~"event") == Some(&String(~"leave")));
+      assert!(fields.find(&~"connection") == Some(&Number(7f)));
+    }
+    _ => fail!(~"Expected a JSON object")
+  }
+}
@@ -0,0 +1,405 @@
+use std::*;
+use core;
+use core::libc::{c_int, c_void};
+use http::auth::{Authenticator, AnonymousAuthenticator};
+use http::query::url_path;
+use http::request::Request;
+use signals::dolittle_watch_shutdown_signals;
+use unix_socket::{read, EINTR};
+use websockets::config::*;
+use websockets::control::*;
+use websockets::framing::types::CLOSE_GOING_AWAY;
+use websockets::handler::Handler;
+use websockets::outbound::{OutboundQueue, OverflowPolicy};
+use websockets::registry::*;
+use websockets::tls::*;
+use websockets::unix::*;
+use websockets::websocket::*;
+
+static LISTENER_POLL_MS: uint = 200;
+
+pub struct Server {
+  events: Port<ServerEvent>,
+  control: ServerControl,
+}
+
+struct ConnectionSettings<A> {
+  limits: HandshakeLimits,
+  authenticator: arc::ARC<A>,
+  subprotocols: ~[~str],
+  queue_limit: uint,
+  queue_policy: OverflowPolicy,
+  tls: Option<arc::ARC<TlsContext>>,
+  control: ServerControl,
+}
+
+impl<A: Const+Owned> Clone for ConnectionSettings<A> {
+  fn clone(&self) -> ConnectionSettings<A> {
+    ConnectionSettings {
+      limits: self.limits.clone(),
+      authenticator: arc::clone(&self.authenticator),
+      subprotocols: copy self.subprotocols,
+      queue_limit: self.queue_limit,
+      queue_policy: self.queue_policy,
+      tls: self.tls.map(|context| arc::clone(context)),
+      control: self.control.clone(),
+    }
+  }
+}
+
+pub fn run_server<H: Handler+Clone+Owned>(port: uint, handler: H) {
+  run_server_with_config(&ServerConfig::listening_on(port), handler);
+}
+
+pub fn run_server_with_config<H: Handler+Clone+Owned>(config: &ServerConfig, handler: H) {
+  Server::new().run_until_signalled(config, AnonymousAuthenticator, handler);
+}
+
+impl Server {
+  pub fn new() -> Server {
+    let (events_po, events_ch) = core::comm::stream();
+    Server { events: events_po, control: ServerControl::new(events_ch) }
+  }
+
+  pub fn control(&self) -> ServerControl {
+    self.control.clone()
+  }
+
+  /* The authenticator is shared by every listener and decides who may
+     complete the websocket handshake. */
+  pub fn run<H: Handler+Clone+Owned, A: Authenticator+Const+Owned>(&self,
+                                                                    config: &ServerConfig,
+                                                                    authenticator: A,
+                                                                    handler: H) {
+    let authenticator = arc::ARC(authenticator);
+
+    for config.listeners.each |listener| {
+      let listener = listener.clone();
+      let listener_authenticator = arc::clone(&authenticator);
+      let listener_handler = handler.clone();
+      let control = self.control.clone();
+
+      do task::spawn_sched(task::SingleThreaded) {
+        control.listener_stopped(listen(&listener, listener_authenticator,
+                                        listener_handler, &control));
+      }
+    }
+
+    coordinate(&self.events,
+               config.listeners.len(),
+               config.shutdown_timeout_ms,
+               config.topic_history);
+  }
+
+  pub fn run_until_signalled<H: Handler+Clone+Owned,
+                             A: Authenticator+Const+Owned>(&self,
+                                                           config: &ServerConfig,
+                                                           authenticator: A,
+                                                           handler: H) {
+    let (stop_po, stop_ch) = core::comm::stream();
+    let control = self.control.clone();
+
+    do task::spawn_sched(task::SingleThreaded) {
+      watch_shutdown_signals(&control, &stop_po);
+    }
+
+    self.run(config, authenticator, handler);
+    stop_ch.send(());
+  }
+}
+
+fn watch_shutdown_signals(control: &ServerControl, stop: &Port<()>) {
+  let fd = unsafe { dolittle_watch_shutdown_signals() };
+
+  if fd < 0 {
+    error!("Could not watch for shutdown signals: %s", os::last_os_error());
+    return;
+  }
+
+  while !stop.peek() {
+    match wait_readable(fd, LISTENER_POLL_MS) {
+      Ok(true) => {
+        let mut signal = 0u8;
+        let count = unsafe { read(fd, ptr::to_mut_unsafe_ptr(&mut signal) as *c_void, 1) };
+
+        if count == 1 {
+          info!("Received signal %u", signal as uint);
+          control.shutdown();
+        } else if count == 0 {
+          error!("Stopped watching for shutdown signals: the signal pipe was closed");
+          return;
+        } else if os::errno() != EINTR {
+          error!("Stopped watching for shutdown signals: %s", os::last_os_error());
+          return;
+        }
+      }
+
+      Ok(false) => {}
+
+      Err(error) => {
+        error!("Stopped watching for shutdown signals: %s", error);
+        return;
+      }
+    }
+  }
+}
+
+fn listen<H: Handler+Clone+Owned, A: Authenticator+Const+Owned>(listener: &ListenerConfig,
+                                                                authenticator: arc::ARC<A>,
+                                                                handler: H,
+                                                                control: &ServerControl)
+   -> Result<(),~str> {
+  let tls = match listener.tls {
+    Some(ref config) => match TlsContext::new(config) {
+      Ok(context) => Some(arc::ARC(context)),
+      Err(error) => return Err(error)
+    },
+    None => None
+  };
+
+  let settings = ConnectionSettings {
+    limits: listener.limits.clone(),
+    authenticator: authenticator,
+    subprotocols: copy listener.subprotocols,
+    queue_limit: listener.queue_limit,
+    queue_policy: listener.queue_policy,
+    tls: tls,
+    control: control.clone(),
+  };
+
+  match listener.address {
+    TcpAddress(ref host, port) => {
+      match parse_ip(*host) {
+        Ok(ip) => listen_tcp(ip, port, listener.backlog, settings, handler),
+        Err(error) => Err(error)
+      }
+    }
+
+    UnixPath(ref path) => listen_unix(*path, listener, settings, handler),
+
+    InheritedSocket(fd) => {
+      let description = listener.address.to_str();
+      listen_polling(UnixListener::from_fd(fd as c_int), description,
+                     listener.max_connections, settings, handler)
+    }
+  }
+}
+
+fn parse_ip(host: &str) -> Result<net_ip::IpAddr,~str> {
+  match net_ip::v4::try_parse_addr(host) {
+    Ok(ip) => Ok(ip),
+    Err(_) => match net_ip::v6::try_parse_addr(host) {
+      Ok(ip) => Ok(ip),
+      Err(error) => Err(~"Invalid listen address " + host + ": " + error.err_msg)
+    }
+  }
+}
+
+fn listen_tcp<H: Handler+Clone+Owned, A: Authenticator+Const+Owned>(ip: net_ip::IpAddr,
+                                                                    port: uint,
+                                                                    backlog: uint,
+                                                                    settings: ConnectionSettings<A>,
+                                                                    handler: H)
+   -> Result<(),~str> {
+  let address = net_ip::format_addr(&ip);
+  let io_task = uv_global_loop::get();
+  let control = settings.control.clone();
+  let on_establish: ~fn(core::comm::SharedChan<Option<net_tcp::TcpErrData>>) =
+   |chan| {
+     info!("Listening on %s:%u", address, port);
+     control.listener_started(TcpListenerHandle(chan));
+   };
+  let new_connect: ~fn(net_tcp::TcpNewConnection,core::comm::SharedChan<Option<net_tcp::TcpErrData>>) =
+   |conn, chan| {
+     let (cont_po, cont_ch) = core::comm::stream::<option::Option<net_tcp::TcpErrData>>();
+     let mut connection_handler = handler.clone();
+     let connection_settings = settings.clone();
+
+     do task::spawn {
+       match net_tcp::accept(conn) {
+         Ok(socket) => {
+           cont_ch.send(None);
+           let source = net_ip::format_addr(&socket.get_peer_addr());
+           handle_connection(socket, source, &connection_settings, &mut connection_handler);
+         }
+         Err(error) => {
+           cont_ch.send(Some(error));
+           debug!("Error during accept");
+         }
+       }
+     };
+
+     match cont_po.recv() {
+       Some(error) => debug!("%s: %s", error.err_name, error.err_msg),
+       None => ()
+     }
+   };
+
+  let result = net_tcp::listen(ip,
+                               port,
+                               backlog,
+                               &io_task,
+                               on_establish,
+                               new_connect);
+
+  match result {
+    Ok(()) => Ok(()),
+    Err(net_tcp::AddressInUse) => Err(~"Address in use: " + address + ":" + port.to_str()),
+    Err(net_tcp::AccessDenied) => Err(~"Access denied: " + address + ":" + port.to_str()),
+    Err(net_tcp::GenericListenErr(name, message)) => Err(name + ": " + message)
+  }
+}
+
+fn listen_unix<H: Handler+Clone+Owned, A: Authenticator+Const+Owned>(path: &str,
+                                                                     listener: &ListenerConfig,
+                                                                     settings: ConnectionSettings<A>,
+                                                                     handler: H)
+   -> Result<(),~str> {
+  let unix_listener = match UnixListener::bind(path, listener.backlog,
+                                               listener.socket_mode,
+                                               listener.unlink_stale) {
+    Ok(unix_listener) => unix_listener,
+    Err(error) => return Err(error)
+  };
+
+  listen_polling(unix_listener, ~"unix:" + path, listener.max_connections, settings, handler)
+}
+
+/* Accepts from a listener that has no event loop behind it by polling, so
+   the stop request is noticed within LISTENER_POLL_MS. Every connection gets
+   its own thread, and no more than max_connections are accepted at once;
+   the rest wait in the backlog. */
+fn listen_polling<H: Handler+Clone+Owned, A: Authenticator+Const+Owned>(listener: UnixListener,
+                                                                        description: ~str,
+                                                                        max_connections: uint,
+                                                                        settings: ConnectionSettings<A>,
+                                                                        handler: H)
+   -> Result<(),~str> {
+  let iotask = uv_global_loop::get();
+  let (stop_po, stop_ch) = core::comm::stream();
+  let (done_po, done_ch) = core::comm::stream();
+  let done_ch = core::comm::SharedChan(done_ch);
+  let mut active = 0;
+
+  settings.control.listener_started(PollingListenerHandle(stop_ch));
+  info!("Listening on %s", description);
+
+  while !stop_po.peek() {
+    while done_po.peek() {
+      done_po.recv();
+      active -= 1;
+    }
+
+    if active >= max_connections {
+      timer::sleep(&iotask, LISTENER_POLL_MS);
+      loop;
+    }
+
+    match listener.accept_timeout(LISTENER_POLL_MS) {
+      Ok(Some(socket)) => {
+        let mut connection_handler = handler.clone();
+        let connection_settings = settings.clone();
+        let source = copy description;
+        let slot = ConnectionSlot { done: done_ch.clone() };
+        active += 1;
+
+        do task::spawn_sched(task::SingleThreaded) {
+          let _slot = &slot;
+          handle_connection(socket, source, &connection_settings, &mut connection_handler);
+        }
+      }
+
+      Ok(None) => {}
+      Err(error) => return Err(error)
+    }
+  }
+
+  Ok(())
+}
+
+/* Frees its place under the listener's max_connections when the connection
+   task ends, even when it fails. */
+struct ConnectionSlot {
+  done: core::comm::SharedChan<()>
+}
+
+impl Drop for ConnectionSlot {
+  fn finalize(&self) {
+    self.done.try_send(());
+  }
+}
+
+/* Closes the outbound queue and unregisters the connection when the
+   connection task ends, even when it fails, so that producers blocked on the
+   queue wake up and room members hear about the disconnect. */
+struct Registration {
+  id: ConnectionId,
+  queue: OutboundQueue,
+  control: ServerControl,
+}
+
+impl Drop for Registration {
+  fn finalize(&self) {
+    self.queue.close();
+    self.control.connection_closed(self.id);
+  }
+}
+
+/* Traffic is counted on the raw socket, so TLS connections report the bytes
+   that actually crossed the wire. */
+fn handle_connection<T: Transport, H: Handler, A: Authenticator+Const+Owned>(socket: T,
+                                                                             source: ~str,
+                                                                             settings: &ConnectionSettings<A>,
+                                                                             handler: &mut H) {
+  let traffic = TrafficCounter::new();
+  let socket = CountingTransport::new(socket, traffic.clone());
+
+  match settings.tls {
+    Some(ref context) => {
+      match TlsTransport::accept(socket, arc::get(context), settings.limits.timeout_ms) {
+        Ok(transport) => handle_socket(transport, source, traffic, settings, handler),
+        Err(error) => debug!("TLS handshake failed: %s", error)
+      }
+    }
+
+    None => handle_socket(socket, source, traffic, settings, handler)
+  }
+}
+
+fn handle_socket<T: Transport, H: Handler, A: Authenticator+Const+Owned>(socket: T,
+                                                                         source: ~str,
+                                                                         traffic: TrafficCounter,
+                                                                         settings: &ConnectionSettings<A>,
+                                                                         handler: &mut H) {
+  let accepted = accept_connection_with_protocols(socket,
+                                                  &settings.limits,
+                                                  arc::get(&settings.authenticator),
+                                                  settings.subprotocols);
+
+  match accepted {
+    Ok((websocket, request, rest)) => {
+      let mut websocket = websocket;
+      let queue = OutboundQueue::new(settings.queue_limit, settings.queue_policy);
+      let path = url_path(request.url().get_or_default(~"/"));
+      let protocol = websocket.protocol().clone();
+      let entry = ConnectionEntry::new(source, path, protocol, traffic, queue.clone());
+
+      match settings.control.connection_opened(entry) {
+        Some(id) => {
+          let _registration = Registration {
+            id: id,
+            queue: queue.clone(),
+            control: settings.control.clone()
+          };
+
+          websocket.set_connection_id(id);
+          websocket.run_with_commands(&request, rest, handler, &queue);
+        }
+
+        None => websocket.close(CLOSE_GOING_AWAY, "Server shutting down")
+      }
+    }
+
+    Err(error) => debug!("Handshake failed: %s", error)
+  }
+}
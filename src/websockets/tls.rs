@@ -0,0 +1,606 @@
+use core::cast::transmute;
+use core::libc::{c_char, c_int, c_long, c_uint, c_void, size_t};
+use core::ptr::{null, to_unsafe_ptr};
+use std::time::precise_time_ns;
+use openssl::*;
+use websockets::config::{TlsConfig, TlsCertificate};
+use websockets::websocket::{Transport, Error};
+
+static READ_BUFFER_SIZE: uint = 16384;
+
+pub struct TlsContext {
+  ctx: *SSL_CTX,
+  state: ~TlsCallbackState,
+}
+
+struct TlsCallbackState {
+  server_names: ~[(~str, *SSL_CTX)],
+  alpn_protocols: ~[u8],
+}
+
+pub struct TlsSession {
+  ssl: *SSL,
+  read_bio: *BIO,
+  write_bio: *BIO,
+}
+
+pub struct TlsTransport<T> {
+  transport: T,
+  session: TlsSession,
+}
+
+impl TlsContext {
+  pub fn new(config: &TlsConfig) -> Result<TlsContext,~str> {
+    match config.validate() {
+      Ok(()) => {}
+      Err(error) => return Err(error)
+    }
+
+    let mut context = TlsContext {
+      ctx: null(),
+      state: ~TlsCallbackState {
+        server_names: ~[],
+        alpn_protocols: encode_alpn_protocols(config.alpn_protocols),
+      }
+    };
+
+    for config.certificates.each |certificate| {
+      let ctx = match load_certificate(certificate) {
+        Ok(ctx) => ctx,
+        Err(error) => return Err(error)
+      };
+
+      match certificate.server_name {
+        Some(ref name) => context.state.server_names.push((name.to_lower(), ctx)),
+        None => context.ctx = ctx
+      }
+    }
+
+    let arg = to_unsafe_ptr(&*context.state) as *c_void;
+
+    unsafe {
+      SSL_CTX_callback_ctrl(context.ctx, SSL_CTRL_SET_TLSEXT_SERVERNAME_CB, on_servername);
+      SSL_CTX_ctrl(context.ctx, SSL_CTRL_SET_TLSEXT_SERVERNAME_ARG, 0, arg);
+
+      if !context.state.alpn_protocols.is_empty() {
+        SSL_CTX_set_alpn_select_cb(context.ctx, on_alpn_select, arg);
+
+        for context.state.server_names.each |server_name| {
+          match *server_name {
+            (_, ctx) => SSL_CTX_set_alpn_select_cb(ctx, on_alpn_select, arg)
+          }
+        }
+      }
+    }
+
+    Ok(context)
+  }
+
+  pub fn client() -> Result<TlsContext,~str> {
+    let ctx = unsafe { SSL_CTX_new(TLS_client_method()) };
+
+    if ctx.is_null() {
+      return Err(last_error("Could not create TLS client context"));
+    }
+
+    Ok(TlsContext {
+      ctx: ctx,
+      state: ~TlsCallbackState { server_names: ~[], alpn_protocols: ~[] }
+    })
+  }
+}
+
+impl TlsCallbackState {
+  fn find_context(&self, server_name: &str) -> Option<*SSL_CTX> {
+    let server_name = server_name.to_lower();
+
+    for self.server_names.each |entry| {
+      match *entry {
+        (ref name, ctx) => {
+          if matches_server_name(*name, server_name) {
+            return Some(ctx);
+          }
+        }
+      }
+    }
+
+    None
+  }
+}
+
+#[unsafe_destructor]
+impl Drop for TlsContext {
+  fn finalize(&self) {
+    unsafe {
+      if !self.ctx.is_null() {
+        SSL_CTX_free(self.ctx);
+      }
+
+      for self.state.server_names.each |entry| {
+        match *entry {
+          (_, ctx) => SSL_CTX_free(ctx)
+        }
+      }
+    }
+  }
+}
+
+impl TlsSession {
+  pub fn accept(context: &TlsContext) -> Result<TlsSession,~str> {
+    TlsSession::new(context.ctx, true)
+  }
+
+  pub fn connect(context: &TlsContext,
+                 server_name: Option<&str>,
+                 alpn_protocols: &[~str]) -> Result<TlsSession,~str> {
+    let session = match TlsSession::new(context.ctx, false) {
+      Ok(session) => session,
+      Err(error) => return Err(error)
+    };
+
+    unsafe {
+      for server_name.each |name| {
+        do str::as_c_str(*name) |c_name| {
+          SSL_ctrl(session.ssl, SSL_CTRL_SET_TLSEXT_HOSTNAME,
+                   TLSEXT_NAMETYPE_host_name as c_long, c_name as *c_void);
+        }
+      }
+
+      if !alpn_protocols.is_empty() {
+        let protocols = encode_alpn_protocols(alpn_protocols);
+
+        do vec::as_imm_buf(protocols) |buf, len| {
+          SSL_set_alpn_protos(session.ssl, buf, len as c_uint);
+        }
+      }
+    }
+
+    Ok(session)
+  }
+
+  priv fn new(ctx: *SSL_CTX, server: bool) -> Result<TlsSession,~str> {
+    unsafe {
+      let ssl = SSL_new(ctx);
+
+      if ssl.is_null() {
+        return Err(last_error("Could not create TLS session"));
+      }
+
+      let read_bio = BIO_new(BIO_s_mem());
+      let write_bio = BIO_new(BIO_s_mem());
+      SSL_set_bio(ssl, read_bio, write_bio);
+
+      if server {
+        SSL_set_accept_state(ssl);
+      } else {
+        SSL_set_connect_state(ssl);
+      }
+
+      Ok(TlsSession { ssl: ssl, read_bio: read_bio, write_bio: write_bio })
+    }
+  }
+
+  pub fn handshake(&self) -> Result<bool,~str> {
+    let ret = unsafe { SSL_do_handshake(self.ssl) };
+
+    if ret == 1 {
+      return Ok(true);
+    }
+
+    match self.error_code(ret) {
+      SSL_ERROR_WANT_READ | SSL_ERROR_WANT_WRITE => Ok(false),
+      _ => Err(last_error("TLS handshake failed"))
+    }
+  }
+
+  pub fn is_established(&self) -> bool {
+    unsafe { SSL_is_init_finished(self.ssl) == 1 }
+  }
+
+  pub fn feed(&self, bytes: &[u8]) {
+    do vec::as_imm_buf(bytes) |buf, len| {
+      unsafe { BIO_write(self.read_bio, buf as *c_void, len as c_int); }
+    }
+  }
+
+  pub fn take_output(&self) -> ~[u8] {
+    let pending = unsafe { BIO_ctrl_pending(self.write_bio) as uint };
+    let mut output = vec::from_elem(pending, 0u8);
+
+    if pending > 0 {
+      do vec::as_mut_buf(output) |buf, len| {
+        unsafe { BIO_read(self.write_bio, buf as *c_void, len as c_int); }
+      }
+    }
+
+    output
+  }
+
+  pub fn read_plaintext(&self) -> Result<Option<~[u8]>,~str> {
+    let mut buffer = vec::from_elem(READ_BUFFER_SIZE, 0u8);
+
+    let ret = do vec::as_mut_buf(buffer) |buf, len| {
+      unsafe { SSL_read(self.ssl, buf as *c_void, len as c_int) }
+    };
+
+    if ret > 0 {
+      return Ok(Some(vec::from_slice(buffer.slice(0, ret as uint))));
+    }
+
+    match self.error_code(ret) {
+      SSL_ERROR_WANT_READ | SSL_ERROR_WANT_WRITE => Ok(None),
+      SSL_ERROR_ZERO_RETURN => Err(~"TLS connection closed"),
+      _ => Err(last_error("TLS read failed"))
+    }
+  }
+
+  pub fn write_plaintext(&self, bytes: &[u8]) -> Result<(),~str> {
+    if bytes.is_empty() {
+      return Ok(());
+    }
+
+    let ret = do vec::as_imm_buf(bytes) |buf, len| {
+      unsafe { SSL_write(self.ssl, buf as *c_void, len as c_int) }
+    };
+
+    if ret > 0 {
+      Ok(())
+    } else {
+      Err(last_error("TLS write failed"))
+    }
+  }
+
+  pub fn shutdown(&self) {
+    unsafe { SSL_shutdown(self.ssl); }
+  }
+
+  pub fn server_name(&self) -> Option<~str> {
+    unsafe {
+      let name = SSL_get_servername(self.ssl, TLSEXT_NAMETYPE_host_name);
+
+      if name.is_null() {
+        None
+      } else {
+        Some(str::raw::from_c_str(name))
+      }
+    }
+  }
+
+  pub fn negotiated_protocol(&self) -> Option<~str> {
+    unsafe {
+      let data: *u8 = null();
+      let len: c_uint = 0;
+      SSL_get0_alpn_selected(self.ssl, to_unsafe_ptr(&data), to_unsafe_ptr(&len));
+
+      if data.is_null() || len == 0 {
+        None
+      } else {
+        Some(str::from_bytes(vec::from_buf(data, len as uint)))
+      }
+    }
+  }
+
+  pub fn uses_context(&self, context: &TlsContext, server_name: &str) -> bool {
+    let selected = unsafe { SSL_get_SSL_CTX(self.ssl) };
+
+    match context.state.find_context(server_name) {
+      Some(ctx) => ctx == selected,
+      None => context.ctx == selected
+    }
+  }
+
+  priv fn error_code(&self, ret: c_int) -> c_int {
+    unsafe { SSL_get_error(self.ssl, ret) }
+  }
+}
+
+#[unsafe_destructor]
+impl Drop for TlsSession {
+  fn finalize(&self) {
+    unsafe { SSL_free(self.ssl); }
+  }
+}
+
+impl<T: Transport> TlsTransport<T> {
+  pub fn accept(transport: T,
+                context: &TlsContext,
+                timeout_ms: uint) -> Result<TlsTransport<T>,~str> {
+    match TlsSession::accept(context) {
+      Ok(session) => {
+        let tls = TlsTransport { transport: transport, session: session };
+
+        match tls.complete_handshake(timeout_ms) {
+          Ok(()) => Ok(tls),
+          Err(error) => Err(error)
+        }
+      }
+
+      Err(error) => Err(error)
+    }
+  }
+
+  pub fn session(&self) -> &'self TlsSession {
+    &self.session
+  }
+
+  priv fn complete_handshake(&self, timeout_ms: uint) -> Result<(),~str> {
+    let started_at = precise_time_ns();
+
+    loop {
+      let result = self.session.handshake();
+      self.flush();
+
+      match result {
+        Ok(true) => return Ok(()),
+        Err(error) => return Err(error),
+        Ok(false) => {}
+      }
+
+      let elapsed_ms = ((precise_time_ns() - started_at) / 1000000) as uint;
+
+      if elapsed_ms >= timeout_ms {
+        return Err(~"TLS handshake timed out");
+      }
+
+      match self.transport.read_timeout(timeout_ms - elapsed_ms) {
+        Ok(Some(bytes)) => self.session.feed(bytes),
+        Ok(None) => return Err(~"TLS handshake timed out"),
+        Err(error) => return Err(error)
+      }
+    }
+  }
+
+  priv fn flush(&self) {
+    let output = self.session.take_output();
+
+    if !output.is_empty() {
+      self.transport.write(output);
+    }
+  }
+}
+
+impl<T: Transport> Transport for TlsTransport<T> {
+  fn read(&self) -> Result<~[u8],Error> {
+    loop {
+      match self.session.read_plaintext() {
+        Ok(Some(bytes)) => return Ok(bytes),
+        Ok(None) => {}
+        Err(error) => return Err(error)
+      }
+
+      self.flush();
+
+      match self.transport.read() {
+        Ok(bytes) => self.session.feed(bytes),
+        Err(error) => return Err(error)
+      }
+    }
+  }
+
+  fn read_timeout(&self, timeout_ms: uint) -> Result<Option<~[u8]>,Error> {
+    loop {
+      match self.session.read_plaintext() {
+        Ok(Some(bytes)) => return Ok(Some(bytes)),
+        Ok(None) => {}
+        Err(error) => return Err(error)
+      }
+
+      self.flush();
+
+      match self.transport.read_timeout(timeout_ms) {
+        Ok(Some(bytes)) => self.session.feed(bytes),
+        Ok(None) => return Ok(None),
+        Err(error) => return Err(error)
+      }
+    }
+  }
+
+  fn write(&self, bytes: ~[u8]) {
+    match self.session.write_plaintext(bytes) {
+      Ok(()) => self.flush(),
+      Err(error) => debug!("TLS write failed: %s", error)
+    }
+  }
+}
+
+fn load_certificate(certificate: &TlsCertificate) -> Result<*SSL_CTX,~str> {
+  unsafe {
+    let ctx = SSL_CTX_new(TLS_server_method());
+
+    if ctx.is_null() {
+      return Err(last_error("Could not create TLS context"));
+    }
+
+    let cert_loaded = do str::as_c_str(certificate.cert_file) |file| {
+      SSL_CTX_use_certificate_chain_file(ctx, file)
+    };
+
+    let key_loaded = cert_loaded == 1 && do str::as_c_str(certificate.key_file) |file| {
+      SSL_CTX_use_PrivateKey_file(ctx, file, SSL_FILETYPE_PEM)
+    } == 1;
+
+    if !key_loaded || SSL_CTX_check_private_key(ctx) != 1 {
+      let error = last_error("Invalid certificate or key");
+      SSL_CTX_free(ctx);
+      return Err(certificate.cert_file + ": " + error);
+    }
+
+    Ok(ctx)
+  }
+}
+
+fn encode_alpn_protocols(protocols: &[~str]) -> ~[u8] {
+  let mut encoded = ~[];
+
+  for protocols.each |protocol| {
+    encoded.push(protocol.len() as u8);
+    encoded.push_all(protocol.to_bytes());
+  }
+
+  encoded
+}
+
+fn matches_server_name(pattern: &str, server_name: &str) -> bool {
+  if pattern.starts_with("*.") {
+    let suffix = pattern.slice(1, pattern.len());
+    server_name.len() > suffix.len() && server_name.ends_with(suffix) &&
+      !str::contains_char(server_name.slice(0, server_name.len() - suffix.len()), '.')
+  } else {
+    str::from_slice(pattern) == str::from_slice(server_name)
+  }
+}
+
+fn last_error(default: &str) -> ~str {
+  unsafe {
+    let code = ERR_get_error();
+
+    if code == 0 {
+      return str::from_slice(default);
+    }
+
+    let mut buffer = vec::from_elem(256, 0u8);
+
+    do vec::as_mut_buf(buffer) |buf, len| {
+      ERR_error_string_n(code, buf as *c_char, len as size_t);
+    }
+
+    ERR_clear_error();
+    str::raw::from_buf(vec::raw::to_ptr(buffer))
+  }
+}
+
+extern fn on_servername(ssl: *SSL, _: *c_int, arg: *c_void) -> c_int {
+  unsafe {
+    let name = SSL_get_servername(ssl, TLSEXT_NAMETYPE_host_name);
+
+    if arg.is_null() || name.is_null() {
+      return SSL_TLSEXT_ERR_OK;
+    }
+
+    let state: &TlsCallbackState = transmute(arg);
+
+    match state.find_context(str::raw::from_c_str(name)) {
+      Some(ctx) => { SSL_set_SSL_CTX(ssl, ctx); }
+      None => {}
+    }
+
+    SSL_TLSEXT_ERR_OK
+  }
+}
+
+extern fn on_alpn_select(_: *SSL,
+                         out: **u8,
+                         out_len: *u8,
+                         client: *u8,
+                         client_len: c_uint,
+                         arg: *c_void) -> c_int {
+  unsafe {
+    if arg.is_null() {
+      return SSL_TLSEXT_ERR_NOACK;
+    }
+
+    let state: &TlsCallbackState = transmute(arg);
+
+    let result = do vec::as_imm_buf(state.alpn_protocols) |server, server_len| {
+      SSL_select_next_proto(out, out_len, server, server_len as c_uint, client, client_len)
+    };
+
+    if result == OPENSSL_NPN_NEGOTIATED {
+      SSL_TLSEXT_ERR_OK
+    } else {
+      SSL_TLSEXT_ERR_ALERT_FATAL
+    }
+  }
+}
+
+#[cfg(test)]
+fn test_tls_config() -> TlsConfig {
+  TlsConfig {
+    certificates: ~[
+      TlsCertificate { server_name: None,
+                       cert_file: ~"test/certs/localhost.crt",
+                       key_file: ~"test/certs/localhost.key" },
+      TlsCertificate { server_name: Some(~"example.com"),
+                       cert_file: ~"test/certs/example.com.crt",
+                       key_file: ~"test/certs/example.com.key" }],
+    alpn_protocols: ~[~"http/1.1"]
+  }
+}
+
+#[cfg(test)]
+fn pump_handshake(client: &TlsSession, server: &TlsSession) -> bool {
+  for 10.times {
+    let client_done = client.handshake() == Ok(true);
+    server.feed(client.take_output());
+    let server_done = server.handshake() == Ok(true);
+    client.feed(server.take_output());
+
+    if client_done && server_done {
+      return true;
+    }
+  }
+
+  false
+}
+
+#[test]
+fn tls_handshake_selects_certificate_by_server_name() {
+  let server_context = TlsContext::new(&test_tls_config()).unwrap();
+  let client_context = TlsContext::client().unwrap();
+  let server = TlsSession::accept(&server_context).unwrap();
+  let client = TlsSession::connect(&client_context, Some("example.com"), [~"http/1.1"]).unwrap();
+
+  assert!(pump_handshake(&client, &server));
+  assert!(server.server_name() == Some(~"example.com"));
+  assert!(server.uses_context(&server_context, "example.com"));
+  assert!(server.negotiated_protocol() == Some(~"http/1.1"));
+  assert!(client.negotiated_protocol() == Some(~"http/1.1"));
+}
+
+#[test]
+fn tls_handshake_falls_back_to_default_certificate() {
+  let server_context = TlsContext::new(&test_tls_config()).unwrap();
+  let client_context = TlsContext::client().unwrap();
+  let server = TlsSession::accept(&server_context).unwrap();
+  let client = TlsSession::connect(&client_context, Some("unknown.test"), []).unwrap();
+
+  assert!(pump_handshake(&client, &server));
+  assert!(server.uses_context(&server_context, "unknown.test"));
+  assert!(server.negotiated_protocol() == None);
+}
+
+#[test]
+fn tls_session_round_trips_plaintext() {
+  let server_context = TlsContext::new(&test_tls_config()).unwrap();
+  let client_context = TlsContext::client().unwrap();
+  let server = TlsSession::accept(&server_context).unwrap();
+  let client = TlsSession::connect(&client_context, None, []).unwrap();
+
+  assert!(pump_handshake(&client, &server));
+
+  client.write_plaintext("GET /chat HTTP/1.1\r\n".to_bytes());
+  server.feed(client.take_output());
+
+  assert!(server.read_plaintext() == Ok(Some("GET /chat HTTP/1.1\r\n".to_bytes())));
+  assert!(server.read_plaintext() == Ok(None));
+}
+
+#[test]
+fn tls_context_rejects_missing_certificate() {
+  let config = TlsConfig {
+    certificates: ~[TlsCertificate { server_name: None,
+                                     cert_file: ~"test/certs/missing.crt",
+                                     key_file: ~"test/certs/missing.key" }],
+    alpn_protocols: ~[]
+  };
+
+  assert!(TlsContext::new(&config).is_err());
+}
+
+#[test]
+fn matches_server_name_test() {
+  assert!(matches_server_name("example.com", "example.com"));
+  assert!(matches_server_name("*.example.com", "chat.example.com"));
+  assert!(!matches_server_name("*.example.com", "a.chat.example.com"));
+  assert!(!matches_server_name("*.example.com", "example.com"));
+}
@@ -0,0 +1,372 @@
+use core::libc::{c_int, c_short, c_uint, c_ulong, c_void, size_t};
+use core::ptr::{null, to_unsafe_ptr};
+use unix_socket::*;
+use websockets::websocket::{Transport, Error};
+
+static READ_BUFFER_SIZE: uint = 16384;
+static WRITE_TIMEOUT_MS: uint = 10000;
+
+pub struct UnixListener {
+  fd: c_int,
+  path: ~str,
+}
+
+pub struct UnixSocket {
+  fd: c_int,
+}
+
+impl UnixListener {
+  pub fn bind(path: &str,
+              backlog: uint,
+              mode: Option<uint>,
+              unlink_stale: bool) -> Result<UnixListener,~str> {
+    match remove_stale_socket(path, unlink_stale) {
+      Ok(()) => {}
+      Err(error) => return Err(error)
+    }
+
+    let address = match socket_address(path) {
+      Ok(address) => address,
+      Err(error) => return Err(error)
+    };
+
+    unsafe {
+      let fd = socket(AF_UNIX, SOCK_STREAM, 0);
+
+      if fd < 0 {
+        return Err(~"Could not create socket: " + os::last_os_error());
+      }
+
+      /* The socket file gets its permissions from the umask when it is
+         created, so it is never reachable with looser ones. */
+      let old_mask = mode.map(|mode| umask(!(*mode as c_uint) & 0x1FF));
+      let bound = bind(fd, to_unsafe_ptr(&address), sys::size_of::<sockaddr_un>() as c_uint);
+      let error = os::last_os_error();
+
+      for old_mask.each |old_mask| {
+        umask(*old_mask);
+      }
+
+      if bound != 0 {
+        close(fd);
+        return Err(~"Could not bind " + path + ": " + error);
+      }
+
+      let listener = UnixListener { fd: fd, path: str::from_slice(path) };
+
+      if listen(fd, backlog as c_int) != 0 {
+        return Err(~"Could not listen on " + path + ": " + os::last_os_error());
+      }
+
+      Ok(listener)
+    }
+  }
+
+  /* Adopts a listening socket inherited from the parent process, such as
+     one passed by systemd or a supervisor. Any stream socket works, TCP
+     included, and since there is no path nothing is unlinked on drop. */
+  pub fn from_fd(fd: c_int) -> UnixListener {
+    UnixListener { fd: fd, path: ~"" }
+  }
+
+  pub fn accept_timeout(&self, timeout_ms: uint) -> Result<Option<UnixSocket>,~str> {
+    match wait_readable(self.fd, timeout_ms) {
+      Ok(true) => match self.accept() {
+        Ok(socket) => Ok(Some(socket)),
+        Err(error) => Err(error)
+      },
+      Ok(false) => Ok(None),
+      Err(error) => Err(error)
+    }
+  }
+
+  pub fn accept(&self) -> Result<UnixSocket,~str> {
+    loop {
+      let fd = unsafe { accept(self.fd, null(), null()) };
+
+      if fd >= 0 {
+        return Ok(UnixSocket { fd: fd });
+      }
+
+      if os::errno() != EINTR {
+        return Err(~"Error during accept: " + os::last_os_error());
+      }
+    }
+  }
+}
+
+impl Drop for UnixListener {
+  fn finalize(&self) {
+    unsafe {
+      close(self.fd);
+
+      if !self.path.is_empty() {
+        do str::as_c_str(self.path) |c_path| { unlink(c_path) };
+      }
+    }
+  }
+}
+
+impl UnixSocket {
+  pub fn connect(path: &str) -> Result<UnixSocket,~str> {
+    let address = match socket_address(path) {
+      Ok(address) => address,
+      Err(error) => return Err(error)
+    };
+
+    unsafe {
+      let fd = socket(AF_UNIX, SOCK_STREAM, 0);
+
+      if fd < 0 {
+        return Err(~"Could not create socket: " + os::last_os_error());
+      }
+
+      if connect(fd, to_unsafe_ptr(&address), sys::size_of::<sockaddr_un>() as c_uint) != 0 {
+        let error = os::last_os_error();
+        close(fd);
+        return Err(~"Could not connect to " + path + ": " + error);
+      }
+
+      Ok(UnixSocket { fd: fd })
+    }
+  }
+}
+
+impl Drop for UnixSocket {
+  fn finalize(&self) {
+    unsafe { close(self.fd); }
+  }
+}
+
+impl Transport for UnixSocket {
+  fn read(&self) -> Result<~[u8],Error> {
+    let mut buffer = vec::from_elem(READ_BUFFER_SIZE, 0u8);
+
+    loop {
+      let count = do vec::as_mut_buf(buffer) |buf, len| {
+        unsafe { read(self.fd, buf as *c_void, len as size_t) }
+      };
+
+      if count > 0 {
+        return Ok(vec::from_slice(buffer.slice(0, count as uint)));
+      } else if count == 0 {
+        return Err(~"Connection closed");
+      } else if os::errno() != EINTR {
+        return Err(os::last_os_error());
+      }
+    }
+  }
+
+  fn read_timeout(&self, timeout_ms: uint) -> Result<Option<~[u8]>,Error> {
+    match wait_readable(self.fd, timeout_ms) {
+      Ok(true) => self.read().map(|bytes| Some(copy *bytes)),
+      Ok(false) => Ok(None),
+      Err(error) => Err(error)
+    }
+  }
+
+  /* MSG_NOSIGNAL turns a hung up peer into EPIPE rather than SIGPIPE. When
+     the rest of the data cannot be written the socket is shut down, so the
+     next read reports the connection as closed. */
+  fn write(&self, bytes: ~[u8]) {
+    let mut written = 0;
+
+    while written < bytes.len() {
+      let count = do vec::as_imm_buf(bytes.slice(written, bytes.len())) |buf, len| {
+        unsafe { send(self.fd, buf as *c_void, len as size_t, MSG_NOSIGNAL) }
+      };
+
+      if count > 0 {
+        written += count as uint;
+        loop;
+      }
+
+      let errno = os::errno();
+
+      if errno == EINTR {
+        loop;
+      }
+
+      if errno == EAGAIN && wait_ready(self.fd, POLLOUT, WRITE_TIMEOUT_MS) == Ok(true) {
+        loop;
+      }
+
+      debug!("Write failed: %s", os::last_os_error());
+      unsafe { shutdown(self.fd, SHUT_RDWR); }
+      return;
+    }
+  }
+}
+
+pub fn wait_readable(fd: c_int, timeout_ms: uint) -> Result<bool,~str> {
+  wait_ready(fd, POLLIN, timeout_ms)
+}
+
+fn wait_ready(fd: c_int, events: c_short, timeout_ms: uint) -> Result<bool,~str> {
+  let descriptor = pollfd { fd: fd, events: events, revents: 0 };
+
+  loop {
+    let ready = unsafe { poll(to_unsafe_ptr(&descriptor), 1 as c_ulong, timeout_ms as c_int) };
+
+    if ready >= 0 {
+      return Ok(ready > 0);
+    }
+
+    if os::errno() != EINTR {
+      return Err(os::last_os_error());
+    }
+  }
+}
+
+fn socket_address(path: &str) -> Result<sockaddr_un,~str> {
+  let bytes = path.to_bytes();
+
+  if bytes.is_empty() || bytes.len() >= SUN_PATH_SIZE {
+    return Err(~"Invalid unix socket path: " + path);
+  }
+
+  let mut address = sockaddr_un { sun_family: AF_UNIX as u16, sun_path: [0u8, ..108] };
+
+  for bytes.eachi |index, byte| {
+    address.sun_path[index] = *byte;
+  }
+
+  Ok(address)
+}
+
+/* A socket file left behind by a crashed server still exists but refuses
+   connections. Only those are removed; live sockets and anything that isn't
+   a socket (open() on a socket fails with ENXIO) are left alone. */
+fn remove_stale_socket(path: &str, unlink_stale: bool) -> Result<(),~str> {
+  let fd = do str::as_c_str(path) |c_path| {
+    unsafe { open(c_path, O_RDONLY | O_NONBLOCK) }
+  };
+
+  if fd >= 0 {
+    unsafe { close(fd); }
+    return Err(~"Refusing to replace " + path + ", it is not a socket");
+  }
+
+  let errno = os::errno();
+
+  if errno == ENOENT {
+    return Ok(());
+  } else if errno != ENXIO {
+    return Err(~"Could not inspect " + path + ": " + os::last_os_error());
+  }
+
+  match UnixSocket::connect(path) {
+    Ok(_) => Err(~"Unix socket " + path + " is already in use"),
+    Err(_) if os::errno() != ECONNREFUSED => {
+      Err(~"Could not inspect " + path + ": " + os::last_os_error())
+    }
+    Err(_) if !unlink_stale => Err(~"Stale unix socket " + path + " exists"),
+    Err(_) => {
+      unsafe { do str::as_c_str(path) |c_path| { unlink(c_path) }; }
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+fn test_socket_path(name: &str) -> ~str {
+  ~"/tmp/dolittle-test-" + os::getpid().to_str() + "-" + name + ".sock"
+}
+
+#[test]
+fn unix_listener_accepts_connections() {
+  let path = test_socket_path("accept");
+  let listener = UnixListener::bind(path, 10, Some(432), true).unwrap();
+  let client = UnixSocket::connect(path).unwrap();
+  let server = listener.accept().unwrap();
+
+  client.write("ping".to_bytes());
+
+  assert!(server.read() == Ok("ping".to_bytes()));
+  assert!(server.read_timeout(10) == Ok(None));
+}
+
+#[test]
+fn unix_socket_write_to_hung_up_peer_closes_connection() {
+  let path = test_socket_path("hangup");
+  let listener = UnixListener::bind(path, 10, None, true).unwrap();
+  let server = {
+    let _client = UnixSocket::connect(path).unwrap();
+    listener.accept().unwrap()
+  };
+
+  server.write("ping".to_bytes());
+  server.write("ping".to_bytes());
+
+  assert!(server.read().is_err());
+}
+
+#[test]
+fn unix_listener_creates_socket_with_mode() {
+  let path = test_socket_path("mode");
+  let _listener = UnixListener::bind(path, 10, Some(384), true).unwrap();
+
+  assert!(os::path_exists(&Path(path)));
+  assert!(Path(path).get_mode().map(|mode| *mode & 0x1FF) == Some(384));
+}
+
+#[test]
+fn unix_listener_removes_socket_on_drop() {
+  let path = test_socket_path("drop");
+
+  {
+    let _listener = UnixListener::bind(path, 10, None, true).unwrap();
+    assert!(os::path_exists(&Path(path)));
+  }
+
+  assert!(!os::path_exists(&Path(path)));
+}
+
+#[test]
+fn unix_listener_adopts_inherited_socket() {
+  let path = test_socket_path("inherited");
+  let listener = UnixListener::bind(path, 10, None, true).unwrap();
+
+  {
+    let inherited = UnixListener::from_fd(listener.fd);
+    let client = UnixSocket::connect(path).unwrap();
+    let server = inherited.accept_timeout(1000).unwrap().unwrap();
+
+    client.write("ping".to_bytes());
+    assert!(server.read() == Ok("ping".to_bytes()));
+  }
+
+  assert!(os::path_exists(&Path(path)));
+
+  unsafe { cast::forget(listener); }
+  os::remove_file(&Path(path));
+}
+
+#[test]
+fn unix_listener_replaces_stale_socket() {
+  let path = test_socket_path("stale");
+  let stale = UnixListener::bind(path, 10, None, true).unwrap();
+
+  unsafe {
+    close(stale.fd);
+    cast::forget(stale);
+  }
+
+  assert!(UnixListener::bind(path, 10, None, false).is_err());
+  assert!(UnixListener::bind(path, 10, None, true).is_ok());
+}
+
+#[test]
+fn unix_listener_refuses_live_socket_and_regular_file() {
+  let path = test_socket_path("live");
+  let _listener = UnixListener::bind(path, 10, None, true).unwrap();
+
+  assert!(UnixListener::bind(path, 10, None, true).is_err());
+
+  let file_path = test_socket_path("file");
+  io::file_writer(&Path(file_path), [io::Create]).get().write_str("not a socket");
+
+  assert!(UnixListener::bind(file_path, 10, None, true).is_err());
+  assert!(os::path_exists(&Path(file_path)));
+  os::remove_file(&Path(file_path));
+}
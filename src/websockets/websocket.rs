@@ -1,57 +1,489 @@
+use std::arc;
+use std::net_tcp;
+use std::time::precise_time_ns;
+use http::auth::*;
+use http::errors::*;
+use http::headers::*;
 use http::parser::*;
+use http::request::*;
+use websockets::framing::parser::FrameParser;
+use websockets::framing::types::{Frame, PING, PONG, CONNECTION_CLOSE};
+use websockets::framing::types::{CLOSE_NORMAL, CLOSE_NO_STATUS, CLOSE_ABNORMAL, CLOSE_PROTOCOL_ERROR};
+use websockets::framing::types::CLOSE_INVALID_PAYLOAD;
+use websockets::handler::*;
+use websockets::messaging::{Receiver, Receiving, Received, ReceptionError, INVALID_UTF8};
+use websockets::messaging::{DataMessage, TextMessage};
+use websockets::outbound::OutboundQueue;
 use websockets::protocol::*;
+use websockets::registry::ConnectionId;
 
 struct WebSocket<T> {
-  socket: T
+  socket: T,
+  identity: Identity,
+  protocol: Option<~str>,
+  connection_id: Option<ConnectionId>
 }
 
-trait Transport {
+pub trait Transport {
   fn read(&self) -> Result<~[u8],Error>;
+  fn read_timeout(&self, timeout_ms: uint) -> Result<Option<~[u8]>,Error>;
   fn write(&self, bytes: ~[u8]);
 }
 
+#[deriving(Eq,Clone)]
+pub struct HandshakeLimits {
+  max_bytes: uint,
+  max_headers: uint,
+  max_header_size: uint,
+  timeout_ms: uint,
+}
+
+#[deriving(Eq)]
+pub enum HandshakeError {
+  TRANSPORT_ERROR(~str),
+  MALFORMED_REQUEST(HttpParseError),
+  HANDSHAKE_TOO_LARGE,
+  TOO_MANY_HEADERS,
+  HEADER_TOO_LARGE,
+  HANDSHAKE_TIMEOUT,
+}
+
+pub type Handshake = (Parser, ~[u8]);
+
+pub type Connection<T> = (WebSocket<T>, Parser, ~[u8]);
+
+/* SendShared carries a frame that was composed once for many connections;
+   each connection copies it only when writing. */
+pub enum ConnectionCommand {
+  SendBytes(~[u8]),
+  SendShared(arc::ARC<~[u8]>),
+  Disconnect(u16, ~str),
+  Abort,
+}
+
+static COMMAND_POLL_MS: uint = 100;
+
+impl Eq for ConnectionCommand {
+  fn eq(&self, other: &ConnectionCommand) -> bool {
+    match (self, other) {
+      (&SendBytes(ref bytes), &SendBytes(ref other_bytes)) => bytes == other_bytes,
+      (&SendShared(ref bytes), &SendShared(ref other_bytes)) => {
+        arc::get(bytes) == arc::get(other_bytes)
+      }
+      (&Disconnect(code, ref reason), &Disconnect(other_code, ref other_reason)) => {
+        code == other_code && reason == other_reason
+      }
+      (&Abort, &Abort) => true,
+      _ => false
+    }
+  }
+
+  fn ne(&self, other: &ConnectionCommand) -> bool {
+    !self.eq(other)
+  }
+}
+
+impl Clone for ConnectionCommand {
+  fn clone(&self) -> ConnectionCommand {
+    match *self {
+      SendBytes(ref bytes) => SendBytes(copy *bytes),
+      SendShared(ref bytes) => SendShared(arc::clone(bytes)),
+      Disconnect(code, ref reason) => Disconnect(code, reason.clone()),
+      Abort => Abort
+    }
+  }
+}
+
+impl HandshakeLimits {
+  pub fn default() -> HandshakeLimits {
+    HandshakeLimits {
+      max_bytes: 16384,
+      max_headers: 64,
+      max_header_size: 8192,
+      timeout_ms: 10000,
+    }
+  }
+
+  fn check(&self, parser: &Parser) -> Option<HandshakeError> {
+    if parser.bytes_consumed() > self.max_bytes {
+      Some(HANDSHAKE_TOO_LARGE)
+    } else if parser.header_count() > self.max_headers {
+      Some(TOO_MANY_HEADERS)
+    } else if parser.largest_header_size() > self.max_header_size {
+      Some(HEADER_TOO_LARGE)
+    } else {
+      None
+    }
+  }
+}
+
+impl HandshakeError {
+  pub fn to_response_str(&self) -> Option<~str> {
+    match *self {
+      TRANSPORT_ERROR(_) => None,
+      MALFORMED_REQUEST(_) => Some(~"HTTP/1.1 400 Bad Request\r\n\r\n"),
+      HANDSHAKE_TIMEOUT => Some(~"HTTP/1.1 408 Request Timeout\r\n\r\n"),
+      _ => Some(~"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n")
+    }
+  }
+
+  pub fn to_str(&self) -> ~str {
+    match *self {
+      TRANSPORT_ERROR(ref message) => message.clone(),
+      MALFORMED_REQUEST(error) => error.description(),
+      HANDSHAKE_TOO_LARGE => ~"Handshake too large",
+      TOO_MANY_HEADERS => ~"Too many headers",
+      HEADER_TOO_LARGE => ~"Header too large",
+      HANDSHAKE_TIMEOUT => ~"Handshake timed out",
+    }
+  }
+}
+
+impl<T: Transport> WebSocket<T> {
+  pub fn identity(&self) -> &'self Identity {
+    &self.identity
+  }
+
+  pub fn protocol(&self) -> &'self Option<~str> {
+    &self.protocol
+  }
+
+  /* Only set for connections registered with a running server. */
+  pub fn connection_id(&self) -> Option<ConnectionId> {
+    self.connection_id
+  }
+
+  pub fn set_connection_id(&mut self, id: ConnectionId) {
+    self.connection_id = Some(id);
+  }
+
+  pub fn send_frame(&self, frame: &Frame) {
+    self.socket.write(frame.compose());
+  }
+
+  pub fn send_text(&self, text: &str) {
+    self.send_frame(&Frame::text(text));
+  }
+
+  pub fn send_binary(&self, data: &[u8]) {
+    self.send_frame(&Frame::binary(data));
+  }
+
+  pub fn close(&self, code: u16, reason: &str) {
+    self.send_frame(&Frame::close(code, reason));
+  }
+
+  pub fn run<H: Handler>(&self, request: &Parser, rest: ~[u8], handler: &mut H) {
+    self.run_loop(request, rest, handler, None);
+  }
+
+  pub fn run_with_commands<H: Handler>(&self,
+                                       request: &Parser,
+                                       rest: ~[u8],
+                                       handler: &mut H,
+                                       commands: &OutboundQueue) {
+    self.run_loop(request, rest, handler, Some(commands));
+  }
+
+  priv fn run_loop<H: Handler>(&self,
+                               request: &Parser,
+                               rest: ~[u8],
+                               handler: &mut H,
+                               commands: Option<&OutboundQueue>) {
+    let mut bytes = rest;
+    let mut frame_parser = FrameParser::new_strict();
+    let mut receiver = Receiver::new();
+    let mut closing = false;
+
+    handler.on_open(self, request);
+
+    loop {
+      for commands.each |queue| {
+        loop {
+          match queue.pop() {
+            Some(SendBytes(frame_bytes)) => {
+              if !closing {
+                self.socket.write(frame_bytes);
+              }
+            }
+
+            Some(SendShared(frame_bytes)) => {
+              if !closing {
+                self.socket.write(copy *arc::get(&frame_bytes));
+              }
+            }
+
+            Some(Disconnect(code, reason)) => {
+              if !closing {
+                closing = true;
+                self.close(code, reason);
+              }
+            }
+
+            Some(Abort) => {
+              handler.on_close(self, CLOSE_ABNORMAL, "");
+              return;
+            }
+
+            None => break
+          }
+        }
+      }
+
+      if bytes.is_empty() {
+        let read = match commands {
+          Some(_) => self.socket.read_timeout(COMMAND_POLL_MS),
+          None => self.socket.read().map(|new_bytes| Some(copy *new_bytes))
+        };
+
+        match read {
+          Ok(Some(new_bytes)) => bytes = new_bytes,
+          Ok(None) => loop,
+          Err(error) => {
+            handler.on_error(self, &CONNECTION_LOST(error));
+            handler.on_close(self, CLOSE_ABNORMAL, "");
+            return;
+          }
+        }
+      }
+
+      let result = match frame_parser.parse(bytes) {
+        Ok(result) => result,
+        Err(error) => {
+          self.fail_connection(handler, error.close_code(), &INVALID_FRAME(error));
+          return;
+        }
+      };
+
+      frame_parser = result.parser;
+      bytes = vec::from_slice(bytes.tailn(result.bytes_parsed));
+
+      if !result.is_done() {
+        loop;
+      }
+
+      frame_parser = FrameParser::new_strict();
+      let frame = result.make_frame_done();
+
+      match frame.op_code {
+        PING => {
+          let payload = frame.unmasked_payload().to_bytes();
+          self.send_frame(&Frame::pong(payload));
+          handler.on_ping(self, payload);
+        }
+
+        CONNECTION_CLOSE => {
+          let (code, reason) = frame.close_status();
+
+          if !closing {
+            let reply_code = if code == CLOSE_NO_STATUS { CLOSE_NORMAL } else { code };
+            self.close(reply_code, "");
+          }
+
+          handler.on_close(self, code, reason);
+          return;
+        }
+
+        PONG => {}
+
+        _ => {
+          match receiver.next_fragment(frame) {
+            Receiving(next) => receiver = next,
+
+            Received(Right(TextMessage(text))) => {
+              receiver = Receiver::new();
+              handler.on_text(self, text);
+            }
+
+            Received(Left(DataMessage(data))) => {
+              receiver = Receiver::new();
+              handler.on_binary(self, data);
+            }
+
+            ReceptionError(INVALID_UTF8) => {
+              self.fail_connection(handler, CLOSE_INVALID_PAYLOAD, &INVALID_MESSAGE(INVALID_UTF8));
+              return;
+            }
+
+            ReceptionError(error) => {
+              self.fail_connection(handler, CLOSE_PROTOCOL_ERROR, &INVALID_MESSAGE(error));
+              return;
+            }
+          }
+        }
+      }
+    }
+  }
+
+  priv fn fail_connection<H: Handler>(&self, handler: &mut H, code: u16, error: &ConnectionError) {
+    handler.on_error(self, error);
+    self.close(code, "");
+    handler.on_close(self, code, "");
+  }
+}
+
 fn accept_websocket<T: Transport>(transport: T)
    -> Result<WebSocket<T>,~str> {
+  accept_websocket_with_limits(transport, &HandshakeLimits::default())
+}
+
+fn accept_websocket_with_limits<T: Transport>(transport: T,
+                                              limits: &HandshakeLimits)
+   -> Result<WebSocket<T>,~str> {
+  accept_websocket_with_authenticator(transport, limits, &AnonymousAuthenticator)
+}
+
+fn accept_websocket_with_authenticator<T: Transport, A: Authenticator>(transport: T,
+                                                                      limits: &HandshakeLimits,
+                                                                      authenticator: &A)
+   -> Result<WebSocket<T>,~str> {
+
+  match read_and_parse_request(&transport, limits) {
+    Ok((parser, _)) => {
+      handle_accept_result(transport, accept_authenticated_request(&parser, authenticator))
+    }
+
+    Err(error) => Err(handle_handshake_error(transport, error))
+  }
+}
+
+pub fn serve_websocket<T: Transport, A: Authenticator, H: Handler>(transport: T,
+                                                                 limits: &HandshakeLimits,
+                                                                 authenticator: &A,
+                                                                 handler: &mut H)
+   -> Result<(),~str> {
+
+  match accept_connection(transport, limits, authenticator) {
+    Ok((websocket, request, rest)) => Ok(websocket.run(&request, rest, handler)),
+    Err(error) => Err(error)
+  }
+}
+
+pub fn accept_connection<T: Transport, A: Authenticator>(transport: T,
+                                                         limits: &HandshakeLimits,
+                                                         authenticator: &A)
+   -> Result<Connection<T>,~str> {
+  accept_connection_with_protocols(transport, limits, authenticator, [])
+}
+
+pub fn accept_connection_with_protocols<T: Transport, A: Authenticator>(transport: T,
+                                                                        limits: &HandshakeLimits,
+                                                                        authenticator: &A,
+                                                                        protocols: &[~str])
+   -> Result<Connection<T>,~str> {
+
+  match read_and_parse_request(&transport, limits) {
+    Ok((parser, rest)) => {
+      let mut accept_result = accept_authenticated_request(&parser, authenticator);
+
+      match accept_result {
+        Ok(ref mut acceptance) => acceptance.protocol = select_protocol(&parser, protocols),
+        Err(_) => {}
+      }
 
-  match read_and_parse_request(&transport) {
-    Ok(parser) => handle_accept_result(transport, accept_request(&parser)),
-    Err(message) => Err(message)
+      match handle_accept_result(transport, accept_result) {
+        Ok(websocket) => Ok((websocket, parser, rest)),
+        Err(error) => Err(error)
+      }
+    }
+
+    Err(error) => Err(handle_handshake_error(transport, error))
   }
 }
 
-fn read_and_parse_request<T: Transport>(transport: &T) -> Result<Parser,~str> {
+pub fn read_and_parse_request<T: Transport>(transport: &T,
+                                            limits: &HandshakeLimits)
+   -> Result<Handshake,HandshakeError> {
   let mut parser = initial_parser();
-  let mut chunk;
+  let started_at = precise_time_ns();
 
   loop {
-    let read = transport.read();
+    let elapsed_ms = ((precise_time_ns() - started_at) / 1000000) as uint;
+
+    if elapsed_ms >= limits.timeout_ms {
+      return Err(HANDSHAKE_TIMEOUT)
+    }
+
+    let read = transport.read_timeout(limits.timeout_ms - elapsed_ms);
 
     match read {
-      Ok(bytes) => {
-        chunk = str::from_bytes(bytes);
-        parser = parser.parse(chunk);
+      Ok(Some(bytes)) => {
+        parser.execute(bytes);
+
+        match parser.error() {
+          Some(error) => return Err(MALFORMED_REQUEST(error)),
+          None => {}
+        }
 
-        if parser.upgrade() {
-          return Ok(parser)
+        match limits.check(&parser) {
+          Some(error) => return Err(error),
+          None => {}
+        }
+
+        if parser.message_complete() {
+          let rest = vec::from_slice(bytes.tailn(parser.offset));
+          return Ok((parser, rest))
         }
       }
 
-      Err(error) => return Err(error)
+      Ok(None) => return Err(HANDSHAKE_TIMEOUT),
+      Err(error) => return Err(TRANSPORT_ERROR(error))
     }
   }
 }
 
+fn handle_handshake_error<T: Transport>(transport: T, error: HandshakeError) -> ~str {
+  for error.to_response_str().each |response| {
+    transport.write(response.to_bytes());
+  }
+
+  error.to_str()
+}
+
 fn handle_accept_result<T: Transport>(transport: T, accept_result: AcceptResult)
    -> Result<WebSocket<T>,~str> {
   transport.write(accept_result.to_websocket_response_str().to_bytes());
 
   if accept_result.is_ok() {
-    Ok(WebSocket { socket: transport })
+    let acceptance = accept_result.get_ref();
+
+    Ok(WebSocket {
+      socket: transport,
+      identity: acceptance.identity.clone(),
+      protocol: acceptance.protocol.clone(),
+      connection_id: None
+    })
   } else {
     Err(~"Failed to accept")
   }
 }
 
+impl Transport for net_tcp::TcpSocket {
+  fn read(&self) -> Result<~[u8],Error> {
+    match net_tcp::read(self, 0) {
+      Ok(bytes) => Ok(bytes),
+      Err(error) => Err(error.err_name + ~": " + error.err_msg)
+    }
+  }
+
+  fn read_timeout(&self, timeout_ms: uint) -> Result<Option<~[u8]>,Error> {
+    match net_tcp::read(self, timeout_ms) {
+      Ok(bytes) => Ok(Some(bytes)),
+      Err(error) => {
+        if error.err_name == ~"TIMEOUT" {
+          Ok(None)
+        } else {
+          Err(error.err_name + ~": " + error.err_msg)
+        }
+      }
+    }
+  }
+
+  fn write(&self, bytes: ~[u8]) {
+    net_tcp::write(self, bytes);
+  }
+}
+
 static sample_handshake:&'static str =
   "GET /chat HTTP/1.1\n\
    Host: server.example.com\n\
@@ -117,6 +549,310 @@ fn accept_connection_sends_response_an_closes_on_invalid_handshake() {
   assert!(client_socket.fake_read() == Err(~"Attempt to read closed socket"));
 }
 
+#[test]
+fn accept_connection_rejects_too_many_headers() {
+  let (server_socket, client_socket) = fake_connection();
+  let limits = HandshakeLimits { max_headers: 3, .. HandshakeLimits::default() };
+  client_socket.fake_write_chunked(sample_handshake.to_bytes());
+
+  let result = accept_websocket_with_limits(server_socket, &limits);
+
+  assert!(result.get_err() == ~"Too many headers");
+  assert!(client_socket.fake_read_str() ==
+          Ok(~"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n"));
+  assert!(client_socket.fake_read() == Err(~"Attempt to read closed socket"));
+}
+
+#[test]
+fn accept_connection_rejects_large_headers() {
+  let (server_socket, client_socket) = fake_connection();
+  let limits = HandshakeLimits { max_header_size: 20, .. HandshakeLimits::default() };
+  client_socket.fake_write(sample_handshake.to_bytes());
+
+  let result = accept_websocket_with_limits(server_socket, &limits);
+
+  assert!(result.get_err() == ~"Header too large");
+  assert!(client_socket.fake_read_str() ==
+          Ok(~"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n"));
+}
+
+#[test]
+fn accept_connection_rejects_large_handshake() {
+  let (server_socket, client_socket) = fake_connection();
+  let limits = HandshakeLimits { max_bytes: 64, .. HandshakeLimits::default() };
+  client_socket.fake_write_chunked(sample_handshake.to_bytes());
+
+  let result = accept_websocket_with_limits(server_socket, &limits);
+
+  assert!(result.get_err() == ~"Handshake too large");
+  assert!(client_socket.fake_read_str() ==
+          Ok(~"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n"));
+}
+
+#[test]
+fn accept_connection_times_out_on_incomplete_handshake() {
+  let (server_socket, client_socket) = fake_connection();
+  client_socket.fake_write_chunked("GET /chat HTTP/1.1\n".to_bytes());
+
+  let result = accept_websocket(server_socket);
+
+  assert!(result.get_err() == ~"Handshake timed out");
+  assert!(client_socket.fake_read_str() ==
+          Ok(~"HTTP/1.1 408 Request Timeout\r\n\r\n"));
+}
+
+#[test]
+fn accept_connection_rejects_request_without_upgrade() {
+  let (server_socket, client_socket) = fake_connection();
+  client_socket.fake_write("GET /chat HTTP/1.1\nHost: server.example.com\n\n".to_bytes());
+
+  let result = accept_websocket(server_socket);
+
+  assert!(result.is_err());
+  assert!(client_socket.fake_read_str() ==
+          Ok(~"HTTP/1.1 400 Bad Request\r\n\r\n"));
+}
+
+struct QueryTokenAuthenticator;
+
+impl Authenticator for QueryTokenAuthenticator {
+  fn authenticate<T: Headers+Request>(&self, request: &T) -> Result<Identity,AuthError> {
+    match request.get_bearer_token("access_token") {
+      Some(token) => {
+        let mut identity = Identity::new(token);
+        identity.add_claim("scope", "chat");
+        Ok(identity)
+      }
+
+      None => Err(UNAUTHORIZED(~"Bearer"))
+    }
+  }
+}
+
+#[test]
+fn accept_connection_rejects_unauthorized_handshake() {
+  let (server_socket, client_socket) = fake_connection();
+  client_socket.fake_write(sample_handshake.to_bytes());
+
+  let result = accept_websocket_with_authenticator(server_socket,
+                                                   &HandshakeLimits::default(),
+                                                   &QueryTokenAuthenticator);
+
+  assert!(result.is_err());
+  assert!(client_socket.fake_read_str() ==
+          Ok(~"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\n\r\n"));
+}
+
+#[test]
+fn accept_connection_with_query_token() {
+  let (server_socket, client_socket) = fake_connection();
+  let handshake = str::replace(sample_handshake, "GET /chat ", "GET /chat?access_token=abc ");
+  client_socket.fake_write(handshake.to_bytes());
+
+  let result = accept_websocket_with_authenticator(server_socket,
+                                                   &HandshakeLimits::default(),
+                                                   &QueryTokenAuthenticator);
+
+  assert!(result.is_ok());
+}
+
+#[test]
+fn accept_connection_keeps_identity() {
+  let (server_socket, client_socket) = fake_connection();
+  let handshake = str::replace(sample_handshake, "GET /chat ", "GET /chat?access_token=bob ");
+  client_socket.fake_write(handshake.to_bytes());
+
+  let result = accept_websocket_with_authenticator(server_socket,
+                                                   &HandshakeLimits::default(),
+                                                   &QueryTokenAuthenticator);
+
+  match result {
+    Ok(websocket) => {
+      assert!(websocket.identity.subject == Some(~"bob"));
+      assert!(websocket.identity.has_claim("scope", "chat"));
+    }
+    Err(_) => fail!(~"Expected handshake to be accepted")
+  }
+}
+
+#[test]
+fn accept_connection_negotiates_protocol() {
+  let (server_socket, client_socket) = fake_connection();
+  let handshake = str::replace(sample_handshake, "Origin:",
+                               "Sec-WebSocket-Protocol: chat, pubsub\nOrigin:");
+  client_socket.fake_write(handshake.to_bytes());
+
+  let (websocket, _, _) = accept_connection_with_protocols(server_socket,
+                                                           &HandshakeLimits::default(),
+                                                           &AnonymousAuthenticator,
+                                                           [~"pubsub"]).unwrap();
+
+  assert!(*websocket.protocol() == Some(~"pubsub"));
+  assert!(websocket.connection_id().is_none());
+  assert!(str::contains(client_socket.fake_read_str().get(), "Sec-WebSocket-Protocol: pubsub\r\n"));
+}
+
+#[test]
+fn accept_connection_rejects_malformed_request() {
+  let (server_socket, client_socket) = fake_connection();
+  client_socket.fake_write("YURT /chat HTTP/1.1\n\n".to_bytes());
+
+  let result = accept_websocket(server_socket);
+
+  assert!(result.is_err());
+  assert!(client_socket.fake_read_str() ==
+          Ok(~"HTTP/1.1 400 Bad Request\r\n\r\n"));
+}
+
+#[test]
+fn read_and_parse_request_returns_bytes_after_handshake() {
+  let (server_socket, client_socket) = fake_connection();
+  client_socket.fake_write(sample_handshake.to_bytes() + ~[0x81, 0x00]);
+
+  let result = read_and_parse_request(&server_socket, &HandshakeLimits::default());
+
+  match result {
+    Ok((parser, rest)) => {
+      assert!(parser.upgrade());
+      assert!(rest == ~[0x81, 0x00]);
+    }
+    Err(_) => fail!(~"Expected handshake to parse")
+  }
+}
+
+struct RecordingHandler {
+  events: ~[~str]
+}
+
+impl Handler for RecordingHandler {
+  fn on_open<T: Transport>(&mut self, _: &WebSocket<T>, request: &Parser) {
+    self.events.push(~"open " + request.url().get());
+  }
+
+  fn on_text<T: Transport>(&mut self, socket: &WebSocket<T>, text: &str) {
+    self.events.push(~"text " + text);
+    socket.send_text(text);
+  }
+
+  fn on_binary<T: Transport>(&mut self, _: &WebSocket<T>, data: &[u8]) {
+    self.events.push(~"binary " + data.len().to_str());
+  }
+
+  fn on_ping<T: Transport>(&mut self, _: &WebSocket<T>, _: &[u8]) {
+    self.events.push(~"ping");
+  }
+
+  fn on_close<T: Transport>(&mut self, _: &WebSocket<T>, code: u16, reason: &str) {
+    self.events.push(~"close " + code.to_str() + " " + reason);
+  }
+
+  fn on_error<T: Transport>(&mut self, _: &WebSocket<T>, _: &ConnectionError) {
+    self.events.push(~"error");
+  }
+}
+
+#[test]
+fn serve_websocket_drives_handler() {
+  let (server_socket, client_socket) = fake_connection();
+  let mut handler = RecordingHandler { events: ~[] };
+  let frames = ~[0x81, 0x82, 0, 0, 0, 0, 'H' as u8, 'i' as u8,
+                 0x89, 0x80, 0, 0, 0, 0,
+                 0x88, 0x84, 0, 0, 0, 0, 0x03, 0xE9, 'o' as u8, 'k' as u8];
+  client_socket.fake_write(sample_handshake.to_bytes() + frames);
+
+  let result = serve_websocket(server_socket, &HandshakeLimits::default(),
+                               &AnonymousAuthenticator, &mut handler);
+
+  assert!(result.is_ok());
+  assert!(handler.events == ~[~"open /chat", ~"text Hi", ~"ping", ~"close 1001 ok"]);
+  assert!(client_socket.fake_read_str().get().starts_with("HTTP/1.1 101"));
+  assert!(client_socket.fake_read() == Ok(~[0x81, 0x02, 'H' as u8, 'i' as u8]));
+  assert!(client_socket.fake_read() == Ok(~[0x8A, 0x00]));
+  assert!(client_socket.fake_read() == Ok(~[0x88, 0x02, 0x03, 0xE9]));
+}
+
+#[test]
+fn serve_websocket_closes_on_invalid_frame() {
+  let (server_socket, client_socket) = fake_connection();
+  let mut handler = RecordingHandler { events: ~[] };
+  client_socket.fake_write(sample_handshake.to_bytes() + ~[0x83, 0x80, 0, 0, 0, 0]);
+
+  let result = serve_websocket(server_socket, &HandshakeLimits::default(),
+                               &AnonymousAuthenticator, &mut handler);
+
+  assert!(result.is_ok());
+  assert!(handler.events == ~[~"open /chat", ~"error", ~"close 1002 "]);
+  assert!(client_socket.fake_read_str().get().starts_with("HTTP/1.1 101"));
+  assert!(client_socket.fake_read() == Ok(~[0x88, 0x02, 0x03, 0xEA]));
+}
+
+#[test]
+fn serve_websocket_closes_on_invalid_utf8() {
+  let (server_socket, client_socket) = fake_connection();
+  let mut handler = RecordingHandler { events: ~[] };
+  client_socket.fake_write(sample_handshake.to_bytes() + ~[0x81, 0x82, 0, 0, 0, 0, 0xFF, 0xFE]);
+
+  let result = serve_websocket(server_socket, &HandshakeLimits::default(),
+                               &AnonymousAuthenticator, &mut handler);
+
+  assert!(result.is_ok());
+  assert!(handler.events == ~[~"open /chat", ~"error", ~"close 1007 "]);
+  assert!(client_socket.fake_read_str().get().starts_with("HTTP/1.1 101"));
+  assert!(client_socket.fake_read() == Ok(~[0x88, 0x02, 0x03, 0xEF]));
+}
+
+#[test]
+fn serve_websocket_reports_lost_connection() {
+  let (server_socket, client_socket) = fake_connection();
+  let mut handler = RecordingHandler { events: ~[] };
+  client_socket.fake_write(sample_handshake.to_bytes());
+  client_socket.fake_error(~"Connection reset");
+
+  let result = serve_websocket(server_socket, &HandshakeLimits::default(),
+                               &AnonymousAuthenticator, &mut handler);
+
+  assert!(result.is_ok());
+  assert!(handler.events == ~[~"open /chat", ~"error", ~"close 1006 "]);
+}
+
+#[test]
+fn run_with_commands_sends_going_away_and_waits_for_close() {
+  let (server_socket, client_socket) = fake_connection();
+  let commands = OutboundQueue::new(16, websockets::outbound::DROP_NEWEST);
+  let mut handler = RecordingHandler { events: ~[] };
+  client_socket.fake_write(sample_handshake.to_bytes());
+  client_socket.fake_write(~[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE8]);
+  commands.push_control(Disconnect(1001, ~"bye"));
+
+  let (websocket, request, rest) =
+    accept_connection(server_socket, &HandshakeLimits::default(), &AnonymousAuthenticator).unwrap();
+  websocket.run_with_commands(&request, rest, &mut handler, &commands);
+
+  assert!(handler.events == ~[~"open /chat", ~"close 1000 "]);
+  assert!(client_socket.fake_read_str().get().starts_with("HTTP/1.1 101"));
+  assert!(client_socket.fake_read() == Ok(~[0x88, 0x05, 0x03, 0xE9, 'b' as u8, 'y' as u8, 'e' as u8]));
+}
+
+#[test]
+fn run_with_commands_sends_bytes_and_aborts() {
+  let (server_socket, client_socket) = fake_connection();
+  let commands = OutboundQueue::new(16, websockets::outbound::DROP_NEWEST);
+  let mut handler = RecordingHandler { events: ~[] };
+  client_socket.fake_write(sample_handshake.to_bytes());
+  commands.push(SendBytes(~[0x81, 0x00]));
+  commands.push(SendShared(arc::ARC(~[0x82, 0x00])));
+
+  let (websocket, request, rest) =
+    accept_connection(server_socket, &HandshakeLimits::default(), &AnonymousAuthenticator).unwrap();
+  commands.push_control(Abort);
+  websocket.run_with_commands(&request, rest, &mut handler, &commands);
+
+  assert!(handler.events == ~[~"open /chat", ~"close 1006 "]);
+  assert!(client_socket.fake_read_str().get().starts_with("HTTP/1.1 101"));
+  assert!(client_socket.fake_read() == Ok(~[0x81, 0x00]));
+  assert!(client_socket.fake_read() == Ok(~[0x82, 0x00]));
+}
+
 enum SocketState { OPEN, CLOSED }
 
 enum FakePacket {
@@ -129,7 +865,7 @@ struct FakeSocket {
   state: @mut SocketState,
 }
 
-type Error = ~str;
+pub type Error = ~str;
 
 fn fake_connection() -> (FakeSocket, FakeSocket) {
   let (stream_1_in, stream_2_out) = stream();
@@ -178,6 +914,23 @@ impl FakeSocket {
     }
   }
 
+  fn fake_read_timeout(&self) -> Result<Option<~[u8]>,Error> {
+    match *self.state {
+      CLOSED => self.closed_read().map(|bytes| Some(copy *bytes)),
+      OPEN => {
+        if self.in.peek() {
+          self.do_read().map(|bytes| Some(copy *bytes))
+        } else {
+          Ok(None)
+        }
+      }
+    }
+  }
+
+  fn fake_read_str(&self) -> Result<~str,Error> {
+    self.fake_read().map(|bytes| { str::from_bytes(*bytes) })
+  }
+
   fn do_read(&self) -> Result<~[u8],Error> {
     match self.in.recv() {
       Data(bytes) => Ok(bytes),
@@ -203,6 +956,10 @@ impl Transport for FakeSocket {
     self.fake_read()
   }
 
+  fn read_timeout(&self, _: uint) -> Result<Option<~[u8]>,Error> {
+    self.fake_read_timeout()
+  }
+
   fn write(&self, bytes: ~[u8]) {
     self.fake_write(bytes)
   }